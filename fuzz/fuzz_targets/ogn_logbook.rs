@@ -0,0 +1,14 @@
+//! Feeds arbitrary answers of OGN to the logbook parser: a malformed logbook
+//! must be an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serveur::ogn::{flights_from_logbook, Logbook, LogbookSettings};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(logbook) = Logbook::parse(data) {
+        let _ = logbook.aircraft();
+        let _ = flights_from_logbook(&logbook, &LogbookSettings::default());
+    }
+});
@@ -0,0 +1,29 @@
+//! Feeds arbitrary query strings to the parameters of the routes of the
+//! server, parsed as the connection handler does before serving a request: a
+//! malformed query must be an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+const ROUTES: [(&str, &str); 10] = [
+    ("GET", "/flightlog"),
+    ("GET", "/flightlogs"),
+    ("GET", "/infos"),
+    ("GET", "/history"),
+    ("GET", "/aircraft"),
+    ("GET", "/conflicts"),
+    ("GET", "/flights"),
+    ("POST", "/admin/backfill"),
+    ("GET", "/admin/backfill"),
+    ("POST", "/updates"),
+];
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&route, query)) = data.split_first() {
+        if let Ok(query) = std::str::from_utf8(query) {
+            let (method, path) = ROUTES[route as usize % ROUTES.len()];
+            let _ = serveur::parse_query(method, path, query);
+        }
+    }
+});
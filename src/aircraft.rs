@@ -0,0 +1,139 @@
+//! The aircraft seen by OGN at each airport.
+//! The devices of the OGN logbooks give the model and the category of the
+//! aircraft. They are kept in a file next to the flightlog of their day:
+//! `YYYY/MM/DD/OACI.aircraft.json`, so that the model can be shown next to the
+//! immatriculation and the tow planes told from the gliders.
+
+use crate::archive;
+use crate::storage::{day_dir, write_atomic, StorageError};
+use crate::Aircraft;
+use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// The aircraft files of all the days, stored under a root directory.
+pub struct AircraftRegistry {
+    root: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AircraftRegistry {
+    /// Creates a registry storing its files under `root`.
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the path of the aircraft file of a day at an airport.
+    pub fn path(&self, date: NaiveDate, oaci: &str) -> PathBuf {
+        day_dir(&self.root, date).join(format!("{}.aircraft.json", oaci))
+    }
+
+    /// Returns the aircraft seen on a day at an airport, sorted by
+    /// immatriculation.
+    pub async fn day(&self, date: NaiveDate, oaci: &str) -> Result<Vec<Aircraft>, StorageError> {
+        let path = self.path(date, oaci);
+        let content = if path.exists() {
+            Some(fs::read(&path).await?)
+        } else {
+            let root = self.root.clone();
+            let name = format!("{}.aircraft.json", oaci);
+            tokio::task::spawn_blocking(move || archive::read_archived(&root, date, &name))
+                .await??
+        };
+        match content {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Records the aircraft seen on a day at an airport. The aircraft already
+    /// known are updated with the new metadata, the others are kept.
+    pub async fn record(
+        &self,
+        date: NaiveDate,
+        oaci: &str,
+        aircraft: Vec<Aircraft>,
+    ) -> Result<(), StorageError> {
+        let _guard = self.lock.lock().await;
+        let mut known: BTreeMap<String, Aircraft> = self
+            .day(date, oaci)
+            .await?
+            .into_iter()
+            .map(|aircraft| (aircraft.immatriculation.clone(), aircraft))
+            .collect();
+        let mut changed = false;
+        for aircraft in aircraft {
+            if aircraft.immatriculation.is_empty() {
+                continue;
+            }
+            if known.get(&aircraft.immatriculation) != Some(&aircraft) {
+                known.insert(aircraft.immatriculation.clone(), aircraft);
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        let path = self.path(date, oaci);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let content = serde_json::to_vec(&known.into_values().collect::<Vec<Aircraft>>())?;
+        write_atomic(&path, &content).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AircraftRegistry;
+    use crate::Aircraft;
+    use chrono::NaiveDate;
+
+    fn aircraft(immatriculation: &str, modele: &str, category: u8) -> Aircraft {
+        Aircraft {
+            modele: modele.to_string(),
+            category,
+            immatriculation: immatriculation.to_string(),
+            competition: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn record_and_update_aircraft() {
+        let root = std::env::temp_dir().join(format!("cepo-aircraft-{}", std::process::id()));
+        let registry = AircraftRegistry::new(root.clone());
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        assert!(registry.day(date, "LFLE").await.unwrap().is_empty());
+
+        registry
+            .record(
+                date,
+                "LFLE",
+                vec![
+                    aircraft("F-GDRT", "Robin DR400", 2),
+                    aircraft("F-CEJU", "", 1),
+                    aircraft("", "Unknown", 0),
+                ],
+            )
+            .await
+            .unwrap();
+        registry
+            .record(date, "LFLE", vec![aircraft("F-CEJU", "ASK 21", 1)])
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.day(date, "LFLE").await.unwrap(),
+            vec![
+                aircraft("F-CEJU", "ASK 21", 1),
+                aircraft("F-GDRT", "Robin DR400", 2)
+            ]
+        );
+        assert!(registry.day(date, "LFLB").await.unwrap().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
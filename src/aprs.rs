@@ -0,0 +1,631 @@
+//! Live ingestion of the OGN APRS feed.
+//! Instead of polling the flightbook every `f_synchronisation_secs`, the
+//! server can log in to an APRS-IS server with a range filter around the
+//! airports, read the position beacons of the configured aircraft and detect
+//! the takeoffs and landings itself from their ground speed. The flightlogs of
+//! the day are updated as soon as a beacon shows a change.
+
+use crate::configuration::{AprsConfiguration, Position};
+use crate::ddb::DeviceDatabase;
+use crate::flightlog::Storage;
+use crate::selection::Selection;
+use crate::time_zone::AirportZone;
+use crate::Context;
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::FlightLog;
+use chrono::NaiveTime;
+use std::collections::HashMap;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Errors returned by the APRS ingestion.
+pub type AprsError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Above this ground speed (in knots) an aircraft is flying.
+pub const TAKEOFF_SPEED_KT: u16 = 30;
+/// Under this ground speed (in knots) an aircraft is on the ground.
+pub const LANDING_SPEED_KT: u16 = 10;
+/// How often a keepalive is sent to the APRS server.
+const KEEPALIVE_SECS: u64 = 240;
+/// How long to wait before connecting again after the feed was lost.
+const RECONNECT_SECS: u64 = 30;
+/// How long the feed may stay silent before the connection is considered
+/// lost: the APRS servers send a comment every 20 seconds or so.
+const READ_TIMEOUT_SECS: u64 = 120;
+
+/// A position beacon sent by an OGN device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beacon {
+    /// The callsign of the sender, like `FLRDD8E3A`.
+    pub callsign: String,
+    /// The OGN device address, like `DD8E3A`, if known.
+    pub address: Option<String>,
+    /// The OGN category of the aircraft, if sent.
+    pub aircraft_type: Option<u8>,
+    /// When the beacon was sent (UTC).
+    pub time: NaiveTime,
+    /// Where the aircraft is.
+    pub position: Position,
+    /// The ground speed in knots, if sent.
+    pub ground_speed_kt: Option<u16>,
+    /// The altitude in feet, if sent.
+    pub altitude_ft: Option<i32>,
+}
+
+/// Parses an APRS position beacon like
+/// `FLRDD8E3A>OGFLR,qAS,LFLE:/100215h4533.67N/00558.53E'086/054/A=001234 !W12! id06DD8E3A`.
+/// Returns `None` for the server comments and the lines that are not
+/// position beacons.
+pub fn parse_beacon(line: &str) -> Option<Beacon> {
+    if line.starts_with('#') || !line.is_ascii() {
+        return None;
+    }
+    let (header, body) = line.split_once(':')?;
+    let callsign = header.split('>').next()?.to_string();
+    if !(body.starts_with('/') || body.starts_with('@')) || body.get(7..8)? != "h" {
+        return None;
+    }
+    let time = NaiveTime::parse_from_str(body.get(1..7)?, "%H%M%S").ok()?;
+    let mut latitude = parse_coordinate(body.get(8..15)?, 2)?;
+    let latitude_sign = match body.get(15..16)? {
+        "N" => 1.0,
+        "S" => -1.0,
+        _ => return None,
+    };
+    let mut longitude = parse_coordinate(body.get(17..25)?, 3)?;
+    let longitude_sign = match body.get(25..26)? {
+        "E" => 1.0,
+        "W" => -1.0,
+        _ => return None,
+    };
+    let extension = body.get(27..)?;
+
+    let ground_speed_kt = extension
+        .get(3..4)
+        .filter(|separator| *separator == "/")
+        .and_then(|_| extension.get(4..7)?.parse::<u16>().ok());
+    let altitude_ft = extension
+        .find("/A=")
+        .and_then(|index| extension.get(index + 3..index + 9)?.parse::<i32>().ok());
+    let mut address = None;
+    let mut aircraft_type = None;
+    for word in extension.split_whitespace() {
+        // The precision enhancement gives the third decimal of the minutes.
+        if word.len() == 5 && word.starts_with("!W") && word.ends_with('!') {
+            let digits: Vec<f64> = word[2..4]
+                .chars()
+                .filter_map(|c| c.to_digit(10).map(|digit| digit as f64))
+                .collect();
+            if digits.len() == 2 {
+                latitude += digits[0] / 1000.0 / 60.0;
+                longitude += digits[1] / 1000.0 / 60.0;
+            }
+        }
+        if word.len() == 10 && word.starts_with("id") {
+            address = Some(word[4..].to_uppercase());
+            // The flags byte holds the aircraft type in its bits 2 to 5.
+            aircraft_type = u8::from_str_radix(&word[2..4], 16)
+                .ok()
+                .map(|flags| (flags >> 2) & 0x0F);
+        }
+    }
+    if address.is_none() && callsign.len() == 9 {
+        let prefix = &callsign[..3];
+        if ["FLR", "ICA", "OGN", "FNT"].contains(&prefix) {
+            address = Some(callsign[3..].to_uppercase());
+        }
+    }
+    Some(Beacon {
+        callsign,
+        address,
+        aircraft_type,
+        time,
+        position: Position {
+            latitude: latitude * latitude_sign,
+            longitude: longitude * longitude_sign,
+        },
+        ground_speed_kt,
+        altitude_ft,
+    })
+}
+
+/// Parses `DDMM.mm` (or `DDDMM.mm` with 3 degrees digits) into degrees.
+fn parse_coordinate(coordinate: &str, degrees_digits: usize) -> Option<f64> {
+    let degrees = coordinate.get(..degrees_digits)?.parse::<f64>().ok()?;
+    let minutes = coordinate.get(degrees_digits..)?.parse::<f64>().ok()?;
+    Some(degrees + minutes / 60.0)
+}
+
+/// Returns the distance between two positions in kilometers.
+pub fn distance_km(a: Position, b: Position) -> f64 {
+    let (latitude_a, latitude_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let delta_latitude = latitude_b - latitude_a;
+    let delta_longitude = (b.longitude - a.longitude).to_radians();
+    let h = (delta_latitude / 2.0).sin().powi(2)
+        + latitude_a.cos() * latitude_b.cos() * (delta_longitude / 2.0).sin().powi(2);
+    2.0 * 6371.0 * h.sqrt().asin()
+}
+
+/// A takeoff or a landing detected at an airport.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlightEvent {
+    /// The aircraft took off at this UTC time.
+    Takeoff {
+        /// The immatriculation of the aircraft.
+        immatriculation: String,
+        /// When it took off (UTC).
+        time: NaiveTime,
+    },
+    /// The aircraft landed at this UTC time.
+    Landing {
+        /// The immatriculation of the aircraft.
+        immatriculation: String,
+        /// When it landed (UTC).
+        time: NaiveTime,
+    },
+}
+
+/// Detects the takeoffs and landings from the ground speed of the beacons.
+/// The first beacon of an aircraft only tells whether it is flying, so no
+/// event is made up when the server starts.
+pub struct FlightDetector {
+    airports: Vec<(String, Position)>,
+    radius_km: f64,
+    flying: HashMap<String, bool>,
+}
+
+impl FlightDetector {
+    /// Creates a detector for the airports and their positions, the events
+    /// being detected within `radius_km` of them.
+    pub fn new(airports: Vec<(String, Position)>, radius_km: f64) -> Self {
+        Self {
+            airports,
+            radius_km,
+            flying: HashMap::new(),
+        }
+    }
+
+    /// Returns the nearest airport within the radius of a position.
+    fn airport_near(&self, position: Position) -> Option<&String> {
+        self.airports
+            .iter()
+            .map(|(oaci, airport)| (oaci, distance_km(*airport, position)))
+            .filter(|(_, distance)| *distance <= self.radius_km)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(oaci, _)| oaci)
+    }
+
+    /// Feeds a beacon of the aircraft `immatriculation` and returns the event
+    /// it shows, with the airport where it happened.
+    pub fn feed(
+        &mut self,
+        immatriculation: &str,
+        beacon: &Beacon,
+    ) -> Option<(String, FlightEvent)> {
+        let speed = beacon.ground_speed_kt?;
+        let now_flying = if speed >= TAKEOFF_SPEED_KT {
+            true
+        } else if speed <= LANDING_SPEED_KT {
+            false
+        } else {
+            return None;
+        };
+        let was_flying = self
+            .flying
+            .insert(immatriculation.to_string(), now_flying)?;
+        if was_flying == now_flying {
+            return None;
+        }
+        let oaci = self.airport_near(beacon.position)?.clone();
+        let immatriculation = immatriculation.to_string();
+        let event = if now_flying {
+            FlightEvent::Takeoff {
+                immatriculation,
+                time: beacon.time,
+            }
+        } else {
+            FlightEvent::Landing {
+                immatriculation,
+                time: beacon.time,
+            }
+        };
+        Some((oaci, event))
+    }
+}
+
+/// Writes an event in a flightlog. A takeoff fills a flight of the aircraft
+/// that was entered but has not taken off yet, or adds a new one; a landing
+/// ends the last flight of the aircraft still in the air, and is ignored when
+/// none is (its takeoff was missed). Returns true if the flightlog changed.
+/// The UTC times of the events are converted to the time zone of the airport.
+/// The flights added get negative numbers, like the ones entered by hand, so
+/// that OGN matches them by glider and time and not by number.
+pub fn apply_event(flightlog: &mut FlightLog, event: &FlightEvent, zone: &AirportZone) -> bool {
+    let unknown = NaiveTime::default();
+    let next_ogn_nb = flightlog
+        .flights
+        .iter()
+        .map(|flight| flight.ogn_nb)
+        .min()
+        .unwrap_or(0)
+        .min(0)
+        - 1;
+    match event {
+        FlightEvent::Takeoff {
+            immatriculation,
+            time,
+        } => {
+            let takeoff = zone.time_of_utc(flightlog.date, *time);
+            let waiting = flightlog.flights.iter_mut().find(|flight| {
+                flight.glider == *immatriculation
+                    && flight.takeoff == unknown
+                    && flight.landing == unknown
+            });
+            match waiting {
+                Some(flight) => flight.takeoff = takeoff,
+                None => flightlog.flights.push(Flight {
+                    ogn_nb: next_ogn_nb,
+                    glider: immatriculation.clone(),
+                    takeoff,
+                    ..Default::default()
+                }),
+            }
+            true
+        }
+        FlightEvent::Landing {
+            immatriculation,
+            time,
+        } => {
+            let landing = zone.time_of_utc(flightlog.date, *time);
+            let in_the_air = flightlog.flights.iter_mut().rev().find(|flight| {
+                flight.glider == *immatriculation
+                    && flight.takeoff != unknown
+                    && flight.landing == unknown
+            });
+            match in_the_air {
+                Some(flight) if flight.takeoff <= landing => {
+                    flight.landing = landing;
+                    true
+                }
+                Some(_) => false,
+                None => {
+                    log::info!(
+                        "Landing of {immatriculation} at {landing} ignored, its takeoff was not seen"
+                    );
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// Returns the airports followed by the feed: the ones having a position.
+pub fn followed_airports(context: &Context) -> Vec<(String, Position)> {
+    context
+        .configuration
+        .airports_configs
+        .iter()
+        .filter_map(|airport| Some((airport.oaci(), airport.position()?)))
+        .collect()
+}
+
+/// Returns the immatriculation of the aircraft that sent a beacon, if it is
+/// logged at some airport.
+fn immatriculation_of(
+    beacon: &Beacon,
+    aprs: &AprsConfiguration,
+    devices: &DeviceDatabase,
+    selections: &[(String, Selection)],
+) -> Option<String> {
+    if let Some(address) = &beacon.address {
+        let device = aprs
+            .devices
+            .iter()
+            .find(|(device, _)| device.eq_ignore_ascii_case(address));
+        if let Some((_, immatriculation)) = device {
+            return Some(immatriculation.clone());
+        }
+        if let Some(immatriculation) = devices.registration(address) {
+            return Some(immatriculation);
+        }
+    }
+    // Some pilots use their immatriculation as callsign.
+    selections
+        .iter()
+        .find_map(|(_, selection)| selection.immatriculation_of_callsign(&beacon.callsign))
+}
+
+/// Returns the selection of every configured airport.
+fn selections(context: &Context) -> Vec<(String, Selection)> {
+    context
+        .configuration
+        .airports_configs
+        .iter()
+        .map(|airport| {
+            let oaci = airport.oaci();
+            let selection = Selection::for_airport(&context.configuration, &oaci);
+            (oaci, selection)
+        })
+        .collect()
+}
+
+/// Reads the beacons of `reader` until its end and updates the flightlogs of
+/// the day. Returns the number of events detected, or an error once the
+/// reader stayed silent for `READ_TIMEOUT_SECS`.
+pub async fn ingest<R: AsyncBufRead + Unpin>(
+    reader: R,
+    context: &Context,
+    detector: &mut FlightDetector,
+) -> Result<usize, AprsError> {
+    let aprs = context.configuration.aprs.clone().unwrap_or_default();
+    let selections = selections(context);
+    let read_timeout = tokio::time::Duration::from_secs(READ_TIMEOUT_SECS);
+    let mut lines = reader.lines();
+    let mut events = 0;
+    while let Some(line) = tokio::time::timeout(read_timeout, lines.next_line())
+        .await
+        .map_err(|_| {
+            format!("Nothing received from the APRS feed for {READ_TIMEOUT_SECS} seconds")
+        })??
+    {
+        if let Some(event) = ingest_line(&line, context, &aprs, &selections, detector).await {
+            log::info!("{:?} detected at {}", event.1, event.0);
+            events += 1;
+        }
+    }
+    Ok(events)
+}
+
+/// Handles a line of the feed and returns the event it showed.
+async fn ingest_line(
+    line: &str,
+    context: &Context,
+    aprs: &AprsConfiguration,
+    selections: &[(String, Selection)],
+    detector: &mut FlightDetector,
+) -> Option<(String, FlightEvent)> {
+    let beacon = parse_beacon(line)?;
+    let immatriculation = immatriculation_of(&beacon, aprs, &context.devices, selections)?;
+    let selects = |(_, selection): &(String, Selection)| {
+        selection.selects(&immatriculation, beacon.aircraft_type)
+    };
+    if !selections.iter().any(selects) {
+        return None;
+    }
+    let (oaci, event) = detector.feed(&immatriculation, &beacon)?;
+    if !selections
+        .iter()
+        .any(|selection| selection.0 == oaci && selects(selection))
+    {
+        return None;
+    }
+    let flightlog_arc = context.flightlogs.get(&oaci)?;
+    let zone = context.configuration.time_zone(&oaci);
+    // Saved under the lock of the day, like the updates.
+    let date = flightlog_arc.lock().unwrap().date;
+    let _day = context.days.lock((oaci.clone(), date)).await;
+    let flightlog = {
+        let mut flightlog = flightlog_arc.lock().unwrap();
+        if !apply_event(&mut flightlog, &event, &zone) {
+            return None;
+        }
+        flightlog.clone()
+    };
+    if let Err(err) = flightlog.save(&oaci, context).await {
+        log::error!("Could not save the flightlog of {oaci} : {err}");
+    }
+    Some((oaci, event))
+}
+
+/// Connects to the APRS server, logs in with a range filter around the
+/// followed airports and ingests the feed until the connection ends.
+pub async fn session(context: &Context, detector: &mut FlightDetector) -> Result<usize, AprsError> {
+    let aprs = context
+        .configuration
+        .aprs
+        .clone()
+        .ok_or("The APRS feed is not configured")?;
+    let filter: Vec<String> = followed_airports(context)
+        .iter()
+        .map(|(_, position)| {
+            format!(
+                "r/{:.4}/{:.4}/{}",
+                position.latitude,
+                position.longitude,
+                aprs.radius_km.ceil() as u32
+            )
+        })
+        .collect();
+    let stream = TcpStream::connect(&aprs.server).await?;
+    let (reader, mut writer) = stream.into_split();
+    let login = format!(
+        "user {} pass -1 vers cepo {} filter {}\r\n",
+        aprs.callsign,
+        env!("CARGO_PKG_VERSION"),
+        filter.join(" ")
+    );
+    writer.write_all(login.as_bytes()).await?;
+    log::info!("Connected to the APRS feed of {}", &aprs.server);
+
+    // The server drops the clients that never write.
+    let keepalive = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(KEEPALIVE_SECS)).await;
+            if writer.write_all(b"#keepalive\r\n").await.is_err() {
+                break;
+            }
+        }
+    });
+    let result = ingest(BufReader::new(reader), context, detector).await;
+    keepalive.abort();
+    result
+}
+
+/// Follows the APRS feed forever, connecting again when it is lost.
+pub async fn run(context: Context) {
+    let radius_km = context
+        .configuration
+        .aprs
+        .as_ref()
+        .map(|aprs| aprs.radius_km)
+        .unwrap_or_default();
+    let mut detector = FlightDetector::new(followed_airports(&context), radius_km);
+    loop {
+        match session(&context, &mut detector).await {
+            Ok(events) => log::warn!("The APRS feed ended after {events} event(s)"),
+            Err(err) => log::error!("Lost the APRS feed : {err}"),
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(RECONNECT_SECS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_event, parse_beacon, FlightDetector, FlightEvent};
+    use crate::configuration::Position;
+    use crate::provenance::Provenance;
+    use crate::reconcile::{reconcile, MatchPolicy};
+    use crate::time_zone::AirportZone;
+    use brick_ogn::flight::Flight;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::NaiveTime;
+
+    #[test]
+    fn parse_position_beacons() {
+        let beacon = parse_beacon(
+            "FLRDD8E3A>OGFLR,qAS,LFLE:/100215h4533.67N/00558.53E'086/054/A=001234 !W52! id06DD8E3A -019fpm +0.0rot",
+        )
+        .unwrap();
+        assert_eq!(beacon.callsign, "FLRDD8E3A");
+        assert_eq!(beacon.address.as_deref(), Some("DD8E3A"));
+        assert_eq!(beacon.aircraft_type, Some(1));
+        assert_eq!(beacon.time, NaiveTime::from_hms_opt(10, 2, 15).unwrap());
+        assert!((beacon.position.latitude - (45.0 + 33.675 / 60.0)).abs() < 1e-9);
+        assert!((beacon.position.longitude - (5.0 + 58.532 / 60.0)).abs() < 1e-9);
+        assert_eq!(beacon.ground_speed_kt, Some(54));
+        assert_eq!(beacon.altitude_ft, Some(1234));
+
+        assert!(parse_beacon("# aprsc 2.1.14 10 Jun 2024 10:02:15 GMT GLIDERN1").is_none());
+        assert!(parse_beacon("LFLE>OGNSDR,TCPIP*,qAC,GLIDERN1:>100215h v0.2.8").is_none());
+        assert!(parse_beacon("FLRDD8E3A>OGFLR:/1002").is_none());
+    }
+
+    #[test]
+    fn detect_takeoff_and_landing_near_airport() {
+        let airport = Position {
+            latitude: 45.5611,
+            longitude: 5.9756,
+        };
+        let far = Position {
+            latitude: 46.5,
+            longitude: 5.9756,
+        };
+        let mut detector = FlightDetector::new(vec![("LFLE".to_string(), airport)], 5.0);
+        let beacon = |speed: u16, position: Position| super::Beacon {
+            callsign: "FLRDD8E3A".to_string(),
+            address: None,
+            aircraft_type: None,
+            time: NaiveTime::from_hms_opt(10, 0, 0).unwrap(),
+            position,
+            ground_speed_kt: Some(speed),
+            altitude_ft: None,
+        };
+        assert_eq!(detector.feed("F-CEJU", &beacon(0, airport)), None);
+        assert_eq!(detector.feed("F-CEJU", &beacon(20, airport)), None);
+        assert!(matches!(
+            detector.feed("F-CEJU", &beacon(45, airport)),
+            Some((_, FlightEvent::Takeoff { .. }))
+        ));
+        assert_eq!(detector.feed("F-CEJU", &beacon(50, airport)), None);
+        // an outlanding is not a landing at the airport
+        assert_eq!(detector.feed("F-CEJU", &beacon(0, far)), None);
+        assert_eq!(detector.feed("F-CEJU", &beacon(50, far)), None);
+        assert!(matches!(
+            detector.feed("F-CEJU", &beacon(3, airport)),
+            Some((oaci, FlightEvent::Landing { .. })) if oaci == "LFLE"
+        ));
+    }
+
+    #[test]
+    fn merge_ogn_flights_in_a_flightlog_from_aprs() {
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let zone = AirportZone(Some(chrono_tz::UTC));
+        let mut flightlog = FlightLog::new();
+        let events = [
+            ("F-CEJU", "10:00", true),
+            ("F-CECY", "10:30", true),
+            ("F-CEJU", "11:00", false),
+            ("F-CECY", "12:00", false),
+        ];
+        for (immatriculation, at, takeoff) in events {
+            let immatriculation = immatriculation.to_string();
+            let event = if takeoff {
+                FlightEvent::Takeoff {
+                    immatriculation,
+                    time: time(at),
+                }
+            } else {
+                FlightEvent::Landing {
+                    immatriculation,
+                    time: time(at),
+                }
+            };
+            assert!(apply_event(&mut flightlog, &event, &zone));
+        }
+        let numbers: Vec<i32> = flightlog.flights.iter().map(|f| f.ogn_nb).collect();
+        assert_eq!(numbers, vec![-1, -2]);
+
+        // OGN numbers the flights of the day in another order
+        let ogn = |ogn_nb, glider: &str, takeoff, landing| Flight {
+            ogn_nb,
+            glider: glider.to_string(),
+            takeoff: time(takeoff),
+            landing: time(landing),
+            takeoff_code: "T".to_string(),
+            ..Default::default()
+        };
+        let report = reconcile(
+            &mut flightlog.flights,
+            vec![
+                ogn(1, "F-CECY", "10:31", "12:01"),
+                ogn(2, "F-CEJU", "10:01", "11:02"),
+            ],
+            &mut Provenance::default(),
+            &MatchPolicy::default(),
+        );
+        assert!(report.created.is_empty());
+        assert_eq!(flightlog.flights.len(), 2);
+        let ceju = &flightlog.flights[0];
+        assert_eq!((ceju.ogn_nb, ceju.glider.as_str()), (2, "F-CEJU"));
+        assert_eq!((ceju.takeoff, ceju.landing), (time("10:01"), time("11:02")));
+        let cecy = &flightlog.flights[1];
+        assert_eq!((cecy.ogn_nb, cecy.glider.as_str()), (1, "F-CECY"));
+        assert_eq!((cecy.takeoff, cecy.landing), (time("10:31"), time("12:01")));
+        assert_eq!(cecy.takeoff_code, "T");
+    }
+
+    #[test]
+    fn a_landing_without_takeoff_is_ignored() {
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let zone = AirportZone(Some(chrono_tz::UTC));
+        let mut flightlog = FlightLog::new();
+        let landing = |at| FlightEvent::Landing {
+            immatriculation: String::from("F-CEJU"),
+            time: time(at),
+        };
+        assert!(!apply_event(&mut flightlog, &landing("11:00"), &zone));
+        assert!(flightlog.flights.is_empty());
+
+        let takeoff = FlightEvent::Takeoff {
+            immatriculation: String::from("F-CEJU"),
+            time: time("12:00"),
+        };
+        assert!(apply_event(&mut flightlog, &takeoff, &zone));
+        // a landing before the takeoff is not its end
+        assert!(!apply_event(&mut flightlog, &landing("11:30"), &zone));
+        assert!(apply_event(&mut flightlog, &landing("13:00"), &zone));
+        assert!(!apply_event(&mut flightlog, &landing("13:05"), &zone));
+        assert_eq!(flightlog.flights.len(), 1);
+        assert_eq!(flightlog.flights[0].landing, time("13:00"));
+    }
+}
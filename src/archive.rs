@@ -0,0 +1,646 @@
+//! Archiving of the finished seasons.
+//! The files of a finished year (or month) of the `YYYY/MM/DD/` tree are
+//! packed into a compressed archive under `archives/` (`YYYY.tar.gz` or
+//! `YYYY-MM.tar.gz`) and removed from the live tree, which then only holds
+//! the current season. The archived files are still read transparently by
+//! the [`crate::storage::JsonFiles`] backend and the audit log: a file of the
+//! live tree always wins over the archived one.
+
+use crate::configuration::StorageConfiguration;
+use crate::nb_2digits_string;
+use crate::storage::{data_dir_lock, StorageError};
+use crate::Context;
+use chrono::{Datelike, NaiveDate};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+
+/// The name of the directory of the archives in the data dir.
+pub const ARCHIVES_DIR: &str = "archives";
+
+/// Which periods are packed into archives once they are finished.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum ArchivePeriod {
+    /// Keep everything in the live tree.
+    #[default]
+    Never,
+    /// One archive per finished year.
+    Year,
+    /// One archive per finished month.
+    Month,
+}
+
+/// A period that can be archived: a year, or a month of a year.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Period {
+    /// The year.
+    pub year: i32,
+    /// The month, if the period is a month.
+    pub month: Option<u32>,
+}
+
+impl Period {
+    /// Returns the name of the archive of the period, like `2023.tar.gz` or
+    /// `2023-06.tar.gz`.
+    pub fn archive_name(&self) -> String {
+        match self.month {
+            Some(month) => format!("{}-{}.tar.gz", self.year, nb_2digits_string(month as i32)),
+            None => format!("{}.tar.gz", self.year),
+        }
+    }
+
+    /// Returns the directory of the period in the live tree.
+    pub fn live_dir(&self, root: &Path) -> PathBuf {
+        let mut path = root.join(self.year.to_string());
+        if let Some(month) = self.month {
+            path.push(nb_2digits_string(month as i32));
+        }
+        path
+    }
+
+    /// Returns the last day of the period.
+    pub fn last_day(&self) -> NaiveDate {
+        let (year, month) = match self.month {
+            Some(12) | None => (self.year + 1, 1),
+            Some(month) => (self.year, month + 1),
+        };
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .and_then(|first| first.pred_opt())
+            .unwrap_or(NaiveDate::MAX)
+    }
+}
+
+/// Returns the path, relative to the data dir and with `/` separators, of the
+/// file `name` of a day: `YYYY/MM/DD/name`.
+pub fn relative_day_path(date: NaiveDate, name: &str) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        date.year(),
+        nb_2digits_string(date.month() as i32),
+        nb_2digits_string(date.day() as i32),
+        name
+    )
+}
+
+/// Returns the archives that may contain the files of a day, the most
+/// specific first.
+pub fn archives_of_day(root: &Path, date: NaiveDate) -> Vec<PathBuf> {
+    let archives = root.join(ARCHIVES_DIR);
+    vec![
+        archives.join(
+            Period {
+                year: date.year(),
+                month: Some(date.month()),
+            }
+            .archive_name(),
+        ),
+        archives.join(
+            Period {
+                year: date.year(),
+                month: None,
+            }
+            .archive_name(),
+        ),
+    ]
+}
+
+/// The number of decoded archives kept in memory: the reads of a day are
+/// usually followed by reads of the days around it, in the same archive.
+const DECODED_ARCHIVES: usize = 2;
+
+/// The files of a decoded archive, by relative path.
+pub type ArchivedFiles = Arc<BTreeMap<String, Vec<u8>>>;
+
+/// An archive decoded in memory, valid while its file is unchanged.
+struct DecodedArchive {
+    /// The path of the archive.
+    path: PathBuf,
+    /// The modification time of the archive when it was decoded.
+    modified: SystemTime,
+    /// The size of the archive when it was decoded.
+    len: u64,
+    /// The files of the archive.
+    files: ArchivedFiles,
+}
+
+/// The archives decoded last, the most recently used first.
+static DECODED: OnceLock<Mutex<Vec<DecodedArchive>>> = OnceLock::new();
+
+/// Returns the decoded archives, recovering them if a reader panicked.
+fn decoded_archives() -> MutexGuard<'static, Vec<DecodedArchive>> {
+    DECODED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Forgets the decoded archives, after the archives of a data dir were
+/// replaced (see [`crate::backup::restore_backup`]).
+pub fn forget_decoded_archives() {
+    decoded_archives().clear();
+}
+
+/// Decodes all the files of an archive.
+fn decode(archive: &Path) -> Result<BTreeMap<String, Vec<u8>>, StorageError> {
+    let mut files = BTreeMap::new();
+    let mut tar_archive = tar::Archive::new(GzDecoder::new(fs::File::open(archive)?));
+    for entry in tar_archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        files.insert(path, content);
+    }
+    Ok(files)
+}
+
+/// Returns the files of an archive, if it exists. An archive is decoded once
+/// and kept in memory while its file is unchanged, so that reading the days
+/// of a season does not decompress the whole season at every read.
+pub fn archived_files(archive: &Path) -> Result<Option<ArchivedFiles>, StorageError> {
+    let metadata = match fs::metadata(archive) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let modified = metadata.modified()?;
+    let len = metadata.len();
+    {
+        let mut decoded = decoded_archives();
+        if let Some(index) = decoded.iter().position(|decoded| decoded.path == archive) {
+            let cached = decoded.remove(index);
+            if cached.modified == modified && cached.len == len {
+                let files = cached.files.clone();
+                decoded.insert(0, cached);
+                return Ok(Some(files));
+            }
+        }
+    }
+    let files = Arc::new(decode(archive)?);
+    let mut decoded = decoded_archives();
+    decoded.retain(|decoded| decoded.path != archive);
+    decoded.insert(
+        0,
+        DecodedArchive {
+            path: archive.to_path_buf(),
+            modified,
+            len,
+            files: files.clone(),
+        },
+    );
+    decoded.truncate(DECODED_ARCHIVES);
+    Ok(Some(files))
+}
+
+/// Returns the content of the archived file of a day (see
+/// [`relative_day_path`]), if it is in an archive.
+pub fn read_archived(
+    root: &Path,
+    date: NaiveDate,
+    name: &str,
+) -> Result<Option<Vec<u8>>, StorageError> {
+    let wanted = relative_day_path(date, name);
+    for archive in archives_of_day(root, date) {
+        if let Some(content) =
+            archived_files(&archive)?.and_then(|files| files.get(&wanted).cloned())
+        {
+            return Ok(Some(content));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the relative paths of the files in an archive.
+pub fn archived_paths(archive: &Path) -> Result<Vec<String>, StorageError> {
+    Ok(archived_files(archive)?
+        .map(|files| files.keys().cloned().collect())
+        .unwrap_or_default())
+}
+
+/// Returns the periods of the live tree that are finished, i.e. whose last day
+/// is older than `final_after_days` before `today`.
+pub fn finished_periods(
+    root: &Path,
+    period: ArchivePeriod,
+    today: NaiveDate,
+    final_after_days: u32,
+) -> Result<Vec<Period>, StorageError> {
+    let mut periods = Vec::new();
+    if period == ArchivePeriod::Never || !root.exists() {
+        return Ok(periods);
+    }
+    for year_dir in numeric_sub_dirs(root)? {
+        let year = year_dir.1 as i32;
+        if period == ArchivePeriod::Year {
+            periods.push(Period { year, month: None });
+        } else {
+            for month_dir in numeric_sub_dirs(&year_dir.0)? {
+                periods.push(Period {
+                    year,
+                    month: Some(month_dir.1),
+                });
+            }
+        }
+    }
+    periods.retain(|period| (today - period.last_day()).num_days() > final_after_days as i64);
+    periods.sort();
+    Ok(periods)
+}
+
+/// The number of times a period is packed again when some of its files were
+/// written while it was being packed.
+const PACKING_ATTEMPTS: usize = 3;
+
+/// Packs the live files of a period into its archive (merged with the files
+/// that were already archived for it, the live ones winning), checks the
+/// archive, then removes the packed files from the live tree. The server may
+/// write to the period meanwhile (a late edit of a day): the archive replaces
+/// the files under the [`data_dir_lock`], and only if none of them changed
+/// since it was packed; otherwise the period is packed again. Returns the
+/// number of files in the archive.
+pub fn archive_period(root: &Path, period: Period) -> Result<usize, StorageError> {
+    let archives = root.join(ARCHIVES_DIR);
+    fs::create_dir_all(&archives)?;
+    let archive = archives.join(period.archive_name());
+
+    // Already archived files, including the monthly archives merged in a
+    // yearly one.
+    let mut merged_archives = vec![archive.clone()];
+    if period.month.is_none() {
+        for month in 1..=12 {
+            merged_archives.push(
+                archives.join(
+                    Period {
+                        year: period.year,
+                        month: Some(month),
+                    }
+                    .archive_name(),
+                ),
+            );
+        }
+    }
+    let mut archived: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    for merged in &merged_archives {
+        if merged.exists() {
+            archived.append(&mut decode(merged)?);
+        }
+    }
+
+    let live_dir = period.live_dir(root);
+    let temporary = archives.join(format!("{}.tmp", period.archive_name()));
+    for _ in 0..PACKING_ATTEMPTS {
+        // The live files replace the archived ones.
+        let mut files = archived.clone();
+        let mut packed = Vec::new();
+        for path in live_files(&live_dir)? {
+            let relative = path
+                .strip_prefix(root)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join("/");
+            let content = fs::read(&path)?;
+            // The journals of lines (like the audit logs) are appended to the
+            // archived ones instead of replacing them.
+            match files.get_mut(&relative) {
+                Some(archived) if relative.ends_with(".jsonl") => {
+                    if !archived.is_empty() && !archived.ends_with(b"\n") {
+                        archived.push(b'\n');
+                    }
+                    archived.extend(&content);
+                }
+                _ => {
+                    files.insert(relative, content.clone());
+                }
+            }
+            packed.push((path, content));
+        }
+        write_archive(&temporary, &files, &period.archive_name())?;
+
+        let _archiving = data_dir_lock().blocking_write();
+        let mut unchanged = true;
+        for (path, content) in &packed {
+            if fs::read(path)? != *content {
+                unchanged = false;
+                break;
+            }
+        }
+        if !unchanged {
+            log::info!(
+                "Files of {:?} were written while packing, packing again",
+                period
+            );
+            continue;
+        }
+        fs::rename(&temporary, &archive)?;
+        fs::File::open(&archives)?.sync_all()?;
+        decoded_archives().retain(|decoded| !merged_archives.contains(&decoded.path));
+        for merged in merged_archives.iter().skip(1) {
+            if merged.exists() {
+                fs::remove_file(merged)?;
+            }
+        }
+        for (path, _) in &packed {
+            fs::remove_file(path)?;
+        }
+        remove_empty_dirs(&live_dir)?;
+        log::info!(
+            "Archived {} file(s) of {:?} in {:?}",
+            files.len(),
+            period,
+            &archive
+        );
+        return Ok(files.len());
+    }
+    fs::remove_file(&temporary)?;
+    Err(format!(
+        "The files of {:?} kept changing while being archived.",
+        period
+    )
+    .into())
+}
+
+/// Writes the `files` of the archive `name` in a compressed archive at
+/// `path`, and checks it.
+fn write_archive(
+    path: &Path,
+    files: &BTreeMap<String, Vec<u8>>,
+    name: &str,
+) -> Result<(), StorageError> {
+    let mut builder =
+        tar::Builder::new(GzEncoder::new(fs::File::create(path)?, Compression::best()));
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content.as_slice())?;
+    }
+    let mut file = builder.into_inner()?.finish()?;
+    file.flush()?;
+    file.sync_all()?;
+    drop(file);
+
+    if decode(path)?.len() != files.len() {
+        fs::remove_file(path)?;
+        return Err(format!("The archive {name} could not be checked.").into());
+    }
+    Ok(())
+}
+
+/// Removes the files `names` from an archive (an unreadable flightlog
+/// quarantined by `fsck`), replacing it under the [`data_dir_lock`].
+pub fn remove_archived(archive: &Path, names: &[String]) -> Result<(), StorageError> {
+    let mut files = decode(archive)?;
+    files.retain(|name, _| !names.contains(name));
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temporary = archive.with_file_name(format!("{name}.tmp"));
+    write_archive(&temporary, &files, &name)?;
+    let _archiving = data_dir_lock().blocking_write();
+    fs::rename(&temporary, archive)?;
+    decoded_archives().retain(|decoded| decoded.path != archive);
+    Ok(())
+}
+
+/// Archives all the finished periods of the live tree.
+pub fn archive_finished(
+    root: &Path,
+    period: ArchivePeriod,
+    today: NaiveDate,
+    final_after_days: u32,
+) -> Result<Vec<(Period, usize)>, StorageError> {
+    let mut archived = Vec::new();
+    for finished in finished_periods(root, period, today, final_after_days)? {
+        let files = archive_period(root, finished)?;
+        archived.push((finished, files));
+    }
+    Ok(archived)
+}
+
+/// Archives the finished periods of the data dir of the context, as
+/// configured by `archive_after`. Only the JSON files storage is archived.
+pub async fn archive_old_seasons(context: &Context) -> Result<Vec<(Period, usize)>, StorageError> {
+    if context.configuration.storage != StorageConfiguration::Files {
+        return Ok(Vec::new());
+    }
+    let root = context.data_dir.clone();
+    let period = context.configuration.archive_after;
+    let final_after_days = context.configuration.final_after_days;
+    // A period is finished once it is finished at every airport.
+    let today = context
+        .configuration
+        .airports_configs
+        .iter()
+        .map(|airport| context.configuration.today(&airport.oaci()))
+        .min()
+        .unwrap_or_else(|| context.configuration.today(&String::new()));
+    tokio::task::spawn_blocking(move || archive_finished(&root, period, today, final_after_days))
+        .await?
+}
+
+/// Returns the sub directories with a number as name, with that number.
+fn numeric_sub_dirs(path: &Path) -> Result<Vec<(PathBuf, u32)>, StorageError> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<u32>().ok());
+        if let (true, Some(number)) = (path.is_dir(), number) {
+            dirs.push((path, number));
+        }
+    }
+    Ok(dirs)
+}
+
+/// Returns the files under `dir`, without the temporary ones.
+fn live_files(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::new();
+    if !dir.exists() {
+        return Ok(files);
+    }
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|extension| extension != "tmp") {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Removes the directories under `dir` (and `dir` itself) that are left
+/// empty.
+fn remove_empty_dirs(dir: &Path) -> Result<(), StorageError> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+        }
+    }
+    if fs::read_dir(dir)?.next().is_none() {
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        archive_finished, archived_paths, decoded_archives, read_archived, ArchivePeriod, Period,
+    };
+    use chrono::NaiveDate;
+    use std::fs;
+
+    #[test]
+    fn last_day_of_periods() {
+        let december = Period {
+            year: 2023,
+            month: Some(12),
+        };
+        assert_eq!(
+            december.last_day(),
+            NaiveDate::from_ymd_opt(2023, 12, 31).unwrap()
+        );
+        let february = Period {
+            year: 2024,
+            month: Some(2),
+        };
+        assert_eq!(
+            february.last_day(),
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()
+        );
+    }
+
+    #[test]
+    fn archive_months_then_year() {
+        let root = std::env::temp_dir().join(format!("cepo-archive-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("2023/06/10")).unwrap();
+        fs::create_dir_all(root.join("2023/07/01")).unwrap();
+        fs::create_dir_all(root.join("2024/06/10")).unwrap();
+        fs::write(root.join("2023/06/10/LFLE.json"), "june").unwrap();
+        fs::write(root.join("2023/07/01/LFLE.json"), "july").unwrap();
+        fs::write(root.join("2024/06/10/LFLE.json"), "current").unwrap();
+        let today = NaiveDate::from_ymd_opt(2024, 6, 11).unwrap();
+        let june = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap();
+
+        let archived = archive_finished(&root, ArchivePeriod::Month, today, 2).unwrap();
+        assert_eq!(archived.len(), 2);
+        assert!(root.join("archives/2023-06.tar.gz").exists());
+        assert!(!root.join("2023/06").exists());
+        assert!(root.join("2024/06/10/LFLE.json").exists());
+        assert_eq!(
+            read_archived(&root, june, "LFLE.json").unwrap(),
+            Some(b"june".to_vec())
+        );
+
+        // the monthly archives are merged in the yearly one
+        archive_finished(&root, ArchivePeriod::Year, today, 2).unwrap();
+        assert!(!root.join("archives/2023-06.tar.gz").exists());
+        assert!(root.join("archives/2023.tar.gz").exists());
+        assert_eq!(
+            read_archived(&root, june, "LFLE.json").unwrap(),
+            Some(b"june".to_vec())
+        );
+        assert_eq!(read_archived(&root, june, "LFLB.json").unwrap(), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn late_writes_are_archived_again() {
+        let root = std::env::temp_dir().join(format!("cepo-archive-late-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("2023/12/30")).unwrap();
+        fs::write(root.join("2023/12/30/LFLE.json"), "first").unwrap();
+        fs::write(root.join("2023/12/30/LFLE.audit.jsonl"), "1\n").unwrap();
+        // a write in progress is not packed nor removed
+        fs::write(root.join("2023/12/30/LFLE.json.42-1.tmp"), "").unwrap();
+        let today = NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+        let day = NaiveDate::from_ymd_opt(2023, 12, 30).unwrap();
+
+        archive_finished(&root, ArchivePeriod::Year, today, 2).unwrap();
+        assert!(!root.join("2023/12/30/LFLE.json").exists());
+        assert!(root.join("2023/12/30/LFLE.json.42-1.tmp").exists());
+        fs::remove_file(root.join("2023/12/30/LFLE.json.42-1.tmp")).unwrap();
+
+        // a late edit of the day, archived on the next run
+        fs::write(root.join("2023/12/30/LFLE.json"), "second").unwrap();
+        fs::write(root.join("2023/12/30/LFLE.audit.jsonl"), "2\n").unwrap();
+        archive_finished(&root, ArchivePeriod::Year, today, 2).unwrap();
+        assert!(!root.join("2023").exists());
+        assert_eq!(
+            read_archived(&root, day, "LFLE.json").unwrap(),
+            Some(b"second".to_vec())
+        );
+        assert_eq!(
+            read_archived(&root, day, "LFLE.audit.jsonl").unwrap(),
+            Some(b"1\n2\n".to_vec())
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn decoded_archives_are_kept_while_unchanged() {
+        let root = std::env::temp_dir().join(format!("cepo-archive-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("2022/05/01")).unwrap();
+        fs::create_dir_all(root.join("2022/05/02")).unwrap();
+        fs::write(root.join("2022/05/01/LFLE.json"), "first").unwrap();
+        fs::write(root.join("2022/05/02/LFLE.json"), "second").unwrap();
+        let today = NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+        let first = NaiveDate::from_ymd_opt(2022, 5, 1).unwrap();
+        let second = NaiveDate::from_ymd_opt(2022, 5, 2).unwrap();
+        let archive = root.join("archives/2022-05.tar.gz");
+
+        archive_finished(&root, ArchivePeriod::Month, today, 2).unwrap();
+        assert_eq!(
+            read_archived(&root, first, "LFLE.json").unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert!(decoded_archives()
+            .iter()
+            .any(|decoded| decoded.path == archive));
+        assert_eq!(archived_paths(&archive).unwrap().len(), 2);
+        assert_eq!(
+            read_archived(&root, second, "LFLE.json").unwrap(),
+            Some(b"second".to_vec())
+        );
+
+        // a late edit archived again replaces the decoded archive
+        fs::create_dir_all(root.join("2022/05/02")).unwrap();
+        fs::write(root.join("2022/05/02/LFLE.json"), "edited").unwrap();
+        archive_finished(&root, ArchivePeriod::Month, today, 2).unwrap();
+        assert_eq!(
+            read_archived(&root, second, "LFLE.json").unwrap(),
+            Some(b"edited".to_vec())
+        );
+        assert_eq!(
+            read_archived(&root, first, "LFLE.json").unwrap(),
+            Some(b"first".to_vec())
+        );
+
+        // an archive that no longer exists is not read from memory
+        fs::remove_file(&archive).unwrap();
+        assert_eq!(read_archived(&root, first, "LFLE.json").unwrap(), None);
+        assert!(archived_paths(&archive).unwrap().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Append-only audit log of the [`Update`]s applied to the flightlogs.
+//! Every accepted update is written once it is saved (or could not be) with
+//! the time it was received, the address of the client, the airport and the
+//! error if it was not saved, in a file next to the flightlog of its day:
+//! `YYYY/MM/DD/OACI.audit.jsonl`. This allows to explain why a flight changed
+//! long after the in-memory updates are gone.
+
+use crate::archive;
+use crate::storage::{append_line, day_dir, StorageError};
+use brick_ogn::flightlog::update::Update;
+use chrono::{DateTime, Local, NaiveDate};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// An update as it was received by the server.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct AuditEntry {
+    /// When the server received the update.
+    pub received_at: DateTime<Local>,
+    /// The address of the client that sent the update.
+    pub client: IpAddr,
+    /// The OACI code of the airport of the flightlog.
+    pub oaci: String,
+    /// The update itself.
+    pub update: Update,
+    /// Why the update could not be saved, if it was not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The audit files of all the days, stored under a root directory.
+pub struct AuditLog {
+    root: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    /// Creates an audit log storing its files under `root`.
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the path of the audit file of a day at an airport.
+    pub fn path(&self, date: NaiveDate, oaci: &str) -> PathBuf {
+        day_dir(&self.root, date).join(format!("{}.audit.jsonl", oaci))
+    }
+
+    /// Appends an entry to the audit file of the day of its update.
+    pub async fn append(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        let path = self.path(entry.update.date, &entry.oaci);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let line = serde_json::to_string(entry)?;
+        let _guard = self.lock.lock().await;
+        append_line(&path, &line).await
+    }
+
+    /// Returns the entries of a day at an airport, in the order they were
+    /// received. If `ogn_nb` is given, only the entries about that flight are
+    /// returned: the ones made under that number, or under one of the
+    /// `former_numbers` it had before OGN numbered it.
+    pub async fn history(
+        &self,
+        date: NaiveDate,
+        oaci: &str,
+        ogn_nb: Option<i32>,
+        former_numbers: &[i32],
+    ) -> Result<Vec<AuditEntry>, StorageError> {
+        let path = self.path(date, oaci);
+        // The entries of an archived day come first, then the ones received
+        // since it was archived.
+        let root = self.root.clone();
+        let name = format!("{}.audit.jsonl", oaci);
+        let archived =
+            tokio::task::spawn_blocking(move || archive::read_archived(&root, date, &name))
+                .await??;
+        let mut content = archived
+            .map(|content| String::from_utf8_lossy(&content).to_string())
+            .unwrap_or_default();
+        if path.exists() {
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&fs::read_to_string(&path).await?);
+        }
+        let mut entries = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) => {
+                    let number = entry.update.ogn_nb;
+                    if ogn_nb.is_none()
+                        || ogn_nb == Some(number)
+                        || former_numbers.contains(&number)
+                    {
+                        entries.push(entry);
+                    }
+                }
+                Err(err) => log::warn!("Skipping a corrupted audit entry in {:?} : {err}", &path),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditEntry, AuditLog};
+    use crate::archive::{archive_finished, ArchivePeriod};
+    use crate::provenance::Provenance;
+    use brick_ogn::flightlog::update::Update;
+    use chrono::{Local, NaiveDate};
+    use std::path::PathBuf;
+
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cepo-audit-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    fn entry(date: NaiveDate, ogn_nb: i32, field: &str) -> AuditEntry {
+        AuditEntry {
+            received_at: Local::now(),
+            client: "127.0.0.1".parse().unwrap(),
+            oaci: String::from("LFLE"),
+            update: Update {
+                ogn_nb,
+                field: field.to_string(),
+                date,
+                time: date.and_hms_opt(12, 0, 0).unwrap(),
+            },
+            error: None,
+        }
+    }
+
+    fn fields(entries: Vec<AuditEntry>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|entry| entry.update.field)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn history_of_a_day_and_of_a_flight() {
+        let root = root("history");
+        let audit = AuditLog::new(root.clone());
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        audit.append(&entry(date, 1, "pilot1")).await.unwrap();
+        audit.append(&entry(date, 2, "glider")).await.unwrap();
+        audit.append(&entry(date, 1, "landing")).await.unwrap();
+        audit
+            .append(&entry(date.succ_opt().unwrap(), 1, "takeoff"))
+            .await
+            .unwrap();
+
+        let day = audit.history(date, "LFLE", None, &[]).await.unwrap();
+        assert_eq!(fields(day), ["pilot1", "glider", "landing"]);
+        let flight = audit.history(date, "LFLE", Some(1), &[]).await.unwrap();
+        assert_eq!(fields(flight), ["pilot1", "landing"]);
+        assert!(audit
+            .history(date, "LFLB", None, &[])
+            .await
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn history_of_an_archived_day() {
+        let root = root("archived");
+        let audit = AuditLog::new(root.clone());
+        let date = NaiveDate::from_ymd_opt(2023, 6, 10).unwrap();
+        audit.append(&entry(date, 1, "pilot1")).await.unwrap();
+        audit.append(&entry(date, 1, "landing")).await.unwrap();
+        let today = NaiveDate::from_ymd_opt(2024, 6, 11).unwrap();
+        let archived = root.clone();
+        tokio::task::spawn_blocking(move || {
+            archive_finished(&archived, ArchivePeriod::Year, today, 2).unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(!audit.path(date, "LFLE").exists());
+        // an update received after the day was archived comes last
+        audit.append(&entry(date, 1, "takeoff")).await.unwrap();
+
+        let flight = audit.history(date, "LFLE", Some(1), &[]).await.unwrap();
+        assert_eq!(fields(flight), ["pilot1", "landing", "takeoff"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn history_of_a_flight_numbered_by_ogn() {
+        let root = root("renumbered");
+        let audit = AuditLog::new(root.clone());
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        audit.append(&entry(date, -1, "pilot1")).await.unwrap();
+        audit.append(&entry(date, -2, "pilot1")).await.unwrap();
+        let mut provenance = Provenance::default();
+        provenance.renumber(-1, 4);
+        audit.append(&entry(date, 4, "landing")).await.unwrap();
+
+        let former_numbers = provenance.former_numbers(4);
+        assert_eq!(former_numbers, [-1]);
+        let flight = audit
+            .history(date, "LFLE", Some(4), &former_numbers)
+            .await
+            .unwrap();
+        assert_eq!(fields(flight), ["pilot1", "landing"]);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Backfill of past days from OGN (`serveur backfill` and
+//! `POST /admin/backfill`).
+//! A past day is otherwise only requested to OGN when someone asks for it, so
+//! the days when the server was down stay empty. The backfill requests every
+//! day of a range, one after the other with a pause between them, merges the
+//! flights in the stored flightlogs and reports what happened to each day.
+//! The server runs it in the background, one at a time per airport, and serves
+//! its progress on `GET /admin/backfill`.
+
+use crate::cache::refresh_day;
+use crate::Context;
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The maximum number of days that can be backfilled at once.
+pub const MAX_BACKFILL_DAYS: i64 = 366;
+
+/// Errors that prevent a backfill from starting.
+pub type BackfillError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What happened to a day.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DayOutcome {
+    /// The flightlog was updated from OGN and saved.
+    Updated {
+        /// The number of flights of the day.
+        flights: usize,
+        /// The number of flights that were not stored before.
+        new_flights: usize,
+    },
+    /// The day was not requested (today is synchronised by the server).
+    Skipped {
+        /// Why the day was skipped.
+        reason: String,
+    },
+    /// OGN or the storage failed, the stored flightlog did not change.
+    Failed {
+        /// The error.
+        error: String,
+    },
+}
+
+/// The outcome of a day of a backfill.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct DayReport {
+    /// The day.
+    pub date: NaiveDate,
+    /// What happened to it.
+    #[serde(flatten)]
+    pub outcome: DayOutcome,
+}
+
+impl fmt::Display for DayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            DayOutcome::Updated {
+                flights,
+                new_flights,
+            } => write!(
+                f,
+                "{} : {} flight(s), {} new",
+                self.date, flights, new_flights
+            ),
+            DayOutcome::Skipped { reason } => write!(f, "{} : skipped, {}", self.date, reason),
+            DayOutcome::Failed { error } => write!(f, "{} : failed, {}", self.date, error),
+        }
+    }
+}
+
+/// The outcome of a backfill.
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize)]
+pub struct BackfillReport {
+    /// The OACI code of the airport.
+    pub oaci: String,
+    /// The outcome of each day, in order.
+    pub days: Vec<DayReport>,
+}
+
+impl BackfillReport {
+    /// Returns the number of days that failed.
+    pub fn failures(&self) -> usize {
+        self.days
+            .iter()
+            .filter(|day| matches!(day.outcome, DayOutcome::Failed { .. }))
+            .count()
+    }
+}
+
+/// The error returned when a backfill of the airport is already running.
+#[derive(Debug)]
+pub struct AlreadyRunning;
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A backfill of the airport is already running.")
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// A backfill running in the background, and the days it did so far.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct BackfillJob {
+    /// The first day of the range.
+    pub from: NaiveDate,
+    /// The last day of the range.
+    pub to: NaiveDate,
+    /// Whether days are still being requested.
+    pub running: bool,
+    /// The days done so far.
+    #[serde(flatten)]
+    pub report: BackfillReport,
+}
+
+/// The backfills started by `POST /admin/backfill`, the last one of each
+/// airport.
+#[derive(Default)]
+pub struct BackfillJobs {
+    jobs: Mutex<HashMap<String, BackfillJob>>,
+}
+
+/// Marks the backfill of an airport as finished, even if it was cancelled.
+struct Finish<'a> {
+    jobs: &'a BackfillJobs,
+    oaci: &'a str,
+}
+
+impl Drop for Finish<'_> {
+    fn drop(&mut self) {
+        if let Some(job) = self.jobs.jobs.lock().unwrap().get_mut(self.oaci) {
+            job.running = false;
+        }
+    }
+}
+
+impl BackfillJobs {
+    /// Creates an empty set of backfills.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the last backfill of an airport.
+    pub fn get(&self, oaci: &str) -> Option<BackfillJob> {
+        self.jobs.lock().unwrap().get(oaci).cloned()
+    }
+
+    /// Records the start of a backfill, unless one is running for the
+    /// airport.
+    fn begin(
+        &self,
+        oaci: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<BackfillJob, BackfillError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.get(oaci).is_some_and(|job| job.running) {
+            return Err(AlreadyRunning.into());
+        }
+        let job = BackfillJob {
+            from,
+            to,
+            running: true,
+            report: BackfillReport {
+                oaci: oaci.to_string(),
+                days: Vec::new(),
+            },
+        };
+        jobs.insert(oaci.to_string(), job.clone());
+        Ok(job)
+    }
+
+    /// Records a day done by the backfill of an airport.
+    fn record(&self, oaci: &str, day: &DayReport) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(oaci) {
+            job.report.days.push(day.clone());
+        }
+    }
+}
+
+/// Starts a backfill of the days from `from` to `to` of an airport in the
+/// background (see [`backfill`]) and returns it. Its progress is kept in the
+/// backfills of the context.
+pub fn start_backfill(
+    context: &Context,
+    oaci: &String,
+    from: NaiveDate,
+    to: NaiveDate,
+    delay: Duration,
+) -> Result<BackfillJob, BackfillError> {
+    check_range(context, oaci, from, to)?;
+    let job = context.backfills.begin(oaci, from, to)?;
+    let context = context.clone();
+    let oaci = oaci.clone();
+    tokio::spawn(async move {
+        let jobs = context.backfills.clone();
+        let _finish = Finish {
+            jobs: &jobs,
+            oaci: &oaci,
+        };
+        let result = run_backfill(&context, &oaci, from, to, delay, |day| {
+            jobs.record(&oaci, day)
+        })
+        .await;
+        if let Err(err) = result {
+            log::error!("Backfill of {oaci} failed : {err}");
+        }
+    });
+    Ok(job)
+}
+
+/// Returns an error if the days from `from` to `to` of an airport cannot be
+/// backfilled.
+fn check_range(
+    context: &Context,
+    oaci: &String,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<(), BackfillError> {
+    context.configuration.airport_configuration(oaci)?;
+    if from > to || (to - from).num_days() >= MAX_BACKFILL_DAYS {
+        return Err(format!(
+            "Invalid range from {from} to {to} (at most {MAX_BACKFILL_DAYS} days)"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Requests the days from `from` to `to` of an airport to OGN, waiting `delay`
+/// between two requests, and merges them in the stored flightlogs.
+pub async fn backfill(
+    context: &Context,
+    oaci: &String,
+    from: NaiveDate,
+    to: NaiveDate,
+    delay: Duration,
+) -> Result<BackfillReport, BackfillError> {
+    check_range(context, oaci, from, to)?;
+    run_backfill(context, oaci, from, to, delay, |_| {}).await
+}
+
+/// Backfills the days of a valid range, calling `on_day` after each day.
+async fn run_backfill(
+    context: &Context,
+    oaci: &String,
+    from: NaiveDate,
+    to: NaiveDate,
+    delay: Duration,
+    mut on_day: impl FnMut(&DayReport),
+) -> Result<BackfillReport, BackfillError> {
+    let today = context.configuration.today(oaci);
+    let mut report = BackfillReport {
+        oaci: oaci.clone(),
+        days: Vec::new(),
+    };
+    let mut requested = false;
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        let outcome = if date >= today {
+            DayOutcome::Skipped {
+                reason: String::from("not a past day"),
+            }
+        } else {
+            if requested {
+                tokio::time::sleep(delay).await;
+            }
+            requested = true;
+            match backfill_day(context, oaci, date).await {
+                Ok(outcome) => outcome,
+                Err(err) => DayOutcome::Failed {
+                    error: err.to_string(),
+                },
+            }
+        };
+        let day = DayReport { date, outcome };
+        log::info!("Backfill of {oaci} : {day}");
+        on_day(&day);
+        report.days.push(day);
+    }
+    Ok(report)
+}
+
+/// Updates a stored day from OGN, sharing the request with the ones of the
+/// same day in progress.
+async fn backfill_day(
+    context: &Context,
+    oaci: &String,
+    date: NaiveDate,
+) -> Result<DayOutcome, BackfillError> {
+    let fetched = refresh_day(date, oaci, context).await?;
+    let report = fetched.update?;
+    Ok(DayOutcome::Updated {
+        flights: fetched.flightlog.flights.len(),
+        new_flights: report.created.len(),
+    })
+}
@@ -0,0 +1,429 @@
+//! Backup and restore of the whole data store.
+//! A backup is a single `.tar.gz` archive containing a `manifest.json`
+//! (format version, date, server version), the `configuration.json` and every
+//! file of the data dir under `data/`: flightlogs, audit logs, journal and a
+//! consistent copy of the SQLite database if it is used.
+//! The admin token is not saved in the archive.
+//! A restore validates the whole archive before replacing the content of the
+//! data dir, and the previous content is kept in it, under
+//! `.before-restore-DATE/`. The data dir itself is neither renamed nor
+//! written next to, so it can be a mount point. Nothing is restored while a
+//! server runs on the data dir.
+
+use crate::archive;
+use crate::configuration::{Configuration, StorageConfiguration};
+use crate::storage::{
+    data_dir_lock, running_server, Sqlite, StorageError, SERVER_PID_FILE, SQLITE_FILE,
+};
+use brick_ogn::flightlog::FlightLog;
+use chrono::{DateTime, Local};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// The directory of the data dir where an archive is unpacked before it
+/// replaces the content of the data dir.
+const RESTORING_DIR: &str = ".restoring";
+
+/// The prefix of the directories of the data dir keeping its content before a
+/// restore.
+const BEFORE_RESTORE_PREFIX: &str = ".before-restore-";
+
+/// The version of the archive format, increased when it changes.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Describes a backup archive.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Manifest {
+    /// The version of the archive format.
+    pub format_version: u32,
+    /// When the backup was made.
+    pub created_at: DateTime<Local>,
+    /// The version of the server that made the backup.
+    pub server_version: String,
+    /// The storage backend that was used.
+    pub storage: StorageConfiguration,
+    /// The number of files of the data dir in the archive.
+    pub files: usize,
+}
+
+/// What was restored from an archive.
+pub struct RestoreReport {
+    /// The manifest of the archive.
+    pub manifest: Manifest,
+    /// The configuration that was saved in the archive, with the data dir
+    /// and the admin token of the local one.
+    pub configuration: Configuration,
+    /// The number of files restored in the data dir.
+    pub files: usize,
+    /// Where the previous content of the data dir was moved, if there was
+    /// one.
+    pub previous_data_dir: Option<PathBuf>,
+}
+
+/// Returns the default name of a backup made now.
+pub fn default_backup_name() -> String {
+    format!(
+        "cepo-backup-{}.tar.gz",
+        Local::now().format("%Y%m%d-%H%M%S")
+    )
+}
+
+/// Writes a backup of the configuration, without its admin token, and of its
+/// data dir to `writer`.
+pub fn write_backup<W: Write>(configuration: &Configuration, writer: W) -> Result<W, StorageError> {
+    let data_dir = configuration.data_dir();
+    let files = data_files(&data_dir)?;
+    let sqlite_path = data_dir.join(SQLITE_FILE);
+    let with_sqlite = configuration.storage == StorageConfiguration::Sqlite && sqlite_path.exists();
+
+    let manifest = Manifest {
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at: Local::now(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        storage: configuration.storage.clone(),
+        files: files.len() + with_sqlite as usize,
+    };
+
+    let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+    append_bytes(
+        &mut builder,
+        "manifest.json",
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    let configuration = Configuration {
+        admin_token: None,
+        ..configuration.clone()
+    };
+    append_bytes(
+        &mut builder,
+        "configuration.json",
+        &serde_json::to_vec_pretty(&configuration)?,
+    )?;
+    for path in &files {
+        let relative = path.strip_prefix(&data_dir)?;
+        builder.append_path_with_name(path, Path::new("data").join(relative))?;
+    }
+    if with_sqlite {
+        let snapshot = std::env::temp_dir().join(format!(
+            "cepo-backup-{}-{}.sqlite",
+            std::process::id(),
+            Local::now().timestamp_micros()
+        ));
+        Sqlite::snapshot(&sqlite_path, &snapshot)?;
+        let appended =
+            builder.append_path_with_name(&snapshot, Path::new("data").join(SQLITE_FILE));
+        fs::remove_file(&snapshot)?;
+        appended?;
+    }
+    let encoder = builder.into_inner()?;
+    Ok(encoder.finish()?)
+}
+
+/// Restores the archive at `archive` in the data dir of the `local`
+/// configuration. Nothing is changed if the archive is not valid or if a
+/// server runs on the data dir. The data dir of the configuration saved in the
+/// archive is the one of the machine where it was made, so the local one is
+/// kept, and so is the local admin token.
+pub fn restore_backup(
+    archive: &Path,
+    local: &Configuration,
+) -> Result<RestoreReport, StorageError> {
+    let data_dir = &local.data_dir();
+    if let Some(pid) = running_server(data_dir) {
+        return Err(format!(
+            "The server (process {pid}) runs on {:?}, stop it before restoring.",
+            data_dir
+        )
+        .into());
+    }
+    let _restoring = data_dir_lock()
+        .try_write()
+        .map_err(|_| "The data dir is being written, try again once it is done.")?;
+    let staging = data_dir.join(RESTORING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    match unpack(archive, &staging) {
+        Ok((manifest, mut configuration, files)) => {
+            configuration.data_dir = Some(data_dir.clone());
+            configuration.admin_token = local.admin_token.clone();
+            let previous = data_dir.join(format!(
+                "{}{}",
+                BEFORE_RESTORE_PREFIX,
+                Local::now().format("%Y%m%d-%H%M%S")
+            ));
+            let previous_data_dir = if move_entries(data_dir, &previous, |name| {
+                name != RESTORING_DIR && !name.starts_with(BEFORE_RESTORE_PREFIX)
+            })? > 0
+            {
+                Some(previous)
+            } else {
+                let _ = fs::remove_dir(&previous);
+                None
+            };
+            move_entries(&staging, data_dir, |_| true)?;
+            fs::remove_dir(&staging)?;
+            archive::forget_decoded_archives();
+            Ok(RestoreReport {
+                manifest,
+                configuration,
+                files,
+                previous_data_dir,
+            })
+        }
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging);
+            Err(err)
+        }
+    }
+}
+
+/// Moves the entries of the directory `from` whose name is accepted by `moved`
+/// to the directory `to`, created if needed. Returns the number of entries
+/// moved.
+fn move_entries(
+    from: &Path,
+    to: &Path,
+    moved: impl Fn(&str) -> bool,
+) -> Result<usize, StorageError> {
+    fs::create_dir_all(to)?;
+    let mut count = 0;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if moved(&name) {
+            fs::rename(entry.path(), to.join(&name))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Unpacks and validates the archive in `staging`.
+fn unpack(
+    archive: &Path,
+    staging: &Path,
+) -> Result<(Manifest, Configuration, usize), StorageError> {
+    let mut manifest: Option<Manifest> = None;
+    let mut configuration: Option<Configuration> = None;
+    let mut files = 0;
+
+    let mut tar_archive = tar::Archive::new(GzDecoder::new(fs::File::open(archive)?));
+    for entry in tar_archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("Unsafe path in the archive: {:?}", path).into());
+        }
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+
+        if path == Path::new("manifest.json") {
+            let read: Manifest = serde_json::from_slice(&content)?;
+            if read.format_version > BACKUP_FORMAT_VERSION {
+                return Err(format!(
+                    "The archive format {} is newer than the supported {}.",
+                    read.format_version, BACKUP_FORMAT_VERSION
+                )
+                .into());
+            }
+            manifest = Some(read);
+        } else if path == Path::new("configuration.json") {
+            configuration = Some(serde_json::from_slice(&content)?);
+        } else if let Ok(relative) = path.strip_prefix("data") {
+            if is_flightlog_path(relative) {
+                serde_json::from_slice::<FlightLog>(&content)
+                    .map_err(|err| format!("Invalid flightlog {:?} : {err}", relative))?;
+            }
+            let destination = staging.join(relative);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(destination, content)?;
+            files += 1;
+        } else {
+            log::warn!("Ignoring unknown file {:?} in the archive", path);
+        }
+    }
+    let manifest = manifest.ok_or("The archive has no manifest.json")?;
+    let configuration = configuration.ok_or("The archive has no configuration.json")?;
+    if files != manifest.files {
+        return Err(format!(
+            "The archive should contain {} data files but contains {}.",
+            manifest.files, files
+        )
+        .into());
+    }
+    Ok((manifest, configuration, files))
+}
+
+/// Returns true for the `YYYY/MM/DD/OACI.json` flightlog files.
+fn is_flightlog_path(relative: &Path) -> bool {
+    let name = relative
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    relative.components().count() == 4
+        && name
+            .strip_suffix(".json")
+            .is_some_and(|stem| !stem.contains('.'))
+}
+
+fn append_bytes<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    content: &[u8],
+) -> Result<(), StorageError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Local::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, name, content)?;
+    Ok(())
+}
+
+/// Returns the files of the data dir to back up: everything but temporary
+/// files, quarantined files, the content kept by previous restores, the mark
+/// of the running server and the live SQLite database (which is copied
+/// separately).
+fn data_files(data_dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::new();
+    if !data_dir.exists() {
+        return Ok(files);
+    }
+    let mut dirs = vec![data_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let top = dir == data_dir;
+            if path.is_dir() {
+                if !(top && (name == "quarantine" || name.starts_with('.'))) {
+                    dirs.push(path);
+                }
+            } else if !(name.ends_with(".tmp")
+                || name.starts_with(SQLITE_FILE)
+                || (top && name == SERVER_PID_FILE))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restore_backup, write_backup};
+    use crate::configuration::Configuration;
+    use crate::storage::SERVER_PID_FILE;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::NaiveDate;
+    use flate2::read::GzDecoder;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    /// Returns the configuration saved in a backup archive.
+    fn saved_configuration(archive: &Path) -> Configuration {
+        let mut archive = tar::Archive::new(GzDecoder::new(fs::File::open(archive).unwrap()));
+        let mut entry = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.path().unwrap() == Path::new("configuration.json"))
+            .unwrap();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        serde_json::from_slice(&content).unwrap()
+    }
+
+    #[test]
+    fn backup_and_restore_round_trip() {
+        let root = std::env::temp_dir().join(format!("cepo-backup-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let data_dir = root.join("data");
+        fs::create_dir_all(data_dir.join("2024/06/10")).unwrap();
+        let mut flightlog = FlightLog::new();
+        flightlog.date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        fs::write(
+            data_dir.join("2024/06/10/LFLE.json"),
+            serde_json::to_string(&flightlog).unwrap(),
+        )
+        .unwrap();
+        fs::write(data_dir.join("2024/06/10/LFLE.audit.jsonl"), "").unwrap();
+        fs::write(data_dir.join("2024/06/10/LFLE.json.tmp"), "garbage").unwrap();
+
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.admin_token = Some(String::from("source"));
+        let archive = root.join("backup.tar.gz");
+        write_backup(&configuration, fs::File::create(&archive).unwrap()).unwrap();
+        // the archive can be downloaded, it does not give the admin token
+        assert_eq!(saved_configuration(&archive).admin_token, None);
+
+        fs::remove_file(data_dir.join("2024/06/10/LFLE.json")).unwrap();
+        let mut local = Configuration::example();
+        local.data_dir = Some(data_dir.clone());
+        local.admin_token = Some(String::from("local"));
+        let report = restore_backup(&archive, &local).unwrap();
+        assert_eq!(report.files, 2);
+        assert!(
+            report.configuration
+                == Configuration {
+                    admin_token: Some(String::from("local")),
+                    ..configuration.clone()
+                }
+        );
+        assert!(data_dir.join("2024/06/10/LFLE.json").exists());
+        assert!(!data_dir.join("2024/06/10/LFLE.json.tmp").exists());
+        // the previous content is kept in the data dir, nothing is written
+        // next to it
+        let previous = report.previous_data_dir.unwrap();
+        assert_eq!(previous.parent(), Some(data_dir.as_path()));
+        assert!(previous.join("2024/06/10/LFLE.json.tmp").exists());
+        let mut names: Vec<String> = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["backup.tar.gz", "data"]);
+        // and it is not backed up again
+        write_backup(&configuration, fs::File::create(&archive).unwrap()).unwrap();
+
+        // nothing is restored while a server runs on the data dir
+        fs::write(data_dir.join(SERVER_PID_FILE), "1").unwrap();
+        assert!(restore_backup(&archive, &local).is_err());
+        fs::remove_file(data_dir.join(SERVER_PID_FILE)).unwrap();
+
+        // the restored configuration reads the data dir it was restored in
+        let copy = root.join("copy");
+        local.data_dir = Some(copy.clone());
+        let report = restore_backup(&archive, &local).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.configuration.data_dir, Some(copy.clone()));
+        assert!(copy.join("2024/06/10/LFLE.json").exists());
+        local.data_dir = Some(data_dir.clone());
+
+        // a corrupted archive does not touch the data dir
+        fs::write(&archive, "not an archive").unwrap();
+        assert!(restore_backup(&archive, &local).is_err());
+        assert!(data_dir.join("2024/06/10/LFLE.json").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
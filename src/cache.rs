@@ -0,0 +1,265 @@
+//! In-memory cache of the flightlogs of past days.
+//! Browsing the previous days should neither reload them from the storage
+//! nor request OGN every time. A past day is refreshed from OGN at most once
+//! every `f_synchronisation_secs` and, once it is older than
+//! `final_after_days`, it is considered final: it is only read from the
+//! storage and never requested to OGN again. The tablets opening the same day
+//! at once share a single request to OGN, and the changes of a day (merges
+//! and updates) are made one at a time.
+
+use crate::flightlog::Storage;
+use crate::reconcile::ReconcileReport;
+use crate::storage::{NotFound, StorageError};
+use crate::Context;
+use brick_ogn::flightlog::FlightLog;
+use chrono::NaiveDate;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A cached flightlog and when it was put in the cache.
+struct CacheEntry {
+    oaci: String,
+    flightlog: FlightLog,
+    cached_at: Instant,
+}
+
+/// A bounded cache of flightlogs, evicting the least recently used ones.
+pub struct FlightLogCache {
+    capacity: usize,
+    entries: Mutex<VecDeque<CacheEntry>>,
+}
+
+impl FlightLogCache {
+    /// Creates a cache keeping at most `capacity` flightlogs.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns the cached flightlog of the day at `oaci` if it was cached less
+    /// than `max_age` ago (or at any time if `max_age` is `None`).
+    pub fn get(&self, date: NaiveDate, oaci: &str, max_age: Option<Duration>) -> Option<FlightLog> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries
+            .iter()
+            .position(|entry| entry.oaci == oaci && entry.flightlog.date == date)?;
+        if max_age.is_some_and(|max_age| entries[index].cached_at.elapsed() > max_age) {
+            return None;
+        }
+        // The most recently used entry goes to the front.
+        let entry = entries.remove(index)?;
+        let flightlog = entry.flightlog.clone();
+        entries.push_front(entry);
+        Some(flightlog)
+    }
+
+    /// Puts a flightlog in the cache, replacing the one of the same day.
+    pub fn insert(&self, oaci: &str, flightlog: FlightLog) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| !(entry.oaci == oaci && entry.flightlog.date == flightlog.date));
+        entries.push_front(CacheEntry {
+            oaci: oaci.to_string(),
+            flightlog,
+            cached_at: Instant::now(),
+        });
+        entries.truncate(self.capacity);
+    }
+}
+
+/// The error returned for a day that has not begun yet at the airport: it has
+/// no flights, so it is neither requested to OGN nor stored.
+#[derive(Debug)]
+pub struct FutureDay;
+
+impl std::fmt::Display for FutureDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The day has not begun yet.")
+    }
+}
+
+impl std::error::Error for FutureDay {}
+
+/// Returns true if the day is old enough to not be requested to OGN anymore.
+pub fn is_final(date: NaiveDate, today: NaiveDate, context: &Context) -> bool {
+    (today - date).num_days() > context.configuration.final_after_days as i64
+}
+
+/// Returns the flightlog of a past day, from the cache if possible, then from
+/// the storage if the day is final, and from the storage updated with OGN
+/// otherwise. A day after `today` is a [`FutureDay`] error.
+pub async fn past_flightlog(
+    date: NaiveDate,
+    oaci: &String,
+    today: NaiveDate,
+    context: &Context,
+) -> Result<FlightLog, StorageError> {
+    if date > today {
+        return Err(FutureDay.into());
+    }
+    let is_final = is_final(date, today, context);
+    let max_age = if is_final {
+        None
+    } else {
+        Some(Duration::from_secs(
+            context.configuration.f_synchronisation_secs.max(0) as u64,
+        ))
+    };
+    if let Some(flightlog) = context.cache.get(date, oaci, max_age) {
+        return Ok(flightlog);
+    }
+    if is_final {
+        match FlightLog::load(date, oaci, context).await {
+            Ok(flightlog) => {
+                context.cache.insert(oaci, flightlog.clone());
+                return Ok(flightlog);
+            }
+            // A day that was never fetched is requested once.
+            Err(err) if err.is::<NotFound>() => {}
+            Err(err) => return Err(err),
+        }
+    }
+    fetch_day(date, oaci, max_age, context)
+        .await
+        .map(|fetched| fetched.flightlog)
+}
+
+/// Returns the latest version of a past day: the cached one if any, else the
+/// stored one, else an empty flightlog of that day. Meant to be called under
+/// the lock of the day in `context.days`, to modify and save it.
+pub async fn latest_flightlog(
+    date: NaiveDate,
+    oaci: &String,
+    context: &Context,
+) -> Result<FlightLog, StorageError> {
+    match context.cache.get(date, oaci, None) {
+        Some(flightlog) => Ok(flightlog),
+        None => stored_flightlog(date, oaci, context).await,
+    }
+}
+
+/// Returns the stored flightlog of a day, or an empty one if it was never
+/// stored. A flightlog that exists but cannot be read is an error, so that it
+/// is not replaced.
+async fn stored_flightlog(
+    date: NaiveDate,
+    oaci: &String,
+    context: &Context,
+) -> Result<FlightLog, StorageError> {
+    match FlightLog::load(date, oaci, context).await {
+        Ok(flightlog) => Ok(flightlog),
+        Err(err) if err.is::<NotFound>() => {
+            let mut flightlog = FlightLog::new();
+            flightlog.date = date;
+            Ok(flightlog)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// A past day updated from OGN, shared by the concurrent requests of the day.
+#[derive(Clone)]
+pub struct FetchedDay {
+    /// The flightlog of the day, the stored one if OGN could not update it.
+    pub flightlog: FlightLog,
+    /// What the update from OGN changed, or why it failed.
+    pub update: Result<ReconcileReport, String>,
+}
+
+/// Requests a past day to OGN again, whatever the cache holds, merges it in
+/// the stored flightlog and saves it. A request of the same day in progress is
+/// shared.
+pub async fn refresh_day(
+    date: NaiveDate,
+    oaci: &String,
+    context: &Context,
+) -> Result<FetchedDay, StorageError> {
+    fetch_day(date, oaci, Some(Duration::ZERO), context).await
+}
+
+/// Updates a day from OGN, saves and caches it. The concurrent requests of the
+/// same day share a single request to OGN and a single save. An error is
+/// returned only if the stored flightlog cannot be read.
+async fn fetch_day(
+    date: NaiveDate,
+    oaci: &String,
+    max_age: Option<Duration>,
+    context: &Context,
+) -> Result<FetchedDay, StorageError> {
+    context
+        .fetches
+        .run((oaci.clone(), date), || async {
+            // Fetched by a request that finished in the meantime.
+            if let Some(flightlog) = context.cache.get(date, oaci, max_age) {
+                return Ok(FetchedDay {
+                    flightlog,
+                    update: Ok(ReconcileReport::default()),
+                });
+            }
+            // An update of the day is not overwritten by the merge.
+            let _day = context.days.lock((oaci.clone(), date)).await;
+            let mut flightlog = stored_flightlog(date, oaci, context)
+                .await
+                .map_err(|err| err.to_string())?;
+            let update = match flightlog.update_ogn(oaci, context).await {
+                Ok(report) => flightlog.save(oaci, context).await.map(|()| report),
+                Err(err) => Err(err),
+            }
+            .map_err(|err| {
+                log::error!(
+                    "Could not update the flightlog of {oaci} on the {date} from OGN : {err}"
+                );
+                err.to_string()
+            });
+            context.cache.insert(oaci, flightlog.clone());
+            Ok(FetchedDay { flightlog, update })
+        })
+        .await
+        .map_err(StorageError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlightLogCache;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::NaiveDate;
+    use std::time::Duration;
+
+    fn flightlog(day: u32) -> FlightLog {
+        let mut flightlog = FlightLog::new();
+        flightlog.date = NaiveDate::from_ymd_opt(2024, 6, day).unwrap();
+        flightlog
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = FlightLogCache::new(2);
+        cache.insert("LFLE", flightlog(1));
+        cache.insert("LFLE", flightlog(2));
+        // using the 1st makes the 2nd the least recently used
+        assert!(cache.get(flightlog(1).date, "LFLE", None).is_some());
+        cache.insert("LFLE", flightlog(3));
+        assert!(cache.get(flightlog(1).date, "LFLE", None).is_some());
+        assert!(cache.get(flightlog(2).date, "LFLE", None).is_none());
+        assert!(cache.get(flightlog(3).date, "LFLE", None).is_some());
+        assert!(cache.get(flightlog(3).date, "LFLB", None).is_none());
+    }
+
+    #[test]
+    fn expired_entries_are_not_returned() {
+        let cache = FlightLogCache::new(2);
+        cache.insert("LFLE", flightlog(1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache
+            .get(flightlog(1).date, "LFLE", Some(Duration::from_millis(1)))
+            .is_none());
+        assert!(cache
+            .get(flightlog(1).date, "LFLE", Some(Duration::from_secs(60)))
+            .is_some());
+    }
+}
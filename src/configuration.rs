@@ -6,10 +6,15 @@
 //! someday a year).
 //! You can specify these lists of pilots etc. globally.
 
-use crate::flightlog::Storage;
+use crate::archive::ArchivePeriod;
+use crate::selection::SelectionRules;
+use crate::storage::Backend;
+use crate::time_zone::AirportZone;
 use brick_ogn::flightlog::FlightLog;
 use chrono::NaiveDate;
-use std::collections::HashMap;
+use chrono_tz::Tz;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 /// An enum about when to monitor an airspace for flights
@@ -21,6 +26,17 @@ pub enum DayMonitor {
     Days(Vec<NaiveDate>),
 }
 
+/// Which backend stores the flightlogs.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug, Default)]
+pub enum StorageConfiguration {
+    /// One JSON file per airport and per day, under `YYYY/MM/DD/OACI.json`.
+    #[default]
+    Files,
+    /// An embedded SQLite database (`cepo.sqlite`), allowing queries per
+    /// pilot, glider or date range.
+    Sqlite,
+}
+
 impl Default for DayMonitor {
     fn default() -> Self {
         return DayMonitor::Always;
@@ -40,6 +56,10 @@ pub struct AirportConfiguration {
     /// A vector of tow pilots that are likely to be in the flightlog of this
     /// airfield
     tow_pilots: Vec<String>,
+    /// The tow pilot on duty, by day, who tows the gliders whose tow pilot is
+    /// not known otherwise
+    #[serde(default)]
+    tow_pilot_duty: BTreeMap<NaiveDate, String>,
     /// A vector of winches that are likely to be in the flightlog of this airfield
     winches: Vec<String>,
     /// A vector of aerotows that are likely to be in the flightlog of this airfield
@@ -48,6 +68,127 @@ pub struct AirportConfiguration {
     day_monitor: DayMonitor,
     /// The immatriculations of the aircraft that we will log
     immatriculations: Vec<String>,
+    /// Where the airfield is, needed to detect takeoffs and landings from the
+    /// live APRS feed.
+    #[serde(default)]
+    position: Option<Position>,
+    /// The immatriculations of the motor gliders of this airfield that launch
+    /// on their own
+    #[serde(default)]
+    self_launchers: Vec<String>,
+    /// Which other aircraft are logged: categories, patterns of
+    /// immatriculations, exclusions or everything seen on the field
+    #[serde(default)]
+    selection: SelectionRules,
+    /// The time zone of the airfield, like `Europe/Paris`, used for its day and
+    /// its times (default to the one of the server)
+    #[serde(default)]
+    time_zone: Option<Tz>,
+}
+
+/// A position in decimal degrees.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct Position {
+    /// The latitude, positive to the north.
+    pub latitude: f64,
+    /// The longitude, positive to the east.
+    pub longitude: f64,
+}
+
+/// How to connect to the live APRS feed of OGN. When it is configured, the
+/// airports having a position get their flights from the feed in near real
+/// time instead of polling the flightbook.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+pub struct AprsConfiguration {
+    /// The `host:port` of the APRS-IS server.
+    #[serde(default = "default_aprs_server")]
+    pub server: String,
+    /// The callsign used to log in (read only, without passcode).
+    #[serde(default = "default_aprs_callsign")]
+    pub callsign: String,
+    /// The radius around the airports in which beacons are received and
+    /// takeoffs and landings are detected, in kilometers.
+    #[serde(default = "default_aprs_radius_km")]
+    pub radius_km: f64,
+    /// The immatriculations of the OGN device addresses (like `DD8E3A`).
+    #[serde(default)]
+    pub devices: HashMap<String, String>,
+}
+
+/// Where the local copy of the OGN devices database is.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct DdbConfiguration {
+    /// The CSV or JSON export of <https://ddb.glidernet.org>.
+    pub path: Option<PathBuf>,
+    /// The corrections of the club, in the same format, that win over the
+    /// DDB and the flightbook.
+    pub overrides: Option<PathBuf>,
+}
+
+/// How the requests to OGN behave when it is slow or down.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct OgnSyncConfiguration {
+    /// How long to wait for an answer of OGN, in seconds.
+    pub timeout_secs: u64,
+    /// How many times a failed request is retried right away.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each next one, in
+    /// seconds.
+    pub retry_base_secs: u64,
+    /// The longest delay between two synchronisations of an airport when OGN
+    /// keeps failing, in seconds.
+    pub max_backoff_secs: u64,
+    /// After how many failures in a row for an airport OGN is not requested
+    /// anymore for that airport for a while.
+    pub breaker_threshold: u32,
+    /// How long OGN is not requested after `breaker_threshold` failures, in
+    /// seconds.
+    pub breaker_cooldown_secs: u64,
+    /// The pause between the days requested by a backfill, in seconds.
+    pub backfill_delay_secs: u64,
+    /// The shortest time between two requests to OGN, all airports together,
+    /// in milliseconds.
+    pub min_request_interval_ms: u64,
+}
+
+impl Default for OgnSyncConfiguration {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            retries: 2,
+            retry_base_secs: 1,
+            max_backoff_secs: 1800,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 600,
+            backfill_delay_secs: 2,
+            min_request_interval_ms: 500,
+        }
+    }
+}
+
+fn default_aprs_server() -> String {
+    String::from("aprs.glidernet.org:14580")
+}
+
+fn default_aprs_callsign() -> String {
+    String::from("CEPO")
+}
+
+fn default_aprs_radius_km() -> f64 {
+    10.0
+}
+
+impl Default for AprsConfiguration {
+    fn default() -> Self {
+        Self {
+            server: default_aprs_server(),
+            callsign: default_aprs_callsign(),
+            radius_km: default_aprs_radius_km(),
+            devices: HashMap::new(),
+        }
+    }
 }
 
 impl Default for AirportConfiguration {
@@ -59,8 +200,13 @@ impl Default for AirportConfiguration {
             winch_pilots: Vec::new(),
             aerotows: Vec::new(),
             tow_pilots: Vec::new(),
+            tow_pilot_duty: BTreeMap::new(),
             day_monitor: DayMonitor::default(),
             immatriculations: Vec::new(),
+            position: None,
+            self_launchers: Vec::new(),
+            selection: SelectionRules::default(),
+            time_zone: None,
         }
     }
 }
@@ -81,6 +227,46 @@ impl AirportConfiguration {
     pub fn immatriculations(&self) -> Vec<String> {
         return self.immatriculations.clone();
     }
+
+    /// Returns the position of the airport, if configured
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// Returns the winches of the airport
+    pub fn winches(&self) -> Vec<String> {
+        self.winches.clone()
+    }
+
+    /// Returns the aerotows of the airport
+    pub fn aerotows(&self) -> Vec<String> {
+        self.aerotows.clone()
+    }
+
+    /// Returns the tow pilots of the airport
+    pub fn tow_pilots(&self) -> Vec<String> {
+        self.tow_pilots.clone()
+    }
+
+    /// Returns the tow pilots on duty of the airport, by day
+    pub fn tow_pilot_duty(&self) -> BTreeMap<NaiveDate, String> {
+        self.tow_pilot_duty.clone()
+    }
+
+    /// Returns the self-launching aircraft of the airport
+    pub fn self_launchers(&self) -> Vec<String> {
+        self.self_launchers.clone()
+    }
+
+    /// Returns the rules selecting the aircraft logged at the airport
+    pub fn selection(&self) -> SelectionRules {
+        self.selection.clone()
+    }
+
+    /// Returns the time zone of the airport
+    pub fn time_zone(&self) -> AirportZone {
+        AirportZone(self.time_zone)
+    }
 }
 
 /// Allows to store and share configuration of the server. Loaded thanks to
@@ -109,6 +295,66 @@ pub struct Configuration {
     pub permanent_aerotows: Vec<String>,
     /// The immatriculations  we always log regardless of the airport
     pub permanent_immatriculations: Vec<String>,
+    /// The immatriculations of the motor gliders that launch on their own,
+    /// whatever the airport.
+    #[serde(default)]
+    pub permanent_self_launchers: Vec<String>,
+    /// Where the flightlogs are stored (default to JSON files).
+    #[serde(default)]
+    pub storage: StorageConfiguration,
+    /// The root directory of the storage. Default to the platform data dir
+    /// (see [`crate::data_dir`]).
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    /// The number of flightlogs of past days kept in memory (default to 64).
+    #[serde(default = "default_cache_capacity")]
+    pub cache_capacity: usize,
+    /// After how many days a past flightlog is final and is not requested to
+    /// OGN anymore (default to 2).
+    #[serde(default = "default_final_after_days")]
+    pub final_after_days: u32,
+    /// The token to give in an `Authorization: Bearer` header to use the
+    /// `/admin` endpoints. They are disabled if it is not set.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Which finished periods of the JSON files are packed into compressed
+    /// archives (default to none).
+    #[serde(default)]
+    pub archive_after: ArchivePeriod,
+    /// The base URL of the OGN flightbook API (default to
+    /// [`crate::ogn::DEFAULT_OGN_URL`]).
+    #[serde(default = "default_ogn_url")]
+    pub ogn_url: String,
+    /// The live APRS feed, disabled if not set.
+    #[serde(default)]
+    pub aprs: Option<AprsConfiguration>,
+    /// Timeouts, retries and backoff of the requests to OGN.
+    #[serde(default)]
+    pub ogn_sync: OgnSyncConfiguration,
+    /// The local copy of the OGN devices database, not used if not set.
+    #[serde(default)]
+    pub ddb: DdbConfiguration,
+    /// The largest difference, in minutes, between the takeoff time typed for
+    /// a flight and the one seen by OGN for them to be the same flight
+    /// (default to 15).
+    #[serde(default = "default_match_tolerance_mins")]
+    pub match_tolerance_mins: u32,
+}
+
+fn default_cache_capacity() -> usize {
+    64
+}
+
+fn default_final_after_days() -> u32 {
+    2
+}
+
+fn default_match_tolerance_mins() -> u32 {
+    15
+}
+
+fn default_ogn_url() -> String {
+    crate::ogn::DEFAULT_OGN_URL.to_string()
 }
 
 impl Default for Configuration {
@@ -124,6 +370,18 @@ impl Default for Configuration {
             permanent_tow_pilots: Vec::new(),
             permanent_winch_pilots: Vec::new(),
             permanent_immatriculations: Vec::new(),
+            permanent_self_launchers: Vec::new(),
+            storage: StorageConfiguration::default(),
+            data_dir: None,
+            cache_capacity: default_cache_capacity(),
+            final_after_days: default_final_after_days(),
+            admin_token: None,
+            archive_after: ArchivePeriod::default(),
+            ogn_url: default_ogn_url(),
+            aprs: None,
+            ogn_sync: OgnSyncConfiguration::default(),
+            ddb: DdbConfiguration::default(),
+            match_tolerance_mins: default_match_tolerance_mins(),
         }
     }
 }
@@ -138,6 +396,7 @@ impl Configuration {
                     pilots: vec![String::from("Walt Disney"), String::from("Roy Disney")],
                     winch_pilots: vec![String::from("Walt Disney"), String::from("Roy Disney")],
                     tow_pilots: vec![String::from("Walt Disney"), String::from("Roy Disney")],
+                    tow_pilot_duty: BTreeMap::new(),
                     winches: vec![String::from("yellow"), String::from("green")],
                     aerotows: vec![String::from("red"), String::from("blue")],
                     day_monitor: DayMonitor::Always,
@@ -147,6 +406,13 @@ impl Configuration {
                         String::from("F-CBAR"),
                         String::from("F-CHFL"),
                     ],
+                    position: Some(Position {
+                        latitude: 45.5611,
+                        longitude: 5.9756,
+                    }),
+                    self_launchers: vec![String::from("F-CHFL")],
+                    selection: SelectionRules::default(),
+                    time_zone: Some(Tz::Europe__Paris),
                 },
                 AirportConfiguration {
                     oaci: String::from("LFLB"),
@@ -156,6 +422,7 @@ impl Configuration {
                         String::from("Pablo Picasso"),
                     ],
                     tow_pilots: vec![String::from("Thomas Edison"), String::from("Pablo Picasso")],
+                    tow_pilot_duty: BTreeMap::new(),
                     winches: vec![String::from("purple"), String::from("pink")],
                     aerotows: vec![String::from("white"), String::from("black")],
                     day_monitor: DayMonitor::Days(vec![
@@ -169,6 +436,17 @@ impl Configuration {
                         String::from("F-CGCZ"),
                         String::from("F-CHFM"),
                     ],
+                    position: Some(Position {
+                        latitude: 45.6381,
+                        longitude: 5.8803,
+                    }),
+                    self_launchers: Vec::new(),
+                    selection: SelectionRules {
+                        patterns: vec![String::from("F-CL*")],
+                        exclusions: vec![String::from("F-CLMT")],
+                        ..Default::default()
+                    },
+                    time_zone: Some(Tz::Europe__Paris),
                 },
             ],
             f_synchronisation_secs: 300,
@@ -184,15 +462,31 @@ impl Configuration {
                 String::from("F-CNON"),
                 String::from("F-CLMT"),
             ],
+            permanent_self_launchers: vec![String::from("F-CMOT")],
+            storage: StorageConfiguration::Files,
+            data_dir: None,
+            cache_capacity: default_cache_capacity(),
+            final_after_days: default_final_after_days(),
+            admin_token: None,
+            archive_after: ArchivePeriod::default(),
+            ogn_url: default_ogn_url(),
+            aprs: None,
+            ogn_sync: OgnSyncConfiguration::default(),
+            ddb: DdbConfiguration::default(),
+            match_tolerance_mins: default_match_tolerance_mins(),
         }
     }
 
     /// Returns a HashMap containing flightlogs associated with their oaci code in String
-    pub async fn create_needed_flightlog_hashmap(&self) -> HashMap<String, Arc<Mutex<FlightLog>>> {
+    pub async fn create_needed_flightlog_hashmap(
+        &self,
+        storage: &Arc<dyn Backend>,
+    ) -> HashMap<String, Arc<Mutex<FlightLog>>> {
         let mut hm = HashMap::new();
         for airport_config in &self.airports_configs {
-            let date_today = chrono::Local::now().date_naive();
-            let flightlog = FlightLog::load(date_today, &airport_config.oaci)
+            let date_today = airport_config.time_zone().today();
+            let flightlog = storage
+                .load(date_today, &airport_config.oaci)
                 .await
                 .unwrap_or_else(|_| {
                     let mut fl = FlightLog::new();
@@ -205,6 +499,15 @@ impl Configuration {
         return hm;
     }
 
+    /// Returns the root directory of the storage: the configured one or the
+    /// platform default.
+    pub fn data_dir(&self) -> PathBuf {
+        match &self.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            None => crate::data_dir(),
+        }
+    }
+
     /// Returns permanent pilots field
     pub fn permanent_pilots(&self) -> Vec<String> {
         return self.permanent_pilots.clone();
@@ -230,6 +533,11 @@ impl Configuration {
         return self.permanent_aerotows.clone();
     }
 
+    /// Returns permanent self_launchers field
+    pub fn permanent_self_launchers(&self) -> Vec<String> {
+        self.permanent_self_launchers.clone()
+    }
+
     /// Returns permanent immatriculations field
     pub fn permanent_immatriculations(&self) -> Vec<String> {
         return self.permanent_immatriculations.clone();
@@ -245,6 +553,19 @@ impl Configuration {
         return Err(String::from("Couldn't find a corresponding airport"));
     }
 
+    /// Returns the time zone of an airport, the one of the server if it is
+    /// unknown
+    pub fn time_zone(&self, oaci: &String) -> AirportZone {
+        self.airport_configuration(oaci)
+            .map(|airport| airport.time_zone())
+            .unwrap_or_default()
+    }
+
+    /// Returns the current day at an airport
+    pub fn today(&self, oaci: &String) -> NaiveDate {
+        self.time_zone(oaci).today()
+    }
+
     /// Returns a vector of the global immatriculations and the ones for the airport
     pub fn immatriculation_ap(&mut self, oaci: &String) -> Vec<String> {
         let mut immatriculations = self.permanent_immatriculations.clone();
@@ -263,7 +584,12 @@ impl Configuration {
             immatriculations: self.permanent_immatriculations(),
             pilots: self.permanent_pilots(),
             tow_pilots: self.permanent_tow_pilots(),
+            tow_pilot_duty: BTreeMap::new(),
             winch_pilots: self.permanent_winch_pilots(),
+            position: None,
+            self_launchers: self.permanent_self_launchers(),
+            selection: SelectionRules::default(),
+            time_zone: None,
         };
         let ap_config = self.airport_configuration(oaci).unwrap();
         return (ap_config, global_config);
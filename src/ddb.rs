@@ -0,0 +1,254 @@
+//! Local copy of the OGN device database (DDB).
+//! The flightbook only gives the registration that the owner of a device wrote
+//! in the DDB, which is often empty or wrong. A copy of the DDB downloaded from
+//! <https://ddb.glidernet.org/download/> (CSV, or JSON with `?j=1`) and a file
+//! of corrections kept by the club, in the same format, resolve the devices to
+//! their registration and competition number. The corrections always win, the
+//! DDB only fills what the flightbook left empty.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Errors returned while reading a device database.
+pub type DdbError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A device of the database.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+pub struct DdbDevice {
+    /// The type of address (`F` for FLARM, `I` for ICAO, `O` for OGN).
+    #[serde(default)]
+    pub device_type: String,
+    /// The address of the device, like `DD8E3A`.
+    pub device_id: String,
+    /// The model of the aircraft.
+    #[serde(default)]
+    pub aircraft_model: String,
+    /// The registration of the aircraft.
+    #[serde(default)]
+    pub registration: String,
+    /// The competition number of the aircraft.
+    #[serde(default)]
+    pub cn: String,
+    /// Whether the owner agreed to the device being tracked.
+    #[serde(default = "yes", deserialize_with = "deserialize_flag")]
+    pub tracked: bool,
+    /// Whether the owner agreed to the aircraft being identified.
+    #[serde(default = "yes", deserialize_with = "deserialize_flag")]
+    pub identified: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Reads the `Y`/`N` flags of the DDB.
+fn deserialize_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let flag: String = serde::Deserialize::deserialize(deserializer)?;
+    Ok(parse_flag(&flag))
+}
+
+/// Only `N` is false, a missing flag is true.
+fn parse_flag(flag: &str) -> bool {
+    !flag.trim().eq_ignore_ascii_case("n")
+}
+
+/// The JSON export of the DDB.
+#[derive(serde::Deserialize)]
+struct DdbExport {
+    devices: Vec<DdbDevice>,
+}
+
+/// The devices known by their address.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeviceDatabase {
+    devices: HashMap<String, DdbDevice>,
+    overrides: HashMap<String, DdbDevice>,
+}
+
+impl DeviceDatabase {
+    /// Creates an empty database.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the DDB and the corrections of the club, both optional.
+    pub fn load(ddb: Option<&Path>, overrides: Option<&Path>) -> Result<Self, DdbError> {
+        let mut database = Self::new();
+        if let Some(path) = ddb {
+            database.devices = read_devices(path)?;
+        }
+        if let Some(path) = overrides {
+            database.overrides = read_devices(path)?;
+        }
+        Ok(database)
+    }
+
+    /// Returns the number of devices of the DDB and of the corrections.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.devices.len(), self.overrides.len())
+    }
+
+    /// Returns true if there is no device at all.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.overrides.is_empty()
+    }
+
+    /// Returns the correction of the club for a device.
+    pub fn correction(&self, address: &str) -> Option<&DdbDevice> {
+        self.overrides.get(&address.to_uppercase())
+    }
+
+    /// Returns the DDB entry of a device, if its owner agreed to it being
+    /// tracked and identified.
+    pub fn entry(&self, address: &str) -> Option<&DdbDevice> {
+        self.devices
+            .get(&address.to_uppercase())
+            .filter(|device| device.tracked && device.identified)
+    }
+
+    /// Returns the registration of a device.
+    pub fn registration(&self, address: &str) -> Option<String> {
+        [self.correction(address), self.entry(address)]
+            .into_iter()
+            .flatten()
+            .map(|device| device.registration.clone())
+            .find(|registration| !registration.is_empty())
+    }
+
+    /// Resolves the fields of a device given by the flightbook: the
+    /// corrections replace them, the DDB fills the empty ones.
+    pub fn resolve(
+        &self,
+        address: &str,
+        registration: &mut Option<String>,
+        competition: &mut Option<String>,
+        model: &mut Option<String>,
+    ) {
+        let fields = |device: &DdbDevice| {
+            [
+                device.registration.clone(),
+                device.cn.clone(),
+                device.aircraft_model.clone(),
+            ]
+        };
+        let mut targets = [registration, competition, model];
+        if let Some(correction) = self.correction(address) {
+            for (target, value) in targets.iter_mut().zip(fields(correction)) {
+                if !value.is_empty() {
+                    **target = Some(value);
+                }
+            }
+        }
+        if let Some(entry) = self.entry(address) {
+            for (target, value) in targets.iter_mut().zip(fields(entry)) {
+                if target.as_deref().unwrap_or_default().is_empty() && !value.is_empty() {
+                    **target = Some(value);
+                }
+            }
+        }
+    }
+}
+
+/// Reads a CSV or JSON export of the DDB.
+fn read_devices(path: &Path) -> Result<HashMap<String, DdbDevice>, DdbError> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read the device database {:?} : {err}", path))?;
+    let devices = if content.trim_start().starts_with('{') {
+        serde_json::from_str::<DdbExport>(&content)?.devices
+    } else {
+        parse_csv(&content)
+    };
+    Ok(devices
+        .into_iter()
+        .filter(|device| !device.device_id.is_empty())
+        .map(|device| (device.device_id.to_uppercase(), device))
+        .collect())
+}
+
+/// Parses the CSV export of the DDB, like
+/// `'F','DD8E3A','Pegase','F-CEJU','JU','Y','Y'`, skipping the header and the
+/// lines that are too short.
+pub fn parse_csv(content: &str) -> Vec<DdbDevice> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields = split_csv_line(line);
+            if fields.len() < 5 {
+                log::warn!("Skipping a malformed line of the device database : {line}");
+                return None;
+            }
+            Some(DdbDevice {
+                device_type: fields[0].clone(),
+                device_id: fields[1].clone(),
+                aircraft_model: fields[2].clone(),
+                registration: fields[3].clone(),
+                cn: fields[4].clone(),
+                tracked: parse_flag(fields.get(5).map_or("", String::as_str)),
+                identified: parse_flag(fields.get(6).map_or("", String::as_str)),
+            })
+        })
+        .collect()
+}
+
+/// Splits a CSV line whose fields may be quoted with `'` or `"`.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quote = None;
+    for c in line.trim_end_matches('\r').chars() {
+        match (quote, c) {
+            (None, '\'' | '"') if field.trim().is_empty() => {
+                field.clear();
+                quote = Some(c);
+            }
+            (Some(open), c) if c == open => quote = None,
+            (None, ',') => fields.push(std::mem::take(&mut field).trim().to_string()),
+            (_, c) => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_csv, DeviceDatabase};
+
+    #[test]
+    fn resolve_devices_with_corrections() {
+        let devices = parse_csv(
+            "#DEVICE_TYPE,DEVICE_ID,AIRCRAFT_MODEL,REGISTRATION,CN,TRACKED,IDENTIFIED\n\
+             'F','DD8E3A','Pegase','F-CEJU','JU','Y','Y'\n\
+             'F','DDA1B4','ASK 21, two seater','','CY','Y','Y'\n\
+             'F','DD0042','LS-4','D-1234','34','Y','N'\n\
+             'F','DD0043'\n",
+        );
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[1].aircraft_model, "ASK 21, two seater");
+        assert!(!devices[2].identified);
+
+        let mut database = DeviceDatabase::new();
+        database.devices = devices
+            .into_iter()
+            .map(|device| (device.device_id.clone(), device))
+            .collect();
+        database.overrides = parse_csv("'F','dda1b4','','F-CECY','',''")
+            .into_iter()
+            .map(|device| (device.device_id.to_uppercase(), device))
+            .collect();
+
+        assert_eq!(database.registration("dd8e3a").as_deref(), Some("F-CEJU"));
+        assert_eq!(database.registration("DDA1B4").as_deref(), Some("F-CECY"));
+        // the owner did not agree to be identified
+        assert_eq!(database.registration("DD0042"), None);
+
+        let mut registration = Some(String::from("F-WRONG"));
+        let mut competition = None;
+        let mut model = Some(String::from("ASK-21"));
+        database.resolve("DDA1B4", &mut registration, &mut competition, &mut model);
+        assert_eq!(registration.as_deref(), Some("F-CECY"));
+        assert_eq!(competition.as_deref(), Some("CY"));
+        assert_eq!(model.as_deref(), Some("ASK-21"));
+    }
+}
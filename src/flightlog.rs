@@ -1,15 +1,17 @@
 //! FlightLog: an object to represent a group of flights and the organization
 //! on the ground at the moment.
 
-use crate::ogn::ogn_flights;
+use crate::launch::fill_tow_pilots;
+use crate::ogn::{ogn_flights, LogbookSettings};
+use crate::reconcile::{reconcile, ReconcileReport};
+use crate::storage::NotFound;
 use crate::Context;
-use crate::{create_fs_path_day, nb_2digits_string};
 use async_trait::async_trait;
 pub use brick_ogn::flightlog::update::Update;
 use brick_ogn::flightlog::FlightLog;
-use chrono::{Datelike, NaiveDate, NaiveTime};
+use chrono::NaiveDate;
 use log;
-use tokio::fs;
+use std::sync::{Arc, Mutex};
 
 /// A trait that cares about the storage of a FlightLog on a computer.
 #[async_trait]
@@ -20,19 +22,25 @@ pub trait Storage {
         oaci: &String,
         context: &Context,
     ) -> Result<FlightLog, Box<dyn std::error::Error + Send + Sync>>;
-    /// Updating the flightlog from ogn using today's date.
+    /// Updating the flightlog from ogn using today's date, returning what
+    /// changed.
     async fn update_ogn(
         &mut self,
         oaci: &String,
         context: &Context,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    /// Loading FlightLog from the disk only, without updating.
+    ) -> Result<ReconcileReport, Box<dyn std::error::Error + Send + Sync>>;
+    /// Loading FlightLog from the storage backend only, without updating.
     async fn load(
         date: NaiveDate,
         oaci: &String,
+        context: &Context,
     ) -> Result<FlightLog, Box<dyn std::error::Error + Send + Sync>>;
-    /// Savinfg on the disk only, wuthout updating/
-    async fn save(&self, oaci: &String);
+    /// Saving in the storage backend only, without updating.
+    async fn save(
+        &self,
+        oaci: &String,
+        context: &Context,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
 }
 
 #[async_trait]
@@ -43,20 +51,22 @@ impl Storage for FlightLog {
         oaci: &String,
         context: &Context,
     ) -> Result<FlightLog, Box<dyn std::error::Error + Send + Sync>> {
-        let year: i32 = date.year();
-        let month = date.month();
-        let day = date.day();
-
-        create_fs_path_day(year, month, day);
-        let mut flightlog = FlightLog::load(date, oaci).await.unwrap_or_else(|err| {
-            log::warn!("Could not load flightlog from disk, trying to update from OGN : {err}");
-            let mut fl = FlightLog::default();
-            fl.date = date;
-            fl
-        });
+        let mut flightlog = match FlightLog::load(date, oaci, context).await {
+            Ok(flightlog) => flightlog,
+            Err(err) if err.is::<NotFound>() => {
+                log::warn!("No flightlog on the disk, trying to update from OGN : {err}");
+                let mut fl = FlightLog::default();
+                fl.date = date;
+                fl
+            }
+            // Do not replace a flightlog that exists but cannot be read.
+            Err(err) => return Err(err),
+        };
         match flightlog.update_ogn(oaci, context).await {
             Ok(_) => {
-                let _ = flightlog.save(oaci).await;
+                if let Err(err) = flightlog.save(oaci, context).await {
+                    log::error!("Could not save the flightlog of {oaci} on the {date} : {err}");
+                }
             }
             Err(err) => {
                 log::error!("Could not connect to OGN ! : {err}");
@@ -70,109 +80,74 @@ impl Storage for FlightLog {
         &mut self,
         oaci: &String,
         context: &Context,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // We test equalities and we replace if needed.
-        let immatriculations = context
-            .configuration
-            .airport_configuration(oaci)
-            .unwrap()
-            .immatriculations();
-        let last_flights_fut = ogn_flights(self.date, immatriculations, (*oaci).clone());
-        let mut index_next_flight = 0;
-        let mut priority_next_flight = 0;
-        let old_flightlog = self;
-        #[allow(unused_assignments)]
-        for (mut index_new_flight, new_flight) in last_flights_fut.await?.into_iter().enumerate() {
-            let mut exists = false;
-            for old_flight in &mut old_flightlog.flights {
-                // if on the same flight
-                if new_flight.ogn_nb == old_flight.ogn_nb {
-                    exists = true;
-                    let heure_default = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
-                    //test the different values that can be updated
-                    if old_flight.takeoff == heure_default {
-                        old_flight.takeoff = new_flight.takeoff;
-                    }
-                    if old_flight.landing == heure_default {
-                        old_flight.landing = new_flight.landing;
-                    }
-                } else if new_flight.glider == old_flight.glider {
-                    if priority_next_flight != 0 {
-                        if priority_next_flight < new_flight.ogn_nb && new_flight.ogn_nb < 0 {
-                            exists = true;
-                            priority_next_flight = new_flight.ogn_nb;
-                            index_next_flight = index_new_flight;
-                        }
-                    } else if new_flight.ogn_nb < 0 && priority_next_flight == 0 {
-                        exists = true;
-                        priority_next_flight = new_flight.ogn_nb;
-                        index_next_flight = index_new_flight;
-                    }
-                }
-            }
-            if priority_next_flight != 0 {
-                // We get the flight with the highest priority and we write on it the data from OGN.
-                old_flightlog.flights[index_next_flight].ogn_nb = new_flight.ogn_nb;
-                old_flightlog.flights[index_next_flight].takeoff_code =
-                    new_flight.takeoff_code.clone();
-                old_flightlog.flights[index_next_flight].takeoff = new_flight.takeoff;
-                old_flightlog.flights[index_next_flight].landing = new_flight.landing;
-            }
-            if !exists {
-                old_flightlog.flights.push(new_flight);
-            }
-            index_new_flight += 1;
+    ) -> Result<ReconcileReport, Box<dyn std::error::Error + Send + Sync>> {
+        let settings = LogbookSettings::for_airport(context, oaci);
+        let (flights, aircraft) =
+            ogn_flights(self.date, (*oaci).clone(), context.ogn.as_ref(), &settings).await?;
+        let report = context
+            .provenance
+            .update(self.date, oaci, |provenance| {
+                reconcile(&mut self.flights, flights, provenance, &settings.matching)
+            })
+            .await;
+        fill_tow_pilots(self, &settings.launch);
+        if let Err(err) = context.aircraft.record(self.date, oaci, aircraft).await {
+            log::error!(
+                "Could not save the aircraft of {oaci} on the {} : {err}",
+                self.date
+            );
         }
-        Ok(())
+        Ok(report)
     }
 
-    /// Returns the flightlog from day and airfield that matches `oaci` from local files
+    /// Returns the flightlog from day and airfield that matches `oaci` from the
+    /// storage backend of the context.
     async fn load(
         date: NaiveDate,
         oaci: &String,
+        context: &Context,
     ) -> Result<FlightLog, Box<dyn std::error::Error + Send + Sync>> {
-        let year = date.year();
-        let month = date.month();
-        let day = date.day();
-        log::info!(
-            "Loading FlightLog at {} from the disk {}-{}-{}",
-            oaci,
-            year,
-            month,
-            day
-        );
-
-        let month_str = nb_2digits_string(month as i32);
-        let day_str = nb_2digits_string(day as i32);
-
-        let mut path = crate::data_dir();
-        path.push(format!("{}/{}/{}/{}.json", year, month_str, day_str, oaci));
-
-        if path.exists() {
-            let flightlog_str = fs::read_to_string(path).await.unwrap_or_default();
-            let flightlog = serde_json::from_str(&flightlog_str)?;
-            Ok(flightlog)
-        } else {
-            Err("No FlightLog found for the date.".into())
-        }
+        context.storage.load(date, oaci).await
     }
 
-    async fn save(&self, oaci: &String) {
-        let date = self.date;
-        let year = date.year();
-        let month = date.month();
-        let day = date.day();
-
-        let day_str = nb_2digits_string(day as i32);
-        let month_str = nb_2digits_string(month as i32);
-
-        let mut file_path = crate::data_dir();
-        file_path.push(format!("{}/{}/{}/{}.json", year, month_str, day_str, oaci));
-
-        fs::write(&file_path, serde_json::to_string(self).unwrap_or_default())
-            .await
-            .unwrap();
+    async fn save(
+        &self,
+        oaci: &String,
+        context: &Context,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        context.storage.save(self, oaci).await
+    }
+}
 
-        log::info!("Saved FlightLog of the {year}-{month_str}-{day_str}.");
+/// Starts the flightlog of the new day of an airport once its local midnight
+/// is over: the flightlog of the day that ended is saved, and the one of the
+/// new day is loaded from the storage or created. Returns true if the day
+/// changed.
+pub async fn roll_over(
+    flightlog_arc: &Arc<Mutex<FlightLog>>,
+    oaci: &String,
+    context: &Context,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let today = context.configuration.today(oaci);
+    let finished = {
+        let flightlog = flightlog_arc.lock().unwrap();
+        if flightlog.date >= today {
+            return Ok(false);
+        }
+        flightlog.clone()
+    };
+    finished.save(oaci, context).await?;
+    let new_day = FlightLog::load(today, oaci, context)
+        .await
+        .unwrap_or_else(|_| {
+            let mut fl = FlightLog::new();
+            fl.date = today;
+            fl
+        });
+    let mut flightlog = flightlog_arc.lock().unwrap();
+    if flightlog.date < today {
+        log::info!("New day at {oaci} : {today}");
+        *flightlog = new_day;
     }
+    Ok(true)
 }
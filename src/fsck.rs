@@ -0,0 +1,539 @@
+//! Integrity check of the storage (`serveur fsck`).
+//! Every stored flightlog, live, archived or in the database, is validated
+//! against its place in the storage and the configuration of the airports.
+//! Problems are reported and, on demand, repaired or the unreadable files
+//! moved to a `quarantine` directory.
+
+use crate::archive::{self, ARCHIVES_DIR};
+use crate::configuration::StorageConfiguration;
+use crate::storage::{temporary_file_target, Sqlite, StorageError, SQLITE_FILE};
+use crate::Context;
+use brick_ogn::flightlog::FlightLog;
+use chrono::{NaiveDate, NaiveTime};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What to do with the problems that are found.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FsckOptions {
+    /// Fix the problems that can be fixed and save the flightlogs.
+    pub repair: bool,
+    /// Move the files that cannot be read to `quarantine/` in the data dir.
+    pub quarantine: bool,
+}
+
+/// The kind of problem found in the storage.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProblemKind {
+    /// The file cannot be parsed as a flightlog.
+    Unparsable(String),
+    /// The date written in the flightlog is not the one of its place.
+    WrongDate(NaiveDate),
+    /// The airport is not in the configuration.
+    UnknownAirport,
+    /// Several flights share the same `ogn_nb`.
+    DuplicateOgnNb(i32),
+    /// The flight with this `ogn_nb` lands before it takes off.
+    LandingBeforeTakeoff(i32),
+    /// A temporary file left by an interrupted write.
+    LeftoverTemporaryFile,
+    /// The day of a flightlog of the database (written there) cannot be
+    /// parsed. The problem has the default date.
+    UnreadableDay(String, String),
+    /// An archive (named there) cannot be decompressed. The problem has the
+    /// default date and no airport.
+    UnreadableArchive(String, String),
+}
+
+/// A problem found in the storage, and what was done about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// The day of the flightlog.
+    pub date: NaiveDate,
+    /// The OACI code of the airport.
+    pub oaci: String,
+    /// The problem itself.
+    pub kind: ProblemKind,
+    /// Whether the problem was repaired or the file quarantined.
+    pub fixed: bool,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ProblemKind::UnreadableDay(day, _) => write!(f, "{day} {}: ", self.oaci)?,
+            ProblemKind::UnreadableArchive(_, _) => {}
+            _ => write!(f, "{} {}: ", self.date, self.oaci)?,
+        }
+        match &self.kind {
+            ProblemKind::Unparsable(err) => write!(f, "cannot be read ({err})")?,
+            ProblemKind::WrongDate(date) => write!(f, "the flightlog is dated {date}")?,
+            ProblemKind::UnknownAirport => write!(f, "airport not in the configuration")?,
+            ProblemKind::DuplicateOgnNb(nb) => write!(f, "several flights with ogn_nb {nb}")?,
+            ProblemKind::LandingBeforeTakeoff(nb) => {
+                write!(f, "flight {nb} lands before taking off")?
+            }
+            ProblemKind::LeftoverTemporaryFile => write!(f, "leftover temporary file")?,
+            ProblemKind::UnreadableDay(_, err) => write!(f, "the day cannot be read ({err})")?,
+            ProblemKind::UnreadableArchive(name, err) => {
+                write!(f, "archive {name} cannot be read ({err})")?
+            }
+        }
+        if self.fixed {
+            write!(f, " [fixed]")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a check of the storage.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FsckReport {
+    /// The number of flightlogs checked.
+    pub checked: usize,
+    /// The problems found.
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    /// Returns true if some problems were not fixed.
+    pub fn has_remaining_problems(&self) -> bool {
+        self.problems.iter().any(|problem| !problem.fixed)
+    }
+}
+
+/// Returns the problems of a flightlog stored for `date` at `oaci`, without
+/// fixing them.
+pub fn check_flightlog(
+    flightlog: &FlightLog,
+    date: NaiveDate,
+    oaci: &str,
+    context: &Context,
+) -> Vec<ProblemKind> {
+    let mut problems = Vec::new();
+    if flightlog.date != date {
+        problems.push(ProblemKind::WrongDate(flightlog.date));
+    }
+    if context
+        .configuration
+        .airport_configuration(&oaci.to_string())
+        .is_err()
+    {
+        problems.push(ProblemKind::UnknownAirport);
+    }
+    let mut seen: Vec<i32> = Vec::new();
+    for flight in &flightlog.flights {
+        if seen.contains(&flight.ogn_nb) {
+            let problem = ProblemKind::DuplicateOgnNb(flight.ogn_nb);
+            if !problems.contains(&problem) {
+                problems.push(problem);
+            }
+        } else {
+            seen.push(flight.ogn_nb);
+        }
+        let unknown = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+        if flight.landing != unknown && flight.landing < flight.takeoff {
+            problems.push(ProblemKind::LandingBeforeTakeoff(flight.ogn_nb));
+        }
+    }
+    problems
+}
+
+/// Fixes what can be fixed in a flightlog stored for `date`: the date is set
+/// to the one of its place, duplicated flights get new negative `ogn_nb` (like
+/// manually created flights) and impossible landing times are reset so that
+/// OGN can fill them again. Returns true if the flightlog changed.
+pub fn repair_flightlog(flightlog: &mut FlightLog, date: NaiveDate) -> bool {
+    let mut changed = false;
+    if flightlog.date != date {
+        flightlog.date = date;
+        changed = true;
+    }
+    let mut next_negative = flightlog
+        .flights
+        .iter()
+        .map(|flight| flight.ogn_nb)
+        .filter(|nb| *nb < 0)
+        .min()
+        .unwrap_or(0)
+        - 1;
+    let mut seen: Vec<i32> = Vec::new();
+    let unknown = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    for flight in &mut flightlog.flights {
+        if seen.contains(&flight.ogn_nb) {
+            flight.ogn_nb = next_negative;
+            next_negative -= 1;
+            changed = true;
+        }
+        seen.push(flight.ogn_nb);
+        if flight.landing != unknown && flight.landing < flight.takeoff {
+            flight.landing = unknown;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Checks (and repairs if asked) the whole storage of the context.
+pub async fn fsck(context: &Context, options: FsckOptions) -> Result<FsckReport, StorageError> {
+    let mut report = FsckReport::default();
+    match context.configuration.storage {
+        StorageConfiguration::Files => {
+            for (date, oaci, path) in day_files(&context.data_dir)? {
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    let fixed = options.repair && std::fs::remove_file(&path).is_ok();
+                    report.problems.push(Problem {
+                        date,
+                        oaci,
+                        kind: ProblemKind::LeftoverTemporaryFile,
+                        fixed,
+                    });
+                    continue;
+                }
+                report.checked += 1;
+                // A corrupted file may not even be UTF-8: it is reported like
+                // any other file that cannot be read, and the check goes on.
+                let flightlog = std::fs::read(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|content| {
+                        serde_json::from_slice::<FlightLog>(&content).map_err(|err| err.to_string())
+                    });
+                match flightlog {
+                    Ok(flightlog) => {
+                        check_and_repair(flightlog, date, &oaci, context, options, &mut report)
+                            .await?;
+                    }
+                    Err(err) => {
+                        let fixed = options.quarantine
+                            && quarantine(context, &path).unwrap_or_else(|err| {
+                                log::error!("Could not quarantine {:?} : {err}", path);
+                                false
+                            });
+                        report.problems.push(Problem {
+                            date,
+                            oaci,
+                            kind: ProblemKind::Unparsable(err),
+                            fixed,
+                        });
+                    }
+                }
+            }
+            check_archives(context, options, &mut report).await?;
+        }
+        StorageConfiguration::Sqlite => {
+            let database = Sqlite::open(&context.data_dir.join(SQLITE_FILE))?;
+            for (oaci, day, content) in database.contents().await? {
+                report.checked += 1;
+                let parsed = day.parse::<NaiveDate>().map(|date| {
+                    let flightlog = serde_json::from_str::<FlightLog>(&content);
+                    (date, flightlog.map_err(|err| err.to_string()))
+                });
+                let (date, kind) = match parsed {
+                    Ok((date, Ok(flightlog))) => {
+                        check_and_repair(flightlog, date, &oaci, context, options, &mut report)
+                            .await?;
+                        continue;
+                    }
+                    Ok((date, Err(err))) => (date, ProblemKind::Unparsable(err)),
+                    Err(err) => (
+                        NaiveDate::default(),
+                        ProblemKind::UnreadableDay(day.clone(), err.to_string()),
+                    ),
+                };
+                let fixed = options.quarantine
+                    && quarantine_row(context, &database, &oaci, &day, &content)
+                        .await
+                        .unwrap_or_else(|err| {
+                            log::error!("Could not quarantine {oaci} on the {day} : {err}");
+                            false
+                        });
+                report.problems.push(Problem {
+                    date,
+                    oaci,
+                    kind,
+                    fixed,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn check_and_repair(
+    mut flightlog: FlightLog,
+    date: NaiveDate,
+    oaci: &str,
+    context: &Context,
+    options: FsckOptions,
+    report: &mut FsckReport,
+) -> Result<(), StorageError> {
+    let problems = check_flightlog(&flightlog, date, oaci, context);
+    let repaired = options.repair && !problems.is_empty() && repair_flightlog(&mut flightlog, date);
+    if repaired {
+        context.storage.save(&flightlog, oaci).await?;
+    }
+    for kind in problems {
+        let fixed = repaired && kind != ProblemKind::UnknownAirport;
+        report.problems.push(Problem {
+            date,
+            oaci: oaci.to_string(),
+            kind,
+            fixed,
+        });
+    }
+    Ok(())
+}
+
+/// Checks the archived flightlogs that no live file replaces, and that the
+/// archives can be read. An unreadable flightlog is quarantined by moving it
+/// out of its archive, an unreadable archive by moving the whole archive.
+async fn check_archives(
+    context: &Context,
+    options: FsckOptions,
+    report: &mut FsckReport,
+) -> Result<(), StorageError> {
+    let archives_dir = context.data_dir.join(ARCHIVES_DIR);
+    if !archives_dir.is_dir() {
+        return Ok(());
+    }
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(&archives_dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".tar.gz") {
+            archives.push(path);
+        }
+    }
+    archives.sort();
+    for path in archives {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let archive = path.clone();
+        let files =
+            match tokio::task::spawn_blocking(move || archive::archived_files(&archive)).await? {
+                Ok(files) => files.unwrap_or_default(),
+                Err(err) => {
+                    let fixed = options.quarantine
+                        && quarantine(context, &path).unwrap_or_else(|err| {
+                            log::error!("Could not quarantine {:?} : {err}", path);
+                            false
+                        });
+                    report.problems.push(Problem {
+                        date: NaiveDate::default(),
+                        oaci: String::new(),
+                        kind: ProblemKind::UnreadableArchive(name, err.to_string()),
+                        fixed,
+                    });
+                    continue;
+                }
+            };
+        let mut quarantined = Vec::new();
+        for (relative, content) in files.iter() {
+            let (date, oaci) = match archived_day(relative) {
+                Some(day) => day,
+                None => continue,
+            };
+            // A live file wins over the archived one, and was checked.
+            if context.data_dir.join(relative).exists() {
+                continue;
+            }
+            report.checked += 1;
+            match serde_json::from_slice::<FlightLog>(content) {
+                Ok(flightlog) => {
+                    check_and_repair(flightlog, date, &oaci, context, options, report).await?;
+                }
+                Err(err) => {
+                    let fixed = options.quarantine
+                        && write_quarantined(context, Path::new(relative), content)
+                            .map(|()| quarantined.push(relative.clone()))
+                            .map_err(|err| {
+                                log::error!("Could not quarantine {relative} of {name} : {err}")
+                            })
+                            .is_ok();
+                    report.problems.push(Problem {
+                        date,
+                        oaci,
+                        kind: ProblemKind::Unparsable(err.to_string()),
+                        fixed,
+                    });
+                }
+            }
+        }
+        if !quarantined.is_empty() {
+            tokio::task::spawn_blocking(move || archive::remove_archived(&path, &quarantined))
+                .await??;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the day and the airport of an archived flightlog, from its
+/// relative path `YYYY/MM/DD/OACI.json`.
+fn archived_day(relative: &str) -> Option<(NaiveDate, String)> {
+    let parts: Vec<&str> = relative.split('/').collect();
+    if let [year, month, day, file] = parts[..] {
+        let oaci = file
+            .strip_suffix(".json")
+            .filter(|oaci| !oaci.contains('.'))?;
+        let date =
+            NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)?;
+        return Some((date, oaci.to_string()));
+    }
+    None
+}
+
+/// Moves a flightlog of the database that cannot be read to
+/// `quarantine/sqlite/DAY/OACI.json` in the data dir.
+async fn quarantine_row(
+    context: &Context,
+    database: &Sqlite,
+    oaci: &str,
+    day: &str,
+    content: &str,
+) -> Result<bool, StorageError> {
+    let safe = |name: &str| -> String {
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let relative = Path::new("sqlite")
+        .join(safe(day))
+        .join(format!("{}.json", safe(oaci)));
+    write_quarantined(context, &relative, content.as_bytes())?;
+    database.remove(oaci, day).await?;
+    log::warn!("Moved {oaci} on the {day} from the database to quarantine");
+    Ok(true)
+}
+
+/// Writes `content` at `relative` under `quarantine/` in the data dir.
+fn write_quarantined(
+    context: &Context,
+    relative: &Path,
+    content: &[u8],
+) -> Result<(), StorageError> {
+    let destination = context.data_dir.join("quarantine").join(relative);
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(destination, content)?;
+    Ok(())
+}
+
+/// Moves a file to the same place under `quarantine/` in the data dir.
+fn quarantine(context: &Context, path: &Path) -> Result<bool, StorageError> {
+    let relative = path.strip_prefix(&context.data_dir)?;
+    let destination = context.data_dir.join("quarantine").join(relative);
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(path, &destination)?;
+    log::warn!("Moved {:?} to {:?}", path, &destination);
+    Ok(true)
+}
+
+/// Returns the flightlog files (and leftover temporary files) stored under
+/// `root/YYYY/MM/DD/`, with their day and airport.
+fn day_files(root: &Path) -> Result<Vec<(NaiveDate, String, PathBuf)>, StorageError> {
+    let mut files = Vec::new();
+    for year in sub_dirs(root)? {
+        for month in sub_dirs(&year)? {
+            for day in sub_dirs(&month)? {
+                let date = match date_of_day_dir(&day) {
+                    Some(date) => date,
+                    None => continue,
+                };
+                for entry in std::fs::read_dir(&day)? {
+                    let path = entry?.path();
+                    let name = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .unwrap_or_default()
+                        .to_string();
+                    let oaci = name.strip_suffix(".json").or_else(|| {
+                        temporary_file_target(&name).and_then(|name| name.strip_suffix(".json"))
+                    });
+                    if let Some(oaci) = oaci {
+                        if !oaci.contains('.') {
+                            files.push((date, oaci.to_string(), path));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn sub_dirs(path: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        let is_number = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.chars().all(|c| c.is_ascii_digit()));
+        if path.is_dir() && is_number {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Returns the date of a `YYYY/MM/DD` directory.
+fn date_of_day_dir(day: &Path) -> Option<NaiveDate> {
+    let mut components = day.components().rev();
+    let mut next_number =
+        || -> Option<u32> { components.next()?.as_os_str().to_str()?.parse::<u32>().ok() };
+    let day = next_number()?;
+    let month = next_number()?;
+    let year = next_number()?;
+    NaiveDate::from_ymd_opt(year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repair_flightlog;
+    use brick_ogn::flight::Flight;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::{NaiveDate, NaiveTime};
+
+    #[test]
+    fn repair_dates_duplicates_and_landings() {
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let mut flightlog = FlightLog::new();
+        flightlog.date = NaiveDate::from_ymd_opt(2024, 6, 11).unwrap();
+        flightlog.flights = vec![
+            Flight {
+                ogn_nb: 1,
+                takeoff: NaiveTime::from_hms_opt(14, 0, 0).unwrap(),
+                landing: NaiveTime::from_hms_opt(13, 0, 0).unwrap(),
+                ..Default::default()
+            },
+            Flight {
+                ogn_nb: 1,
+                ..Default::default()
+            },
+            Flight {
+                ogn_nb: -1,
+                ..Default::default()
+            },
+        ];
+        assert!(repair_flightlog(&mut flightlog, date));
+        assert_eq!(flightlog.date, date);
+        let ogn_nbs: Vec<i32> = flightlog.flights.iter().map(|f| f.ogn_nb).collect();
+        assert_eq!(ogn_nbs, vec![1, -2, -1]);
+        assert_eq!(
+            flightlog.flights[0].landing,
+            NaiveTime::from_hms_opt(0, 0, 0).unwrap()
+        );
+        assert!(!repair_flightlog(&mut flightlog, date));
+    }
+}
@@ -0,0 +1,185 @@
+//! Write-ahead journal of the [`Update`]s received by the server.
+//! Every update is appended (and synced) to the journal before being applied
+//! to a flightlog. Once the flightlog is saved, its entry is removed. If the
+//! server stops in between (power loss on the field...), the remaining
+//! entries are replayed on the next startup.
+
+use crate::flightlog::Storage;
+use crate::storage::{append_line, NotFound, StorageError};
+use crate::Context;
+use brick_ogn::flightlog::update::Update;
+use brick_ogn::flightlog::FlightLog;
+use chrono::NaiveDate;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// An update waiting for its flightlog to be saved.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct JournalEntry {
+    /// Identifies the entry to remove it once applied and saved.
+    pub id: u64,
+    /// The OACI code of the airport of the flightlog.
+    pub oaci: String,
+    /// The update to apply.
+    pub update: Update,
+}
+
+/// The journal file and a lock serializing its writes.
+pub struct Journal {
+    path: PathBuf,
+    lock: Mutex<()>,
+    next_id: AtomicU64,
+}
+
+impl Journal {
+    /// Creates a journal stored at `path`.
+    pub fn new(path: PathBuf) -> Self {
+        // Starting from the current time keeps ids unique across restarts.
+        let first_id = chrono::Utc::now().timestamp_micros().max(0) as u64;
+        Self {
+            path,
+            lock: Mutex::new(()),
+            next_id: AtomicU64::new(first_id),
+        }
+    }
+
+    /// Appends an update to the journal and waits for it to be on the disk.
+    /// Returns the id of the entry.
+    pub async fn append(&self, oaci: &str, update: &Update) -> Result<u64, StorageError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = JournalEntry {
+            id,
+            oaci: oaci.to_string(),
+            update: update.clone(),
+        };
+        let line = serde_json::to_string(&entry)?;
+        let _guard = self.lock.lock().await;
+        append_line(&self.path, &line).await?;
+        Ok(id)
+    }
+
+    /// Returns the entries of the journal. A line that cannot be parsed (for
+    /// example the last one, if the server stopped while writing it) is
+    /// skipped.
+    pub async fn entries(&self) -> Result<Vec<JournalEntry>, StorageError> {
+        let _guard = self.lock.lock().await;
+        self.read_entries().await
+    }
+
+    /// Removes entries from the journal, to be called once the flightlog they
+    /// were applied to is saved.
+    pub async fn remove(&self, ids: &[u64]) -> Result<(), StorageError> {
+        let _guard = self.lock.lock().await;
+        let entries = self.read_entries().await?;
+        let remaining: Vec<JournalEntry> = entries
+            .iter()
+            .filter(|entry| !ids.contains(&entry.id))
+            .cloned()
+            .collect();
+        if remaining.len() != entries.len() {
+            self.write_entries(&remaining).await?;
+        }
+        Ok(())
+    }
+
+    /// Applies the entries left in the journal to the stored flightlogs, then
+    /// empties it. Updates set fields to values, so replaying an update that
+    /// was already saved is harmless.
+    pub async fn replay(&self, context: &Context) -> Result<usize, StorageError> {
+        let entries = self.entries().await?;
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        log::warn!(
+            "Replaying {} update(s) that were not saved before the last stop.",
+            entries.len()
+        );
+        let mut days: Vec<(String, NaiveDate)> = Vec::new();
+        for entry in &entries {
+            let day = (entry.oaci.clone(), entry.update.date);
+            if !days.contains(&day) {
+                days.push(day);
+            }
+        }
+        for (oaci, date) in &days {
+            let mut flightlog = match FlightLog::load(*date, oaci, context).await {
+                Ok(flightlog) => flightlog,
+                Err(err) if err.is::<NotFound>() => {
+                    let mut fl = FlightLog::new();
+                    fl.date = *date;
+                    fl
+                }
+                Err(err) => return Err(err),
+            };
+            let mut ids = Vec::new();
+            for entry in entries
+                .iter()
+                .filter(|entry| entry.oaci == *oaci && entry.update.date == *date)
+            {
+                flightlog.update(entry.update.clone());
+                ids.push(entry.id);
+            }
+            flightlog.save(oaci, context).await?;
+            self.remove(&ids).await?;
+        }
+        Ok(entries.len())
+    }
+
+    async fn read_entries(&self) -> Result<Vec<JournalEntry>, StorageError> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path).await?;
+        let mut entries = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => log::warn!("Skipping a corrupted journal entry : {err}"),
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn write_entries(&self, entries: &[JournalEntry]) -> Result<(), StorageError> {
+        let mut content = String::new();
+        for entry in entries {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+        crate::storage::write_atomic(&self.path, content.as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Journal;
+    use brick_ogn::flightlog::update::Update;
+
+    #[tokio::test]
+    async fn append_remove_and_skip_torn_lines() {
+        let path = std::env::temp_dir().join(format!("cepo-journal-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journal = Journal::new(path.clone());
+
+        let first = journal.append("LFLE", &Update::default()).await.unwrap();
+        let second = journal.append("LFLB", &Update::default()).await.unwrap();
+        assert_ne!(first, second);
+        // a line torn by a power loss
+        std::fs::write(
+            &path,
+            std::fs::read_to_string(&path).unwrap() + "{\"id\": 12, \"oa",
+        )
+        .unwrap();
+
+        let third = journal.append("LFLE", &Update::default()).await.unwrap();
+
+        assert_eq!(journal.entries().await.unwrap().len(), 3);
+        journal.remove(&[first, third]).await.unwrap();
+        let entries = journal.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].oaci, "LFLB");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+//! Detection of the launch method of the flights seen by OGN.
+//! The flightbook only links a glider to its tow plane when both are tracked
+//! and seen together. The launch method is found from that link, the category
+//! of the aircraft, the self-launching aircraft, the tow planes that took off
+//! at the same time and the winches and aerotows of the airport.
+
+use crate::configuration::Configuration;
+use crate::ogn::Logbook;
+use brick_ogn::flightlog::FlightLog;
+use chrono::{NaiveDate, NaiveTime};
+use std::collections::BTreeMap;
+
+/// The takeoff code of a winch launch.
+pub const WINCH: &str = "T";
+/// The takeoff code of an aerotow.
+pub const AEROTOW: &str = "R";
+/// The takeoff code of a self-launch.
+pub const SELF_LAUNCH: &str = "A";
+
+/// The OGN categories of the aircraft that take off on their own: tow plane,
+/// helicopter, powered aircraft and jet.
+const SELF_POWERED_CATEGORIES: [u8; 4] = [2, 3, 8, 9];
+/// The OGN category of the tow planes.
+const TOW_PLANE_CATEGORY: u8 = 2;
+/// The largest gap between the takeoff of a glider and the one of its tow
+/// plane, the flightbook giving times to the minute.
+const TOW_TAKEOFF_GAP_SECS: i64 = 60;
+
+/// What the launch method is detected from, for an airport.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LaunchSettings {
+    /// The winches of the airport.
+    pub winches: Vec<String>,
+    /// The tow planes of the airport.
+    pub aerotows: Vec<String>,
+    /// The immatriculations of the aircraft that launch on their own.
+    pub self_launchers: Vec<String>,
+    /// The tow pilots of the airport.
+    pub tow_pilots: Vec<String>,
+    /// The tow pilot on duty at the airport, by day.
+    pub tow_pilot_duty: BTreeMap<NaiveDate, String>,
+}
+
+impl LaunchSettings {
+    /// Returns the settings of an airport, with the permanent ones of the
+    /// configuration.
+    pub fn for_airport(configuration: &Configuration, oaci: &String) -> Self {
+        let mut settings = Self {
+            winches: configuration.permanent_winches(),
+            aerotows: configuration.permanent_aerotows(),
+            self_launchers: configuration.permanent_self_launchers(),
+            tow_pilots: configuration.permanent_tow_pilots(),
+            tow_pilot_duty: BTreeMap::new(),
+        };
+        if let Ok(airport) = configuration.airport_configuration(oaci) {
+            settings.winches.append(&mut airport.winches());
+            settings.aerotows.append(&mut airport.aerotows());
+            settings
+                .self_launchers
+                .append(&mut airport.self_launchers());
+            settings.tow_pilots.append(&mut airport.tow_pilots());
+            settings.tow_pilot_duty = airport.tow_pilot_duty();
+        }
+        settings
+    }
+}
+
+/// A launch method: a takeoff code and the machine that launched.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Launch {
+    /// The takeoff code ([`WINCH`], [`AEROTOW`] or [`SELF_LAUNCH`]).
+    pub code: String,
+    /// The winch or the tow plane, empty if unknown or self-launched.
+    pub machine: String,
+}
+
+impl Launch {
+    fn new(code: &str, machine: String) -> Self {
+        Self {
+            code: code.to_string(),
+            machine,
+        }
+    }
+}
+
+/// Returns the only machine of a list, if there is only one.
+fn only(machines: &[String]) -> String {
+    match machines {
+        [machine] => machine.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Returns the launch method of the flight at `index` in the logbook.
+pub fn detect_launch(logbook: &Logbook, index: usize, settings: &LaunchSettings) -> Launch {
+    let flight = match logbook.flights.get(index) {
+        Some(Some(flight)) => flight,
+        _ => return Launch::default(),
+    };
+    let device = logbook.device(flight).ok();
+    let registration = device
+        .and_then(|device| device.registration.clone())
+        .unwrap_or_default();
+    let category = device.and_then(|device| device.aircraft_type);
+
+    // Linked to its tow plane by OGN
+    if let Some(tow) = flight.tow {
+        let tow_device = logbook
+            .flights
+            .get(tow)
+            .and_then(|tow_flight| tow_flight.as_ref())
+            .and_then(|tow_flight| logbook.device(tow_flight).ok());
+        match tow_device {
+            Some(tow_device) => {
+                return Launch::new(AEROTOW, tow_device.registration.clone().unwrap_or_default())
+            }
+            None => {
+                log::warn!("Unknown tow plane of the OGN flight {index}");
+                return Launch::new(AEROTOW, only(&settings.aerotows));
+            }
+        }
+    }
+    if settings.self_launchers.contains(&registration)
+        || category.is_some_and(|category| SELF_POWERED_CATEGORIES.contains(&category))
+    {
+        return Launch::new(SELF_LAUNCH, String::new());
+    }
+    if let Some(tug) = unlinked_tug(logbook, index, settings) {
+        return Launch::new(AEROTOW, tug);
+    }
+    if settings.winches.is_empty() && !settings.aerotows.is_empty() {
+        return Launch::new(AEROTOW, only(&settings.aerotows));
+    }
+    Launch::new(WINCH, only(&settings.winches))
+}
+
+/// Returns the tow plane that took off with the flight at `index` while OGN did
+/// not link them: a tow plane of the airport (or of the tow plane category),
+/// taking off in the same minute and not already towing another glider.
+fn unlinked_tug(logbook: &Logbook, index: usize, settings: &LaunchSettings) -> Option<String> {
+    let takeoff = takeoff_of(logbook, index)?;
+    let towing: Vec<usize> = logbook
+        .flights
+        .iter()
+        .flatten()
+        .filter_map(|flight| flight.tow)
+        .collect();
+    logbook
+        .flights
+        .iter()
+        .enumerate()
+        .filter(|(tug_index, _)| *tug_index != index && !towing.contains(tug_index))
+        .find_map(|(tug_index, tug_flight)| {
+            let device = logbook.device(tug_flight.as_ref()?).ok()?;
+            let registration = device.registration.clone().unwrap_or_default();
+            let is_tug = settings.aerotows.contains(&registration)
+                || device.aircraft_type == Some(TOW_PLANE_CATEGORY);
+            let gap = (takeoff_of(logbook, tug_index)? - takeoff)
+                .num_seconds()
+                .abs();
+            (is_tug && gap <= TOW_TAKEOFF_GAP_SECS).then_some(registration)
+        })
+}
+
+/// Fills the tow pilot of the aerotows of the flightlog that have none, and
+/// returns how many were filled. The tow pilot is, in this order:
+/// - the pilot of the own flight of the tow plane, taking off at the same time,
+/// - the tow pilot of the previous aerotow of the tow plane that day,
+/// - the tow pilot on duty that day,
+/// - the tow pilot of the airport, if there is only one.
+pub fn fill_tow_pilots(flightlog: &mut FlightLog, settings: &LaunchSettings) -> usize {
+    let mut order: Vec<usize> = (0..flightlog.flights.len()).collect();
+    order.sort_by_key(|index| flightlog.flights[*index].takeoff);
+    let on_duty = settings
+        .tow_pilot_duty
+        .get(&flightlog.date)
+        .cloned()
+        .unwrap_or_else(|| only(&settings.tow_pilots));
+    let mut filled = 0;
+    for index in order {
+        let flight = &flightlog.flights[index];
+        if flight.takeoff_code != AEROTOW || !flight.takeoff_machine_pilot.is_empty() {
+            continue;
+        }
+        let tug = &flight.takeoff_machine;
+        let tug_flight_pilot = flightlog
+            .flights
+            .iter()
+            .filter(|tug_flight| !tug.is_empty() && tug_flight.glider == *tug)
+            .find(|tug_flight| {
+                (tug_flight.takeoff - flight.takeoff).num_seconds().abs() <= TOW_TAKEOFF_GAP_SECS
+            })
+            .map(|tug_flight| tug_flight.pilot1.clone())
+            .filter(|pilot| !pilot.is_empty());
+        let previous_tow_pilot = || {
+            flightlog
+                .flights
+                .iter()
+                .filter(|previous| {
+                    !tug.is_empty()
+                        && previous.takeoff_code == AEROTOW
+                        && previous.takeoff_machine == *tug
+                        && previous.takeoff < flight.takeoff
+                        && !previous.takeoff_machine_pilot.is_empty()
+                })
+                .max_by_key(|previous| previous.takeoff)
+                .map(|previous| previous.takeoff_machine_pilot.clone())
+        };
+        let tow_pilot = tug_flight_pilot
+            .or_else(previous_tow_pilot)
+            .unwrap_or_else(|| on_duty.clone());
+        if !tow_pilot.is_empty() {
+            flightlog.flights[index].takeoff_machine_pilot = tow_pilot;
+            filled += 1;
+        }
+    }
+    filled
+}
+
+/// Returns the takeoff time of the flight at `index`.
+fn takeoff_of(logbook: &Logbook, index: usize) -> Option<NaiveTime> {
+    let start = logbook.flights.get(index)?.as_ref()?.start.as_deref()?;
+    NaiveTime::parse_from_str(start, "%Hh%M").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        detect_launch, fill_tow_pilots, Launch, LaunchSettings, AEROTOW, SELF_LAUNCH, WINCH,
+    };
+    use crate::ogn::Logbook;
+    use brick_ogn::flight::Flight;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::{NaiveDate, NaiveTime};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn detect_launch_methods() {
+        let logbook = Logbook::parse(
+            br#"{"devices": [
+                {"registration": "F-CEJU", "aircraft_type": 1},
+                {"registration": "F-GDRT", "aircraft_type": 2},
+                {"registration": "F-CMOT", "aircraft_type": 1},
+                {"registration": "F-JDAG", "aircraft_type": 8},
+                {"registration": "F-GTUG", "aircraft_type": 8},
+                {"registration": "F-CECY", "aircraft_type": 1}
+            ], "flights": [
+                {"device": 1, "start": "10h00", "stop": "10h10"},
+                {"device": 0, "start": "10h00", "stop": "11h00", "tow": 0},
+                {"device": 2, "start": "11h00"},
+                {"device": 3, "start": "11h30"},
+                {"device": 4, "start": "12h00"},
+                {"device": 5, "start": "12h01"},
+                {"device": 5, "start": "14h00"},
+                {"device": 0, "start": "15h00", "tow": 42}
+            ]}"#,
+        )
+        .unwrap();
+        let settings = LaunchSettings {
+            winches: vec![String::from("yellow")],
+            aerotows: vec![String::from("F-GTUG")],
+            self_launchers: vec![String::from("F-CMOT")],
+            ..Default::default()
+        };
+        let launch = |code: &str, machine: &str| Launch {
+            code: code.to_string(),
+            machine: machine.to_string(),
+        };
+
+        let cases = [
+            // the tow plane itself
+            (0, launch(SELF_LAUNCH, "")),
+            // linked by OGN to its tow plane
+            (1, launch(AEROTOW, "F-GDRT")),
+            // a configured self-launcher
+            (2, launch(SELF_LAUNCH, "")),
+            // a powered aircraft
+            (3, launch(SELF_LAUNCH, "")),
+            // a configured tow plane taking off in the same minute
+            (5, launch(AEROTOW, "F-GTUG")),
+            // nothing took off with it
+            (6, launch(WINCH, "yellow")),
+            // the tow plane is not in the logbook
+            (7, launch(AEROTOW, "F-GTUG")),
+        ];
+        for (index, expected) in cases {
+            assert_eq!(
+                detect_launch(&logbook, index, &settings),
+                expected,
+                "{index}"
+            );
+        }
+
+        let aerotows_only = LaunchSettings {
+            aerotows: vec![String::from("F-GTUG")],
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_launch(&logbook, 6, &aerotows_only),
+            launch(AEROTOW, "F-GTUG")
+        );
+    }
+
+    #[test]
+    fn fill_tow_pilots_from_tug_flights_and_roster() {
+        let time = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+        let flight = |glider: &str, takeoff, code: &str, tug: &str, pilot: &str| Flight {
+            glider: glider.to_string(),
+            takeoff,
+            takeoff_code: code.to_string(),
+            takeoff_machine: tug.to_string(),
+            takeoff_machine_pilot: pilot.to_string(),
+            ..Default::default()
+        };
+        let mut flightlog = FlightLog::new();
+        flightlog.flights = vec![
+            // towed again later by the same tug
+            flight("F-CECY", time(14, 0), AEROTOW, "F-GDRT", ""),
+            flight("F-CEJU", time(10, 0), AEROTOW, "F-GDRT", ""),
+            Flight {
+                pilot1: String::from("Walt Disney"),
+                ..flight("F-GDRT", time(10, 1), "", "", "")
+            },
+            // entered by hand
+            flight("F-CHFL", time(11, 0), AEROTOW, "F-GDRT", "Roy Disney"),
+            // unknown tug
+            flight("F-CBAR", time(12, 0), AEROTOW, "", ""),
+            flight("F-CMOT", time(13, 0), WINCH, "yellow", ""),
+        ];
+        let settings = LaunchSettings {
+            tow_pilots: vec![String::from("Jony Ive")],
+            ..Default::default()
+        };
+
+        assert_eq!(fill_tow_pilots(&mut flightlog, &settings), 3);
+        let pilots: Vec<&str> = flightlog
+            .flights
+            .iter()
+            .map(|flight| flight.takeoff_machine_pilot.as_str())
+            .collect();
+        assert_eq!(
+            pilots,
+            [
+                "Roy Disney",
+                "Walt Disney",
+                "",
+                "Roy Disney",
+                "Jony Ive",
+                ""
+            ]
+        );
+        // nothing left to fill
+        assert_eq!(fill_tow_pilots(&mut flightlog, &settings), 0);
+
+        // the tow pilot on duty that day, among several
+        flightlog.date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        flightlog.flights[4].takeoff_machine_pilot.clear();
+        let settings = LaunchSettings {
+            tow_pilots: vec![String::from("Jony Ive"), String::from("Steve Jobs")],
+            tow_pilot_duty: BTreeMap::from([(flightlog.date, String::from("Steve Jobs"))]),
+            ..Default::default()
+        };
+        assert_eq!(fill_tow_pilots(&mut flightlog, &settings), 1);
+        assert_eq!(flightlog.flights[4].takeoff_machine_pilot, "Steve Jobs");
+        flightlog.flights[4].takeoff_machine_pilot.clear();
+        flightlog.date = flightlog.date.succ_opt().unwrap();
+        assert_eq!(fill_tow_pilots(&mut flightlog, &settings), 0);
+    }
+}
@@ -5,24 +5,53 @@
 //! names, immatriculations to look at, takeoff_machines and pilots etc.
 
 use crate::client::Client;
+use aircraft::AircraftRegistry;
+use audit::{AuditEntry, AuditLog};
+use backfill::BackfillJobs;
+use cache::{latest_flightlog, past_flightlog, FetchedDay, FlightLogCache};
 use configuration::{Configuration, DayMonitor};
-use ogn::synchronisation_ogn;
+use ddb::DeviceDatabase;
+use journal::Journal;
+use ogn::{synchronisation_ogn, HttpOgn, OgnSource};
+use provenance::ProvenanceStore;
+use single_flight::{KeyedLocks, SingleFlight};
 use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use storage::Backend;
+use sync::{backoff_delay, ResilientOgn, SyncStatusBoard};
 
 use brick_ogn::flightlog::update::Update;
 use brick_ogn::flightlog::FlightLog;
-use flightlog::Storage;
+use flightlog::{roll_over, Storage};
 
 use hyper::header::*;
 use hyper::service::{make_service_fn, service_fn};
 
+pub mod aircraft;
+pub mod aprs;
+pub mod archive;
+pub mod audit;
+pub mod backfill;
+pub mod backup;
+pub mod cache;
 pub mod client;
 pub mod configuration;
-pub mod flight;
+pub mod ddb;
 pub mod flightlog;
+pub mod fsck;
+pub mod journal;
+pub mod launch;
 pub mod ogn;
+pub mod provenance;
+pub mod reconcile;
+pub mod selection;
+pub mod single_flight;
+pub mod storage;
+pub mod sync;
+pub mod time_zone;
 
 use crate::client::UsageControl;
 use brick_ogn::flightlog::update::ObsoleteUpdates;
@@ -41,6 +70,7 @@ use hyper::{Body, Request, Response, Server};
 use hyper::{Method, StatusCode};
 
 /// Aircraft struct, used to parse OGN API.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Aircraft {
     /// The type of the aircraft, coming from OGN.
     pub modele: String,
@@ -49,6 +79,9 @@ pub struct Aircraft {
     pub category: u8,
     /// The string of the immatriculation e(ex: `F-CMOI`).
     pub immatriculation: String,
+    /// The competition number of the aircraft, if known.
+    #[serde(default)]
+    pub competition: String,
 }
 
 /// Return a  Two char long string of the number.
@@ -66,18 +99,17 @@ pub fn nb_2digits_string(nombre: i32) -> String {
     }
 }
 
-/// Create the path associated with a day of the time at "$XDG_DATA_DIR/cepo/year/month/day".
-pub fn create_fs_path_day(annee: i32, mois: u32, jour: u32) {
+/// Create the path associated with a day of the time at "root/year/month/day".
+pub fn create_fs_path_day(root: &Path, annee: i32, mois: u32, jour: u32) {
     let jour_str = nb_2digits_string(jour as i32);
     let mois_str = nb_2digits_string(mois as i32);
 
-    let mut path = crate::data_dir();
+    let mut path = root.to_path_buf();
     path.push(annee.to_string());
     path.push(&mois_str);
     path.push(&jour_str);
 
     if !path.as_path().exists() {
-        dbg!(&path);
         fs::create_dir_all(&path).unwrap();
         log::info!("Creating path {}/{}/{}", annee, &mois_str, &jour_str);
     }
@@ -98,33 +130,130 @@ pub struct Context {
     /// A vector that stores who is actually requesting, to limit the number of
     /// concurrent request of the same user. (Some sort of ddos protection).
     pub current_requests: Arc<Mutex<Vec<Client>>>,
+    /// The backend where flightlogs are loaded from and saved to.
+    pub storage: Arc<dyn Backend>,
+    /// The journal of the updates that are not saved yet.
+    pub journal: Arc<Journal>,
+    /// The persistent history of the updates made to the flightlogs.
+    pub audit: Arc<AuditLog>,
+    /// The aircraft seen by OGN at each airport, day by day.
+    pub aircraft: Arc<AircraftRegistry>,
+    /// Where the fields of the flights come from and their conflicts, day by
+    /// day.
+    pub provenance: Arc<ProvenanceStore>,
+    /// The root directory of the storage.
+    pub data_dir: PathBuf,
+    /// The flightlogs of past days that were recently served.
+    pub cache: Arc<FlightLogCache>,
+    /// The past days being requested to OGN, shared by the concurrent
+    /// requests of the same day.
+    pub fetches: Arc<SingleFlight<(String, NaiveDate), Result<FetchedDay, String>>>,
+    /// The past days being modified, one change of a day at a time.
+    pub days: Arc<KeyedLocks<(String, NaiveDate)>>,
+    /// Where the OGN logbooks are requested.
+    pub ogn: Arc<dyn OgnSource>,
+    /// The state of the synchronisation of each airport with OGN.
+    pub sync_status: Arc<SyncStatusBoard>,
+    /// The local copy of the OGN devices database and its corrections.
+    pub devices: Arc<DeviceDatabase>,
+    /// The backfills started by `POST /admin/backfill`.
+    pub backfills: Arc<BackfillJobs>,
 }
 
 impl Context {
-    /// Context constructor
+    /// Context constructor, replaying the journal of the updates that were
+    /// not saved before the last stop.
     pub async fn new(configuration: Configuration) -> Self {
+        Self::open(configuration, true).await
+    }
+
+    /// Context of the maintenance commands (`fsck`, `archive`, `backfill`):
+    /// the journal is left for the server to replay, so that a command does
+    /// not write the flightlogs it is about to check or pack.
+    pub async fn for_maintenance(configuration: Configuration) -> Self {
+        Self::open(configuration, false).await
+    }
+
+    async fn open(configuration: Configuration, replay: bool) -> Self {
         let current_requests: Arc<Mutex<Vec<Client>>> = Arc::new(Mutex::new(Vec::new()));
         //let ecouteur = TcpListener::bind("127.0.0.1:7878").unwrap();
         // Creation of the working dir if needed
-        if !(crate::data_dir().as_path().exists()) {
-            fs::create_dir_all(data_dir().as_path())
-                .expect("Could not create data_dir on your platform.");
+        let data_dir = configuration.data_dir();
+        if !(data_dir.as_path().exists()) {
+            fs::create_dir_all(data_dir.as_path())
+                .unwrap_or_else(|err| panic!("Could not create data dir {:?} : {err}", &data_dir));
             log::info!("Create dir for data.");
         }
-        let flightlogs = (&configuration).create_needed_flightlog_hashmap();
+        log::info!("Storing data in {:?}", &data_dir);
+        let storage = storage::open_backend(&configuration.storage, data_dir.as_path())
+            .expect("Could not open the storage backend.");
+        let journal = Arc::new(Journal::new(data_dir.join("journal.jsonl")));
+        let audit = Arc::new(AuditLog::new(data_dir.clone()));
+        let aircraft = Arc::new(AircraftRegistry::new(data_dir.clone()));
+        let provenance = Arc::new(ProvenanceStore::new(data_dir.clone()));
+        let cache = Arc::new(FlightLogCache::new(configuration.cache_capacity));
+        let sync_status = Arc::new(SyncStatusBoard::new());
+        let fetches = Arc::new(SingleFlight::new());
+        let ogn: Arc<dyn OgnSource> = Arc::new(ResilientOgn::new(
+            Arc::new(HttpOgn::new(configuration.ogn_url.clone())),
+            configuration.ogn_sync.clone(),
+            sync_status.clone(),
+        ));
+
+        let devices = DeviceDatabase::load(
+            configuration.ddb.path.as_deref(),
+            configuration.ddb.overrides.as_deref(),
+        )
+        .unwrap_or_else(|err| {
+            log::error!("Could not load the devices database : {err}");
+            DeviceDatabase::new()
+        });
+        if !devices.is_empty() {
+            let (known, corrected) = devices.counts();
+            log::info!("{known} device(s) known, {corrected} corrected by the club");
+        }
+        let devices = Arc::new(devices);
 
         let updates_arc: Arc<Mutex<Vec<Update>>> = Arc::new(Mutex::new(Vec::new()));
-        return Self {
+        let mut context = Self {
             configuration: configuration.clone(),
-            flightlogs: flightlogs.await,
+            flightlogs: HashMap::new(),
             updates: updates_arc,
             current_requests,
+            storage,
+            journal,
+            audit,
+            aircraft,
+            provenance,
+            data_dir,
+            cache,
+            fetches,
+            days: Arc::new(KeyedLocks::new()),
+            ogn,
+            sync_status,
+            devices,
+            backfills: Arc::new(BackfillJobs::new()),
         };
+        // Updates that were not saved before the last stop are applied before
+        // loading the flightlogs of the day.
+        if replay {
+            if let Err(err) = context.journal.clone().replay(&context).await {
+                log::error!("Could not replay the journal of updates : {err}");
+            }
+        }
+        context.flightlogs = configuration
+            .create_needed_flightlog_hashmap(&context.storage)
+            .await;
+        return context;
     }
     /// The main server function that is launched after the parsing of the
     /// configuration.
     pub async fn server(&self) -> Result<(), hyper::Error> {
         log::info!("Starting up...");
+        // The data dir is not restored while the server runs on it.
+        let _running = storage::ServerMark::new(&self.data_dir)
+            .map_err(|err| log::warn!("Could not mark the data dir as used : {err}"))
+            .ok();
         let address = SocketAddr::from(([0, 0, 0, 0], self.configuration.port as u16));
 
         let context_svc = self.clone();
@@ -139,33 +268,83 @@ impl Context {
                 }))
             }
         });
-        let f_synchronisation_secs_clone = self
-            .clone()
-            .configuration
-            .clone()
-            .f_synchronisation_secs
-            .clone() as u64;
+        let f_synchronisation =
+            Duration::from_secs(self.configuration.f_synchronisation_secs.max(1) as u64);
+        let max_backoff = Duration::from_secs(self.configuration.ogn_sync.max_backoff_secs);
+        // The airports having a position follow the live APRS feed if it is
+        // configured, the others keep polling the flightbook.
+        let live = self.configuration.aprs.is_some();
+        if live {
+            log::info!("Launching the APRS feed thread");
+            let context_aprs = self.clone();
+            supervise(String::from("APRS feed"), move || {
+                aprs::run(context_aprs.clone())
+            });
+        }
         // Spawning the regularly requesting OGN thread
         for ap in &self.configuration.airports_configs {
-            if ap.day_monitor() == DayMonitor::Always {
+            if ap.day_monitor() == DayMonitor::Always && !(live && ap.position().is_some()) {
                 let oaci = ap.oaci();
                 let flightlog_arc = self.flightlogs[&oaci].clone();
-                let context_c = context_svc.clone();
-                tokio::spawn(async move {
-                    let flightlog_arc_c = flightlog_arc.clone();
-                    log::info!("Launching the OGN thread of {}", &oaci);
-                    loop {
-                        let res = synchronisation_ogn(flightlog_arc_c.clone(), &oaci, &context_c);
-
-                        tokio::time::sleep(tokio::time::Duration::from_secs(
-                            f_synchronisation_secs_clone,
-                        ))
-                        .await; //5 minutes
-                        res.await.unwrap();
+                let context_ogn = context_svc.clone();
+                supervise(format!("OGN synchronisation of {oaci}"), move || {
+                    let (oaci, flightlog_arc) = (oaci.clone(), flightlog_arc.clone());
+                    let context_c = context_ogn.clone();
+                    async move {
+                        log::info!("Launching the OGN thread of {}", &oaci);
+                        loop {
+                            let res =
+                                synchronisation_ogn(flightlog_arc.clone(), &oaci, &context_c).await;
+                            // OGN is requested less and less often while it fails.
+                            let delay = match res {
+                                Ok(_) => f_synchronisation,
+                                Err(err) => {
+                                    log::error!("Could not synchronise {} with OGN : {err}", &oaci);
+                                    let failures =
+                                        context_c.sync_status.get(&oaci).consecutive_failures;
+                                    backoff_delay(failures.max(1), f_synchronisation, max_backoff)
+                                        .max(f_synchronisation / 2)
+                                }
+                            };
+                            tokio::time::sleep(delay).await;
+                        }
                     }
                 });
             }
         }
+        // Starting the flightlog of the new day at the local midnight of each
+        // airport
+        let context_roll_over = context_svc.clone();
+        supervise(String::from("roll-over"), move || {
+            let context_day = context_roll_over.clone();
+            async move {
+                loop {
+                    for (oaci, flightlog_arc) in &context_day.flightlogs {
+                        if let Err(err) = roll_over(flightlog_arc, oaci, &context_day).await {
+                            log::error!("Could not start the new day of {oaci} : {err}");
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+            }
+        });
+        // Packing the finished seasons at startup then every day
+        let context_archiving = context_svc.clone();
+        supervise(String::from("archiving"), move || {
+            let context_archive = context_archiving.clone();
+            async move {
+                loop {
+                    match archive::archive_old_seasons(&context_archive).await {
+                        Ok(archived) if !archived.is_empty() => {
+                            log::info!("Archived {} finished period(s).", archived.len())
+                        }
+                        Ok(_) => {}
+                        Err(err) => log::error!("Could not archive the finished seasons : {err}"),
+                    }
+                    tokio::time::sleep(tokio::time::Duration::from_secs(24 * 60 * 60)).await;
+                }
+            }
+        });
         let server = Server::bind(&address)
             .serve(service)
             .with_graceful_shutdown(signal_extinction());
@@ -182,26 +361,142 @@ struct GetFlightLogsQueryParameters {
     oaci: String,
 }
 
+/// Handles the parameters for a GET request of the flightlogs of a range of
+/// days. Without `refresh`, only the stored flightlogs are sent, without
+/// requesting OGN.
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct GetFlightLogsRangeQueryParameters {
+    oaci: String,
+    from: NaiveDate,
+    to: NaiveDate,
+    #[serde(default)]
+    refresh: bool,
+}
+
+/// The maximum number of days that can be requested at once on `/flightlogs`.
+const MAX_FLIGHTLOGS_RANGE_DAYS: i64 = 366;
+
+/// The maximum number of days that can be refreshed from OGN at once on
+/// `/flightlogs`.
+const MAX_REFRESHED_DAYS: i64 = 31;
+
+impl GetFlightLogsRangeQueryParameters {
+    /// Returns the last day sent: the future days are not refreshed.
+    fn last_day(&self, today: NaiveDate) -> NaiveDate {
+        if self.refresh {
+            self.to.min(today)
+        } else {
+            self.to
+        }
+    }
+
+    /// Returns true if the range can be sent at once.
+    fn is_valid(&self, today: NaiveDate) -> bool {
+        self.from <= self.to
+            && (self.to - self.from).num_days() < MAX_FLIGHTLOGS_RANGE_DAYS
+            && (!self.refresh || (self.last_day(today) - self.from).num_days() < MAX_REFRESHED_DAYS)
+    }
+}
+
 /// Handles the parameters for an airports's infos GET request
 #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 struct GetInfosQueryParameters {
     oaci: String,
 }
 
+/// Handles the parameters for the history of a flightlog or of one of its
+/// flights
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct GetHistoryQueryParameters {
+    date: NaiveDate,
+    oaci: String,
+    ogn_nb: Option<i32>,
+}
+
+/// Handles the parameters for the aircraft seen on a day at an airport
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct GetAircraftQueryParameters {
+    date: NaiveDate,
+    oaci: String,
+}
+
+/// Handles the parameters for the conflicts of a day at an airport
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct GetConflictsQueryParameters {
+    date: NaiveDate,
+    oaci: String,
+}
+
+/// Handles the parameters for a backfill of an airport
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct PostBackfillQueryParameters {
+    oaci: String,
+    from: NaiveDate,
+    to: NaiveDate,
+}
+
+/// Handles the parameters for the progress of the backfill of an airport
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+struct GetBackfillQueryParameters {
+    oaci: String,
+}
+
 /// Handles the parameters for a updates post request
 #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 struct PostUpdateQueryParameters {
     oaci: String,
 }
 
+/// The parameters of a request, by route.
+enum Query {
+    FlightLog(GetFlightLogsQueryParameters),
+    FlightLogs(GetFlightLogsRangeQueryParameters),
+    Infos(GetInfosQueryParameters),
+    History(GetHistoryQueryParameters),
+    Aircraft(GetAircraftQueryParameters),
+    Conflicts(GetConflictsQueryParameters),
+    Flights(storage::FlightQuery),
+    StartBackfill(PostBackfillQueryParameters),
+    Backfill(GetBackfillQueryParameters),
+    Update(PostUpdateQueryParameters),
+    /// A route without parameters, or an unknown one.
+    None,
+}
+
+/// Deserializes the query string of a request to the parameters of its route.
+/// The routes without parameters accept any query.
+fn route_query(method: &Method, path: &str, query: &str) -> Result<Query, serde_qs::Error> {
+    Ok(match (method, path) {
+        (&Method::GET, "/flightlog") => Query::FlightLog(serde_qs::from_str(query)?),
+        (&Method::GET, "/flightlogs") => Query::FlightLogs(serde_qs::from_str(query)?),
+        (&Method::GET, "/infos") => Query::Infos(serde_qs::from_str(query)?),
+        (&Method::GET, "/history") => Query::History(serde_qs::from_str(query)?),
+        (&Method::GET, "/aircraft") => Query::Aircraft(serde_qs::from_str(query)?),
+        (&Method::GET, "/conflicts") => Query::Conflicts(serde_qs::from_str(query)?),
+        (&Method::GET, "/flights") => Query::Flights(serde_qs::from_str(query)?),
+        (&Method::POST, "/admin/backfill") => Query::StartBackfill(serde_qs::from_str(query)?),
+        (&Method::GET, "/admin/backfill") => Query::Backfill(serde_qs::from_str(query)?),
+        (&Method::POST, "/updates") => Query::Update(serde_qs::from_str(query)?),
+        _ => Query::None,
+    })
+}
+
+/// Deserializes the query string of a request to the parameters of its route,
+/// like [`connection_handler`] does before serving it. Used by the fuzz
+/// targets.
+pub fn parse_query(method: &str, path: &str, query: &str) -> Result<(), serde_qs::Error> {
+    match Method::from_bytes(method.as_bytes()) {
+        Ok(method) => route_query(&method, path, query).map(|_| ()),
+        Err(_) => Ok(()),
+    }
+}
+
 /// Main connexion handler for hyper server
 async fn connection_handler(
     req: Request<Body>,
     context: Context,
     remote_addr: IpAddr,
 ) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
-    let today = chrono::Local::now().date_naive();
-
     if context
         .current_requests
         .clone()
@@ -209,38 +504,57 @@ async fn connection_handler(
     {
         let (parts, body) = req.into_parts();
 
-        let mut full_path_b = dirs::data_dir().expect(
-            "Could not deduce where to store \
-        files. Check your platform compatibility with dirs \
-        (https://crates.io/crates/dirs) crate.",
-        );
-        full_path_b.push(parts.uri.path());
-
         let corps_str = hyper::body::to_bytes(body);
 
         log::info!(
-            "Request of file {} {}",
-            &full_path_b
-                .to_str()
-                .expect("Path to standard storage is not valid UTF-8 !"),
+            "Request of {} {} {}",
+            &parts.method,
+            &parts.uri.path(),
             &parts.uri.query().unwrap_or_default()
         );
 
         let mut response = Response::new(Body::empty());
 
-        match (&parts.method, parts.uri.path()) {
-            (&Method::GET, "/flightlog") => {
+        let query = route_query(
+            &parts.method,
+            parts.uri.path(),
+            parts.uri.query().unwrap_or_default(),
+        );
+        let query = match query {
+            Ok(query) => query,
+            Err(err) => {
+                log::error!("Error while deserializing query objects: {err}");
+                context
+                    .current_requests
+                    .clone()
+                    .decrease_usage(&remote_addr);
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                return Ok(response);
+            }
+        };
+
+        match (&parts.method, parts.uri.path(), query) {
+            (&Method::GET, "/flightlog", Query::FlightLog(query_parameters))
+                if !context.flightlogs.contains_key(&query_parameters.oaci) =>
+            {
+                log::warn!(
+                    "Flightlog of an unknown airport requested: {:?}",
+                    query_parameters
+                );
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+            }
+            (&Method::GET, "/flightlog", Query::FlightLog(query_parameters))
+                if query_parameters.date > context.configuration.today(&query_parameters.oaci) =>
+            {
+                log::warn!(
+                    "Flightlog of a day to come requested: {:?}",
+                    query_parameters
+                );
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+            }
+            (&Method::GET, "/flightlog", Query::FlightLog(query_parameters)) => {
                 add_get_headers(&mut response);
-                let query = parts.uri.query().unwrap();
-                let query_parameters: GetFlightLogsQueryParameters = serde_qs::from_str(query)
-                    .unwrap_or_else(|err| {
-                        log::error!("Error while deserializing query objects: {err}");
-                        context
-                            .current_requests
-                            .clone()
-                            .decrease_usage(&remote_addr);
-                        panic!();
-                    });
+                let today = context.configuration.today(&query_parameters.oaci);
                 if query_parameters.date == today {
                     let flightlog_lock = context.flightlogs[&query_parameters.oaci].lock().unwrap();
                     let clone_planche = (*flightlog_lock).clone();
@@ -248,28 +562,51 @@ async fn connection_handler(
                     *response.body_mut() =
                         Body::from(serde_json::to_string(&clone_planche).unwrap_or_default());
                 } else {
-                    *response.body_mut() = Body::from(
-                        serde_json::to_string(
-                            &FlightLog::from_day(
-                                query_parameters.date,
-                                &query_parameters.oaci,
-                                &context,
-                            )
-                            .await
-                            .unwrap(),
-                        )
-                        .unwrap_or_else(|err| {
+                    match past_flightlog(
+                        query_parameters.date,
+                        &query_parameters.oaci,
+                        today,
+                        &context,
+                    )
+                    .await
+                    {
+                        Ok(flightlog) => {
+                            *response.body_mut() =
+                                Body::from(serde_json::to_string(&flightlog).unwrap_or_default());
+                        }
+                        Err(err) => {
                             log::error!(
                                 "Could not load FlightLog either from disk or network ! : {err}"
                             );
-                            let mut fl = FlightLog::default();
-                            fl.date = today;
-                            serde_json::to_string(&fl).unwrap()
-                        }),
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                    }
+                }
+            }
+            (&Method::GET, "/flightlogs", Query::FlightLogs(query_parameters)) => {
+                add_get_headers(&mut response);
+                if context.flightlogs.contains_key(&query_parameters.oaci)
+                    && query_parameters
+                        .is_valid(context.configuration.today(&query_parameters.oaci))
+                {
+                    let today = context.configuration.today(&query_parameters.oaci);
+                    let (sender, body) = Body::channel();
+                    *response.body_mut() = body;
+                    tokio::spawn(stream_flightlogs(
+                        sender,
+                        query_parameters,
+                        today,
+                        context.clone(),
+                    ));
+                } else {
+                    log::warn!(
+                        "Invalid range of flightlogs requested: {:?}",
+                        query_parameters
                     );
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
                 }
             }
-            (&Method::GET, "/updates") => {
+            (&Method::GET, "/updates", _) => {
                 add_get_headers(&mut response);
                 let mut updates_lock = context.updates.lock().unwrap();
                 let majs = (*updates_lock).clone();
@@ -277,18 +614,187 @@ async fn connection_handler(
                 drop(updates_lock);
                 *response.body_mut() = Body::from(serde_json::to_string(&majs).unwrap_or_default());
             }
-            (&Method::GET, "/infos") => {
+            (&Method::GET, "/history", Query::History(query_parameters)) => {
+                add_get_headers(&mut response);
+                // The updates made before OGN numbered the flight.
+                let former_numbers = match query_parameters.ogn_nb {
+                    Some(ogn_nb) => context
+                        .provenance
+                        .day(query_parameters.date, &query_parameters.oaci)
+                        .await
+                        .map(|provenance| provenance.former_numbers(ogn_nb))
+                        .unwrap_or_else(|err| {
+                            log::error!("Could not read the provenance : {err}");
+                            Vec::new()
+                        }),
+                    None => Vec::new(),
+                };
+                let history = context
+                    .audit
+                    .history(
+                        query_parameters.date,
+                        &query_parameters.oaci,
+                        query_parameters.ogn_nb,
+                        &former_numbers,
+                    )
+                    .await;
+                match history {
+                    Ok(entries) => {
+                        *response.body_mut() =
+                            Body::from(serde_json::to_string(&entries).unwrap_or_default());
+                    }
+                    Err(err) => {
+                        log::error!("Could not read the audit log : {err}");
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                }
+            }
+            (&Method::GET, "/aircraft", Query::Aircraft(query_parameters)) => {
+                add_get_headers(&mut response);
+                let aircraft = context
+                    .aircraft
+                    .day(query_parameters.date, &query_parameters.oaci)
+                    .await;
+                match aircraft {
+                    Ok(aircraft) => {
+                        *response.body_mut() =
+                            Body::from(serde_json::to_string(&aircraft).unwrap_or_default());
+                    }
+                    Err(err) => {
+                        log::error!("Could not read the aircraft : {err}");
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                }
+            }
+            (&Method::GET, "/conflicts", Query::Conflicts(query_parameters)) => {
+                add_get_headers(&mut response);
+                let provenance = context
+                    .provenance
+                    .day(query_parameters.date, &query_parameters.oaci)
+                    .await;
+                match provenance {
+                    Ok(provenance) => {
+                        *response.body_mut() = Body::from(
+                            serde_json::to_string(&provenance.open_conflicts()).unwrap_or_default(),
+                        );
+                    }
+                    Err(err) => {
+                        log::error!("Could not read the conflicts : {err}");
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                }
+            }
+            (&Method::GET, "/flights", Query::Flights(flight_query)) => {
+                add_get_headers(&mut response);
+                if context.flightlogs.contains_key(&flight_query.oaci)
+                    && flight_query.from <= flight_query.to
+                {
+                    match context.storage.flights(&flight_query).await {
+                        Ok(flights) => {
+                            *response.body_mut() =
+                                Body::from(serde_json::to_string(&flights).unwrap_or_default());
+                        }
+                        Err(err) => {
+                            log::error!("Could not query the stored flights : {err}");
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                    }
+                } else {
+                    log::warn!("Invalid query of flights: {:?}", flight_query);
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                }
+            }
+            // Not admin-gated: the tablets show the status to the field crew.
+            (&Method::GET, "/status", _) => {
+                add_get_headers(&mut response);
+                let statuses: HashMap<String, sync::SyncStatus> = context
+                    .configuration
+                    .airports_configs
+                    .iter()
+                    .map(|airport| (airport.oaci(), context.sync_status.get(&airport.oaci())))
+                    .collect();
+                *response.body_mut() =
+                    Body::from(serde_json::to_string(&statuses).unwrap_or_default());
+            }
+            (&Method::GET, "/admin/backup", _) => {
+                if is_admin(&parts.headers, &context) {
+                    log::info!("Sending a backup of the data store.");
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, "application/gzip".parse().unwrap());
+                    response.headers_mut().insert(
+                        CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}\"", backup::default_backup_name())
+                            .parse()
+                            .unwrap(),
+                    );
+                    let (sender, body) = Body::channel();
+                    *response.body_mut() = body;
+                    let configuration = context.configuration.clone();
+                    tokio::task::spawn_blocking(move || stream_backup(sender, &configuration));
+                } else {
+                    log::warn!("Refused admin request from {}", remote_addr);
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                }
+            }
+            (&Method::POST, "/admin/backfill", Query::StartBackfill(query_parameters)) => {
+                if is_admin(&parts.headers, &context) {
+                    let delay =
+                        Duration::from_secs(context.configuration.ogn_sync.backfill_delay_secs);
+                    // The days are requested in the background, the
+                    // progress is on GET /admin/backfill.
+                    let job = backfill::start_backfill(
+                        &context,
+                        &query_parameters.oaci,
+                        query_parameters.from,
+                        query_parameters.to,
+                        delay,
+                    );
+                    match job {
+                        Ok(job) => {
+                            *response.status_mut() = StatusCode::ACCEPTED;
+                            *response.body_mut() =
+                                Body::from(serde_json::to_string(&job).unwrap_or_default());
+                        }
+                        Err(err) if err.is::<backfill::AlreadyRunning>() => {
+                            log::warn!("Refused backfill : {err}");
+                            *response.status_mut() = StatusCode::CONFLICT;
+                        }
+                        Err(err) => {
+                            log::warn!("Refused backfill : {err}");
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
+                    }
+                } else {
+                    log::warn!("Refused admin request from {}", remote_addr);
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                }
+            }
+            (&Method::GET, "/admin/backfill", Query::Backfill(query_parameters)) => {
+                if is_admin(&parts.headers, &context) {
+                    match context.backfills.get(&query_parameters.oaci) {
+                        Some(job) => {
+                            *response.body_mut() =
+                                Body::from(serde_json::to_string(&job).unwrap_or_default());
+                        }
+                        None => *response.status_mut() = StatusCode::NOT_FOUND,
+                    }
+                } else {
+                    log::warn!("Refused admin request from {}", remote_addr);
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                }
+            }
+            (&Method::GET, "/infos", Query::Infos(query_parameters))
+                if !context.flightlogs.contains_key(&query_parameters.oaci) =>
+            {
+                log::warn!(
+                    "Infos of an unknown airport requested: {:?}",
+                    query_parameters
+                );
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+            }
+            (&Method::GET, "/infos", Query::Infos(query_parameters)) => {
                 add_get_headers(&mut response);
-                let query = parts.uri.query().unwrap();
-                let query_parameters: GetInfosQueryParameters = serde_qs::from_str(query)
-                    .unwrap_or_else(|err| {
-                        log::error!("Error while deserializing query objects: {err}");
-                        context
-                            .current_requests
-                            .clone()
-                            .decrease_usage(&remote_addr);
-                        panic!();
-                    });
                 let infos = context.configuration.infos(&query_parameters.oaci);
                 let body = serde_json::to_string(&infos);
                 match body {
@@ -308,38 +814,82 @@ async fn connection_handler(
                     }
                 }
             }
-            (&Method::POST, "/updates") => {
-                let query = parts.uri.query().unwrap();
-                let query_parameters: PostUpdateQueryParameters =
-                    serde_qs::from_str(query).unwrap();
-                let mut clean_json = String::new();
-                for char in std::str::from_utf8(&corps_str.await?)
-                    .unwrap()
-                    .to_string()
+            (&Method::POST, "/updates", Query::Update(query_parameters))
+                if !context.flightlogs.contains_key(&query_parameters.oaci) =>
+            {
+                log::warn!(
+                    "Update of an unknown airport received: {:?}",
+                    query_parameters
+                );
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+            }
+            (&Method::POST, "/updates", Query::Update(query_parameters)) => {
+                let clean_json: String = String::from_utf8_lossy(&corps_str.await?)
                     .chars()
-                {
-                    if char as u32 != 0 {
-                        clean_json.push_str(char.to_string().as_str());
+                    .filter(|char| *char as u32 != 0)
+                    .collect();
+
+                let update: Update = match serde_json::from_str(&clean_json) {
+                    Ok(update) => update,
+                    Err(err) => {
+                        log::warn!("Malformed update received from {remote_addr} : {err}");
+                        context
+                            .current_requests
+                            .clone()
+                            .decrease_usage(&remote_addr);
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from(format!("Malformed update : {err}"));
+                        return Ok(response);
                     }
+                };
+                let oaci = query_parameters.oaci;
+                let today = context.configuration.today(&oaci);
+                if update.date > today {
+                    log::warn!("Update of a day to come received from {remote_addr}");
+                    context
+                        .current_requests
+                        .clone()
+                        .decrease_usage(&remote_addr);
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    return Ok(response);
                 }
-
-                let update: Update = serde_json::from_str(&clean_json).unwrap_or_default();
                 {
                     let mut updates_lock = context.updates.lock().unwrap();
                     (*updates_lock).push(update.clone());
                 }
 
-                if update.date != today {
-                    let mut wanted_flightlog =
-                        FlightLog::from_day(update.date, &query_parameters.oaci, &context).await?;
-                    wanted_flightlog.update(update);
-                    wanted_flightlog.save(&query_parameters.oaci).await;
-                } else {
-                    let mut flightlog_lock =
-                        context.flightlogs[&query_parameters.oaci].lock().unwrap();
-                    (*flightlog_lock).update(update);
-                    let _ = (*flightlog_lock).save(&query_parameters.oaci);
-                    drop(flightlog_lock);
+                let date = update.date;
+                let received_at = chrono::Local::now();
+                let saved = match context.journal.append(&oaci, &update).await {
+                    Ok(id) => {
+                        let saved = apply_update(update.clone(), &oaci, today, &context).await;
+                        // The audit log tells whether the update was saved.
+                        let entry = AuditEntry {
+                            received_at,
+                            client: remote_addr,
+                            oaci: oaci.clone(),
+                            update,
+                            error: saved.as_ref().err().map(|err| err.to_string()),
+                        };
+                        if let Err(err) = context.audit.append(&entry).await {
+                            log::error!("Could not write the update in the audit log : {err}");
+                        }
+                        saved.map(|()| id)
+                    }
+                    Err(err) => Err(err),
+                };
+                match saved {
+                    Ok(id) => {
+                        if let Err(err) = context.journal.remove(&[id]).await {
+                            log::warn!("Could not clean the journal of updates : {err}");
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Could not save the update of {oaci} on the {date} : {err}");
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() =
+                            Body::from(format!("Could not save the update : {err}"));
+                    }
                 }
                 response
                     .headers_mut()
@@ -348,7 +898,7 @@ async fn connection_handler(
                     .headers_mut()
                     .insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
             }
-            (&Method::OPTIONS, "/majs") => {
+            (&Method::OPTIONS, "/majs", _) => {
                 *response.status_mut() = StatusCode::NO_CONTENT;
                 response
                     .headers_mut()
@@ -368,7 +918,7 @@ async fn connection_handler(
                     "origin, content-type".parse().unwrap(),
                 );
             }
-            (&Method::OPTIONS, "/flightlog") => {
+            (&Method::OPTIONS, "/flightlog", _) => {
                 log::info!("Serving OPTIONS for flightlog");
                 *response.status_mut() = StatusCode::NO_CONTENT;
                 response
@@ -388,15 +938,14 @@ async fn connection_handler(
                 );
                 *response.status_mut() = hyper::StatusCode::NOT_FOUND;
                 *response.body_mut() = Body::from(
-                    fs::read_to_string(data_dir().as_path().join("404.html")).unwrap_or_else(
-                        |err| {
-                            log::warn!(
-                                "Could not load 404.html : {} Please add it to $XDG_DATA_DIR/cepo.",
-                                err
-                            );
-                            "".to_string()
-                        },
-                    ),
+                    fs::read_to_string(context.data_dir.join("404.html")).unwrap_or_else(|err| {
+                        log::warn!(
+                            "Could not load 404.html : {} Please add it to {:?}.",
+                            err,
+                            &context.data_dir
+                        );
+                        "".to_string()
+                    }),
                 );
             }
         };
@@ -411,6 +960,186 @@ async fn connection_handler(
     }
 }
 
+/// Writes a backup of the data store in a response body as it is compressed,
+/// so that it does not need to be in memory. Runs on a blocking thread.
+fn stream_backup(mut sender: hyper::body::Sender, configuration: &Configuration) {
+    /// Sends what is written in the body of the response.
+    struct BodyWriter<'a> {
+        sender: &'a mut hyper::body::Sender,
+        runtime: tokio::runtime::Handle,
+    }
+
+    impl std::io::Write for BodyWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let chunk = hyper::body::Bytes::copy_from_slice(buf);
+            self.runtime
+                .block_on(self.sender.send_data(chunk))
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::BrokenPipe, err))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let writer = std::io::BufWriter::with_capacity(
+        64 * 1024,
+        BodyWriter {
+            sender: &mut sender,
+            runtime: tokio::runtime::Handle::current(),
+        },
+    );
+    let sent = backup::write_backup(configuration, writer).and_then(|writer| {
+        writer
+            .into_inner()
+            .map(|_| ())
+            .map_err(|err| err.into_error().into())
+    });
+    if let Err(err) = sent {
+        log::error!("Could not send a backup : {err}");
+        // The client sees an aborted download rather than a truncated archive.
+        sender.abort();
+    }
+}
+
+/// Sends the flightlogs of a range of days as a JSON array, one flightlog at a
+/// time so that a whole month does not need to be in memory.
+async fn stream_flightlogs(
+    mut sender: hyper::body::Sender,
+    query_parameters: GetFlightLogsRangeQueryParameters,
+    today: NaiveDate,
+    context: Context,
+) {
+    let last_day = query_parameters.last_day(today);
+    let oaci = query_parameters.oaci;
+    let dates = if query_parameters.refresh {
+        query_parameters
+            .from
+            .iter_days()
+            .take_while(|date| *date <= last_day)
+            .collect()
+    } else {
+        match context
+            .storage
+            .dates(&oaci, query_parameters.from, query_parameters.to)
+            .await
+        {
+            Ok(dates) => dates,
+            Err(err) => {
+                log::error!("Could not list the stored flightlogs of {oaci} : {err}");
+                sender.abort();
+                return;
+            }
+        }
+    };
+    if sender.send_data("[".into()).await.is_err() {
+        return;
+    }
+    for (index, date) in dates.into_iter().enumerate() {
+        let flightlog = if date == today {
+            Ok(context.flightlogs[&oaci].lock().unwrap().clone())
+        } else if query_parameters.refresh {
+            past_flightlog(date, &oaci, today, &context).await
+        } else {
+            FlightLog::load(date, &oaci, &context).await
+        };
+        let flightlog = match flightlog {
+            Ok(flightlog) => flightlog,
+            Err(err) => {
+                log::error!("Could not load the flightlog of {oaci} on the {date} : {err}");
+                sender.abort();
+                return;
+            }
+        };
+        let mut chunk = if index == 0 {
+            String::new()
+        } else {
+            String::from(",")
+        };
+        chunk.push_str(&serde_json::to_string(&flightlog).unwrap_or_default());
+        if sender.send_data(chunk.into()).await.is_err() {
+            // The client went away.
+            return;
+        }
+    }
+    let _ = sender.send_data("]".into()).await;
+}
+
+/// Applies an update to the flightlog of its day (the one in memory for today)
+/// and saves it. Once saved, the field is known to be entered by hand, which
+/// resolves its conflict with OGN. The changes of a day are made one at a
+/// time, so when it returns, the saved flightlog includes the update.
+pub async fn apply_update(
+    update: Update,
+    oaci: &String,
+    today: NaiveDate,
+    context: &Context,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (date, ogn_nb, field) = (update.date, update.ogn_nb, update.field.clone());
+    if date != today {
+        // The day is fetched first, as the fetch takes the lock of the day.
+        past_flightlog(date, oaci, today, context).await?;
+        let _day = context.days.lock((oaci.clone(), date)).await;
+        let mut wanted_flightlog = latest_flightlog(date, oaci, context).await?;
+        wanted_flightlog.update(update);
+        wanted_flightlog.save(oaci, context).await?;
+        context.cache.insert(oaci, wanted_flightlog);
+    } else {
+        // Saved under the lock of the day, so that an older version of the
+        // flightlog is never written over a newer one.
+        let _day = context.days.lock((oaci.clone(), date)).await;
+        let flightlog = {
+            let mut flightlog_lock = context.flightlogs[oaci].lock().unwrap();
+            (*flightlog_lock).update(update);
+            (*flightlog_lock).clone()
+        };
+        flightlog.save(oaci, context).await?;
+    }
+    context
+        .provenance
+        .update(date, oaci, |provenance| {
+            provenance.record_manual(ogn_nb, &field)
+        })
+        .await;
+    Ok(())
+}
+
+/// How long a background loop of the server waits before it is started again
+/// after a panic.
+const RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Runs a background loop of the server (`start` returns a new one), and
+/// starts it again after [`RESTART_DELAY`] if it panics, so that a bug on a
+/// day neither stops it silently nor the server.
+fn supervise<F, Fut>(name: String, start: F) -> tokio::task::JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match tokio::spawn(start()).await {
+                Ok(()) => {
+                    log::warn!("The {name} loop ended.");
+                    return;
+                }
+                Err(err) if err.is_panic() => {
+                    log::error!(
+                        "The {name} loop panicked, starting it again in {:?} : {err}",
+                        RESTART_DELAY
+                    );
+                    tokio::time::sleep(RESTART_DELAY).await;
+                }
+                Err(err) => {
+                    log::warn!("The {name} loop was cancelled : {err}");
+                    return;
+                }
+            }
+        }
+    })
+}
+
 /// The handler for the end of the program
 async fn signal_extinction() {
     // Waiting for the CTRL-C signal
@@ -419,8 +1148,10 @@ async fn signal_extinction() {
         .expect("Failed to install signal handler for Ctrl-C");
 }
 
-/// A function that provides the basic path for storage using dirs crate to
-/// provide platform specific paths.
+/// A function that provides the default path for storage using dirs crate to
+/// provide platform specific paths. It is used when no data dir is given in
+/// the configuration, on the command line or in the `CEPO_DATA_DIR`
+/// environment variable.
 pub fn data_dir() -> std::path::PathBuf {
     let mut data_dir = dirs::data_dir().expect(
         "Couldn't guess where to store files. Check your os compatibility \
@@ -430,6 +1161,18 @@ pub fn data_dir() -> std::path::PathBuf {
     data_dir
 }
 
+/// Returns true if the request carries the admin token of the configuration.
+pub fn is_admin(headers: &HeaderMap, context: &Context) -> bool {
+    match &context.configuration.admin_token {
+        Some(token) if !token.is_empty() => headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| given == token),
+        _ => false,
+    }
+}
+
 /// Add common headers to a get Response
 pub fn add_get_headers(response: &mut Response<Body>) {
     response
@@ -446,8 +1189,17 @@ pub fn add_get_headers(response: &mut Response<Body>) {
 
 #[cfg(test)]
 mod tests {
-    use crate::GetFlightLogsQueryParameters;
-    use chrono::NaiveDate;
+    use crate::cache::{past_flightlog, FutureDay};
+    use crate::configuration::Configuration;
+    use crate::{
+        connection_handler, supervise, Context, GetFlightLogsQueryParameters,
+        GetFlightLogsRangeQueryParameters,
+    };
+    use brick_ogn::flightlog::update::Update;
+    use chrono::{NaiveDate, NaiveTime};
+    use hyper::{Body, Method, Request, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn get_flightlogs_query_parameters_deser() {
@@ -458,4 +1210,260 @@ mod tests {
         };
         assert_eq!(str, serde_qs::from_str(query).unwrap())
     }
+
+    #[test]
+    fn get_flightlogs_range_query_parameters_deser() {
+        let query = "oaci=LFLE&from=2024-06-01&to=2024-06-30";
+        let parameters: GetFlightLogsRangeQueryParameters = serde_qs::from_str(query).unwrap();
+        assert_eq!(
+            parameters,
+            GetFlightLogsRangeQueryParameters {
+                oaci: String::from("LFLE"),
+                from: NaiveDate::from_ymd_opt(2024, 6, 1).unwrap(),
+                to: NaiveDate::from_ymd_opt(2024, 6, 30).unwrap(),
+                refresh: false,
+            }
+        );
+        let parameters: GetFlightLogsRangeQueryParameters =
+            serde_qs::from_str(&format!("{query}&refresh=true")).unwrap();
+        assert!(parameters.refresh);
+    }
+
+    #[test]
+    fn refreshed_range_of_flightlogs() {
+        let date = |day| NaiveDate::from_ymd_opt(2024, 6, day).unwrap();
+        let query = |from, to, refresh| GetFlightLogsRangeQueryParameters {
+            oaci: String::from("LFLE"),
+            from: date(from),
+            to: date(to),
+            refresh,
+        };
+        let today = date(10);
+        // the future days are not refreshed
+        assert_eq!(query(1, 30, true).last_day(today), today);
+        assert_eq!(query(1, 30, false).last_day(today), date(30));
+        assert!(query(1, 30, true).is_valid(today));
+        assert!(query(20, 30, true).is_valid(today));
+        assert!(!query(2, 1, false).is_valid(today));
+        let year = GetFlightLogsRangeQueryParameters {
+            from: NaiveDate::from_ymd_opt(2023, 6, 11).unwrap(),
+            ..query(1, 10, false)
+        };
+        assert!(year.is_valid(today));
+        assert!(!GetFlightLogsRangeQueryParameters {
+            refresh: true,
+            ..year
+        }
+        .is_valid(today));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backups_are_streamed_without_the_admin_token() {
+        let data_dir = std::env::temp_dir().join(format!("cepo-backup-{}", std::process::id()));
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.ogn_url = String::from("http://127.0.0.1:9");
+        configuration.admin_token = Some(String::from("secret"));
+        let context = Context::new(configuration).await;
+        std::fs::write(data_dir.join("journal.jsonl"), "").unwrap();
+
+        let request = Request::builder()
+            .uri("/admin/backup")
+            .header("Authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = connection_handler(request, context.clone(), "127.0.0.1".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let archive = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut names = Vec::new();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&archive[..]));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            assert!(!content.contains("secret"), "{name}");
+            names.push(name);
+        }
+        assert!(names.contains(&String::from("manifest.json")));
+        assert!(names.contains(&String::from("data/journal.jsonl")));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stored_flights_are_queried_by_pilot() {
+        let data_dir = std::env::temp_dir().join(format!("cepo-flights-{}", std::process::id()));
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.ogn_url = String::from("http://127.0.0.1:9");
+        let context = Context::new(configuration).await;
+        let mut flightlog = brick_ogn::flightlog::FlightLog::new();
+        flightlog.date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        for (ogn_nb, pilot) in [(1, "Marie"), (2, "Pierre")] {
+            flightlog.flights.push(brick_ogn::flight::Flight {
+                ogn_nb,
+                glider: String::from("F-CEJU"),
+                pilot1: String::from(pilot),
+                ..Default::default()
+            });
+        }
+        context.storage.save(&flightlog, "LFLE").await.unwrap();
+
+        let request = Request::builder()
+            .uri("/flights?oaci=LFLE&from=2024-06-01&to=2024-06-30&pilot=Marie")
+            .body(Body::empty())
+            .unwrap();
+        let response = connection_handler(request, context.clone(), "127.0.0.1".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let flights: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(flights.len(), 1);
+        assert_eq!(flights[0]["date"], "2024-06-10");
+        assert_eq!(flights[0]["flight"]["pilot1"], "Marie");
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_queries_are_bad_requests() {
+        let data_dir = std::env::temp_dir().join(format!("cepo-queries-{}", std::process::id()));
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.ogn_url = String::from("http://127.0.0.1:9");
+        let context = Context::new(configuration).await;
+        let remote_addr = "127.0.0.1".parse().unwrap();
+        let requests = [
+            (Method::GET, "/flightlog"),
+            (Method::GET, "/flightlog?date=2024-06-10"),
+            (Method::GET, "/flightlog?date=yesterday&oaci=LFLE"),
+            (Method::GET, "/flightlog?date=2024-06-10&oaci=XXXX"),
+            (Method::GET, "/flightlogs?oaci=LFLE&from=2024-06-10"),
+            (Method::GET, "/infos"),
+            (Method::GET, "/infos?oaci=XXXX"),
+            (Method::GET, "/history?oaci=LFLE"),
+            (
+                Method::GET,
+                "/flights?oaci=LFLE&from=2024-06-10&to=2024-06-01",
+            ),
+            (Method::POST, "/updates"),
+            (Method::POST, "/updates?oaci=XXXX"),
+            (Method::POST, "/updates?oaci=LFLE"),
+            (Method::POST, "/admin/backfill?oaci=LFLE&from=2024-06-10"),
+        ];
+        for (method, uri) in requests {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let response = connection_handler(request, context.clone(), remote_addr)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+        assert!(context.current_requests.lock().unwrap().is_empty());
+        // a malformed update is neither kept nor journaled
+        assert!(context.updates.lock().unwrap().is_empty());
+        assert!(context.journal.entries().await.unwrap().is_empty());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn days_to_come_are_bad_requests() {
+        let data_dir = std::env::temp_dir().join(format!("cepo-future-{}", std::process::id()));
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.ogn_url = String::from("http://127.0.0.1:9");
+        let context = Context::new(configuration).await;
+        let remote_addr = "127.0.0.1".parse().unwrap();
+        let tomorrow = context
+            .configuration
+            .today(&String::from("LFLE"))
+            .succ_opt()
+            .unwrap();
+
+        let request = Request::builder()
+            .uri(format!("/flightlog?oaci=LFLE&date={tomorrow}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = connection_handler(request, context.clone(), remote_addr)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let update = Update {
+            ogn_nb: 1,
+            field: String::from("Marie"),
+            date: tomorrow,
+            time: tomorrow.and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap()),
+        };
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/updates?oaci=LFLE")
+            .body(Body::from(serde_json::to_string(&update).unwrap()))
+            .unwrap();
+        let response = connection_handler(request, context.clone(), remote_addr)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(context.journal.entries().await.unwrap().is_empty());
+
+        // nothing is requested nor stored for the day
+        let oaci = String::from("LFLE");
+        let today = context.configuration.today(&oaci);
+        let fetched = past_flightlog(tomorrow, &oaci, today, &context).await;
+        assert!(fetched.is_err_and(|err| err.is::<FutureDay>()));
+        assert!(context
+            .storage
+            .dates(&oaci, tomorrow, tomorrow)
+            .await
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn maintenance_contexts_leave_the_journal_to_the_server() {
+        let data_dir =
+            std::env::temp_dir().join(format!("cepo-maintenance-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let mut configuration = Configuration::example();
+        configuration.data_dir = Some(data_dir.clone());
+        configuration.ogn_url = String::from("http://127.0.0.1:9");
+        let oaci = String::from("LFLE");
+        let today = configuration.today(&oaci);
+        let context = Context::new(configuration.clone()).await;
+        let update = Update {
+            ogn_nb: 1,
+            field: String::from("Marie"),
+            date: today,
+            time: today.and_time(NaiveTime::from_hms_opt(12, 0, 0).unwrap()),
+        };
+        context.journal.append(&oaci, &update).await.unwrap();
+
+        let maintenance = Context::for_maintenance(configuration.clone()).await;
+        assert_eq!(maintenance.journal.entries().await.unwrap().len(), 1);
+        let server = Context::new(configuration).await;
+        assert!(server.journal.entries().await.unwrap().is_empty());
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicking_loops_are_started_again() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        let supervisor = supervise(String::from("test"), move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("a bug of the day");
+                }
+            }
+        });
+        supervisor.await.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
 }
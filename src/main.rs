@@ -1,24 +1,101 @@
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
 use serveur::{
+    archive::archive_old_seasons,
+    backfill::backfill,
+    backup::{default_backup_name, restore_backup, write_backup},
     configuration::{copy_example_configuration_file, Configuration},
-    data_dir, Context,
+    fsck::{fsck, FsckOptions},
+    Context,
 };
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[cfg(not(debug_assertions))]
 use human_panic::setup_panic;
 
 use std::io::IsTerminal;
 
+/// Command line arguments of the server.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The root directory of the storage. Overrides the one of the
+    /// configuration file.
+    #[arg(long, env = "CEPO_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// What to do instead of running the server.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Maintenance commands.
+#[derive(Subcommand)]
+enum Command {
+    /// Checks the stored flightlogs (live, archived or in the database) and
+    /// reports their problems.
+    Fsck {
+        /// Repair the flightlogs that can be repaired.
+        #[arg(long)]
+        repair: bool,
+        /// Move the flightlogs and archives that cannot be read to
+        /// `quarantine/`.
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// Writes a compressed archive of the configuration (without the admin
+    /// token) and of all the data.
+    Backup {
+        /// The archive to write (default to `cepo-backup-DATE.tar.gz`).
+        output: Option<PathBuf>,
+    },
+    /// Replaces the configuration and the data with the ones of an archive,
+    /// once the server is stopped. The previous data is kept in the data dir,
+    /// under `.before-restore-DATE/`.
+    Restore {
+        /// The archive to restore.
+        archive: PathBuf,
+    },
+    /// Packs the finished years (or months, see `archive_after` in the
+    /// configuration) of the flightlogs into compressed archives.
+    Archive,
+    /// Requests the past days of an airport to OGN and merges them in the
+    /// stored flightlogs.
+    Backfill {
+        /// The OACI code of the airport.
+        oaci: String,
+        /// The first day (YYYY-MM-DD).
+        from: NaiveDate,
+        /// The last day (YYYY-MM-DD), default to the first one.
+        to: Option<NaiveDate>,
+        /// The pause between two days, in seconds (default to
+        /// `ogn_sync.backfill_delay_secs` of the configuration).
+        #[arg(long)]
+        delay_secs: Option<u64>,
+    },
+}
+
+/// Replaces the data dir of the configuration by the one of the command line
+/// or of `CEPO_DATA_DIR`, if any.
+fn override_data_dir(configuration: &mut Configuration, data_dir: Option<PathBuf>) {
+    if data_dir.is_some() {
+        configuration.data_dir = data_dir;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // initializing cli tools (confy, log, panic)
     #[cfg(not(debug_assertions))]
     setup_panic!();
 
+    let cli = Cli::parse();
+
     let mut configuration = confy::load("cepo", None).unwrap_or_else(|err| {
         log::error!(
             "Error while loading configuration : {} \nFor information the file should be located at {:?}",
             err,
-            data_dir()
+            confy::get_configuration_file_path("cepo", None)
         );
         Configuration::default()
     });
@@ -57,13 +134,75 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             panic!(); //should return a more sexy error
         }
     }
+    override_data_dir(&mut configuration, cli.data_dir);
     env_logger::Builder::from_env(
         env_logger::Env::default().default_filter_or(&configuration.log_level),
     )
     .init();
 
-    let context = Context::new(configuration).await;
-    context.server().await?;
+    match cli.command {
+        Some(Command::Backup { output }) => {
+            let output = output.unwrap_or_else(|| PathBuf::from(default_backup_name()));
+            write_backup(&configuration, std::fs::File::create(&output)?)?;
+            println!("Backup written to {:?}", output);
+        }
+        Some(Command::Restore { archive }) => {
+            let report = restore_backup(&archive, &configuration)?;
+            confy::store("cepo", None, report.configuration)?;
+            println!(
+                "Restored {} file(s) from a backup of the {} (format {}).",
+                report.files, report.manifest.created_at, report.manifest.format_version
+            );
+            if let Some(previous) = report.previous_data_dir {
+                println!("The previous data was moved to {:?}", previous);
+            }
+        }
+        None => Context::new(configuration).await.server().await?,
+        Some(Command::Fsck { repair, quarantine }) => {
+            let context = Context::for_maintenance(configuration).await;
+            let report = fsck(&context, FsckOptions { repair, quarantine }).await?;
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            println!(
+                "{} flightlog(s) checked, {} problem(s) found.",
+                report.checked,
+                report.problems.len()
+            );
+            if report.has_remaining_problems() {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Archive) => {
+            let context = Context::for_maintenance(configuration).await;
+            for (period, files) in archive_old_seasons(&context).await? {
+                println!("{} file(s) archived in {}", files, period.archive_name());
+            }
+        }
+        Some(Command::Backfill {
+            oaci,
+            from,
+            to,
+            delay_secs,
+        }) => {
+            let context = Context::for_maintenance(configuration).await;
+            let delay = Duration::from_secs(
+                delay_secs.unwrap_or(context.configuration.ogn_sync.backfill_delay_secs),
+            );
+            let report = backfill(&context, &oaci, from, to.unwrap_or(from), delay).await?;
+            for day in &report.days {
+                println!("{}", day);
+            }
+            println!(
+                "{} day(s) backfilled, {} failed.",
+                report.days.len(),
+                report.failures()
+            );
+            if report.failures() > 0 {
+                std::process::exit(1);
+            }
+        }
+    }
 
     return Ok(());
 }
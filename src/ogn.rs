@@ -1,134 +1,316 @@
 //! To request ogn
+//! The logbooks are fetched through an [`OgnSource`] held by the
+//! [`crate::Context`], [`HttpOgn`] being the one requesting the OGN flightbook,
+//! and parsed into the typed [`Logbook`]. A malformed flight is skipped with a
+//! warning instead of failing the whole synchronisation.
 
+use crate::ddb::DeviceDatabase;
 use crate::flightlog::Storage;
+use crate::launch::{detect_launch, fill_tow_pilots, LaunchSettings};
+use crate::reconcile::{reconcile, MatchPolicy};
+use crate::selection::Selection;
+use crate::time_zone::AirportZone;
 use crate::Context;
+use async_trait::async_trait;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use crate::Aircraft;
 use brick_ogn::flight::Flight;
 use brick_ogn::flightlog::FlightLog;
 use chrono::prelude::*;
-use json::JsonValue;
 use log;
 
-/// Returns Flights that we requested to OGN and these are sorted
-pub async fn ogn_flights(
-    date: NaiveDate,
-    immatriculations: Vec<String>,
-    oaci: String,
-) -> Result<Vec<Flight>, hyper::Error> {
-    log::info!(
-        "Requete à http://flightbook.glidernet.org/api/logbook/{}/{}",
-        oaci,
-        date.format("%Y-%m-%d").to_string()
-    );
-    let client = hyper::Client::new();
-    let chemin = format!(
-        "http://flightbook.glidernet.org/api/logbook/{}/{}",
-        oaci,
-        date.format("%Y-%m-%d")
-    )
-    .parse::<hyper::Uri>()
-    .unwrap();
-    let reponse = client.get(chemin).await?;
-    let bytes = hyper::body::to_bytes(reponse.into_body()).await?;
-    let corps_str = std::str::from_utf8(&bytes).unwrap().to_string();
-
-    let requete_parse = json::parse(corps_str.as_str()).unwrap();
-    log::info!("Traitement de la requete.");
+/// The default base URL of the OGN flightbook API.
+pub const DEFAULT_OGN_URL: &str = "http://flightbook.glidernet.org/api";
+
+/// Errors returned when requesting OGN or reading its answers.
+#[derive(Debug)]
+pub enum OgnError {
+    /// The URL of the request is not valid.
+    InvalidUrl(String),
+    /// The request could not be made.
+    Http(hyper::Error),
+    /// OGN answered with an error status.
+    Status(hyper::StatusCode),
+    /// The answer is not a JSON logbook.
+    Malformed(serde_json::Error),
+    /// A flight has no `device`.
+    MissingDevice,
+    /// A flight refers to a device that is not in the logbook.
+    UnknownDevice(usize),
+    /// A time is not like `10h42`.
+    InvalidTime(String),
+    /// OGN did not answer in time.
+    Timeout(std::time::Duration),
+    /// OGN failed too many times and is not requested until then.
+    CircuitOpen(DateTime<Local>),
+}
 
-    /* making link between number and immatriculations because ogn identifies
-    aircrafts with numbers */
-    let devices = requete_parse["devices"].clone();
-    let mut appareils_ogn: Vec<Aircraft> = Vec::new();
-    let tableau_devices = match devices {
-        JsonValue::Array(appareils_json) => appareils_json,
-        _ => {
-            eprintln!("devices n'est pas un tableau");
-            Vec::new()
+impl fmt::Display for OgnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OgnError::InvalidUrl(url) => write!(f, "invalid OGN URL {url}"),
+            OgnError::Http(err) => write!(f, "could not request OGN : {err}"),
+            OgnError::Status(status) => write!(f, "OGN answered {status}"),
+            OgnError::Malformed(err) => write!(f, "malformed OGN logbook : {err}"),
+            OgnError::MissingDevice => write!(f, "flight without device"),
+            OgnError::UnknownDevice(device) => write!(f, "unknown device {device}"),
+            OgnError::InvalidTime(time) => write!(f, "invalid time {time:?}"),
+            OgnError::Timeout(timeout) => write!(f, "no answer of OGN after {timeout:?}"),
+            OgnError::CircuitOpen(until) => {
+                write!(f, "OGN is not requested until {}", until.format("%H:%M"))
+            }
         }
-    };
+    }
+}
+
+impl std::error::Error for OgnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OgnError::Http(err) => Some(err),
+            OgnError::Malformed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
-    for appareil in tableau_devices {
-        let modele_json = appareil["aircraft"].clone();
-        let modele = modele_json.as_str().unwrap_or_default().to_string();
+impl From<hyper::Error> for OgnError {
+    fn from(err: hyper::Error) -> Self {
+        OgnError::Http(err)
+    }
+}
 
-        let category_json = appareil["aircraft_type"].clone();
-        let category = category_json.as_u8().unwrap();
+/// Where the OGN logbooks come from.
+#[async_trait]
+pub trait OgnSource: Send + Sync {
+    /// Returns the raw JSON logbook of an airport on a day.
+    async fn logbook(&self, oaci: &str, date: NaiveDate) -> Result<Vec<u8>, OgnError>;
+}
 
-        let immatriculation_json = appareil["registration"].clone();
-        let immatriculation = immatriculation_json
-            .as_str()
-            .unwrap_or_default()
-            .to_string();
+/// Requests the logbooks to an OGN flightbook API over HTTP, at
+/// `base_url/logbook/OACI/YYYY-MM-DD`.
+pub struct HttpOgn {
+    base_url: String,
+    client: hyper::Client<hyper::client::HttpConnector>,
+}
 
-        let appareil_actuel = Aircraft {
-            modele,
-            category,
-            immatriculation,
-        };
-        appareils_ogn.push(appareil_actuel);
+impl HttpOgn {
+    /// Creates a source requesting the API at `base_url` (see
+    /// [`DEFAULT_OGN_URL`]).
+    pub fn new(base_url: String) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: hyper::Client::new(),
+        }
     }
+}
 
-    /* listing flights to give them immatriculations */
+#[async_trait]
+impl OgnSource for HttpOgn {
+    async fn logbook(&self, oaci: &str, date: NaiveDate) -> Result<Vec<u8>, OgnError> {
+        let chemin = format!(
+            "{}/logbook/{}/{}",
+            self.base_url,
+            oaci,
+            date.format("%Y-%m-%d")
+        );
+        log::info!("Requete à {}", chemin);
+        let uri = chemin
+            .parse::<hyper::Uri>()
+            .map_err(|_| OgnError::InvalidUrl(chemin.clone()))?;
+        let reponse = self.client.get(uri).await?;
+        if !reponse.status().is_success() {
+            return Err(OgnError::Status(reponse.status()));
+        }
+        let bytes = hyper::body::to_bytes(reponse.into_body()).await?;
+        Ok(bytes.to_vec())
+    }
+}
 
-    let mut vols: Vec<Flight> = Vec::new();
-    let flights = requete_parse["flights"].clone();
-    let vols_json = match flights {
-        JsonValue::Array(vols_json) => vols_json,
-        _ => {
-            eprintln!("La requete ogn n'a pas fourni un tableau.");
-            Vec::new()
+/// A device of a logbook, as described by the
+/// [API](https://gitlab.com/davischappins/ogn-flightbook/-/blob/master/doc/API.md).
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+pub struct LogbookDevice {
+    /// The model of the aircraft.
+    #[serde(default)]
+    pub aircraft: Option<String>,
+    /// The OGN category of the aircraft (glider, tow plane...).
+    #[serde(default)]
+    pub aircraft_type: Option<u8>,
+    /// The immatriculation of the aircraft.
+    #[serde(default)]
+    pub registration: Option<String>,
+    /// The address of the device, like `DD8E3A`.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// The competition number of the aircraft.
+    #[serde(default)]
+    pub competition: Option<String>,
+}
+
+impl LogbookDevice {
+    /// Returns the aircraft described by the device.
+    pub fn aircraft(&self) -> Aircraft {
+        Aircraft {
+            modele: self.aircraft.clone().unwrap_or_default(),
+            category: self.aircraft_type.unwrap_or_default(),
+            immatriculation: self.registration.clone().unwrap_or_default(),
+            competition: self.competition.clone().unwrap_or_default(),
         }
-    };
+    }
+}
 
-    for (mut index, vol_json) in vols_json.clone().into_iter().enumerate() {
-        index += 1;
+/// A flight of a logbook.
+#[derive(Debug, Clone, PartialEq, Default, serde::Deserialize)]
+pub struct LogbookFlight {
+    /// The index of the aircraft in the devices.
+    #[serde(default)]
+    pub device: Option<usize>,
+    /// The takeoff time, like `10h42`.
+    #[serde(default)]
+    pub start: Option<String>,
+    /// The landing time, `None` if still flying.
+    #[serde(default)]
+    pub stop: Option<String>,
+    /// The UNIX timestamp of the takeoff.
+    #[serde(default)]
+    pub start_tsp: Option<i64>,
+    /// The UNIX timestamp of the landing.
+    #[serde(default)]
+    pub stop_tsp: Option<i64>,
+    /// The index of the flight of the tow plane, for an aerotow.
+    #[serde(default)]
+    pub tow: Option<usize>,
+}
 
-        // getting necessary fields
-        let device = vol_json["device"].clone();
-        let device_number = device.as_u8().unwrap() as usize;
-        let immatriculation = appareils_ogn[device_number].immatriculation.clone();
-        if !(immatriculations
-            .iter()
-            .any(|immat| *immat == immatriculation.clone()))
-        {
-            //Don't take immatriculation into account if not in list
-            continue;
+/// A logbook of an airport on a day. The devices and flights that cannot be
+/// read are `None` so that the indexes of the others stay right.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Logbook {
+    /// The aircrafts seen during the day.
+    pub devices: Vec<Option<LogbookDevice>>,
+    /// The flights of the day.
+    pub flights: Vec<Option<LogbookFlight>>,
+}
+
+/// The logbook as sent by OGN, before reading each of its entries.
+#[derive(serde::Deserialize)]
+struct RawLogbook {
+    #[serde(default)]
+    devices: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    flights: Option<Vec<serde_json::Value>>,
+}
+
+impl Logbook {
+    /// Parses a raw logbook. Only an answer that is not a JSON logbook at all
+    /// is an error, the malformed entries are logged and left out.
+    pub fn parse(bytes: &[u8]) -> Result<Self, OgnError> {
+        let raw: RawLogbook = serde_json::from_slice(bytes).map_err(OgnError::Malformed)?;
+        let devices = raw
+            .devices
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(index, device)| {
+                serde_json::from_value(device)
+                    .map_err(|err| log::warn!("Skipping the OGN device {index} : {err}"))
+                    .ok()
+            })
+            .collect();
+        let flights = raw
+            .flights
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(index, flight)| {
+                serde_json::from_value(flight)
+                    .map_err(|err| log::warn!("Skipping the OGN flight {index} : {err}"))
+                    .ok()
+            })
+            .collect();
+        Ok(Self { devices, flights })
+    }
+
+    /// Resolves the registration, competition number and model of the devices
+    /// with the devices database.
+    pub fn resolve_devices(&mut self, database: &DeviceDatabase) {
+        if database.is_empty() {
+            return;
+        }
+        for device in self.devices.iter_mut().flatten() {
+            if let Some(address) = device.address.clone() {
+                database.resolve(
+                    &address,
+                    &mut device.registration,
+                    &mut device.competition,
+                    &mut device.aircraft,
+                );
+            }
         }
+    }
+
+    /// Returns the aircraft of the devices having an immatriculation.
+    pub fn aircraft(&self) -> Vec<Aircraft> {
+        self.devices
+            .iter()
+            .flatten()
+            .filter(|device| device.registration.is_some())
+            .map(LogbookDevice::aircraft)
+            .collect()
+    }
+
+    /// Returns the device of a flight.
+    pub fn device(&self, flight: &LogbookFlight) -> Result<&LogbookDevice, OgnError> {
+        let device = flight.device.ok_or(OgnError::MissingDevice)?;
+        self.devices
+            .get(device)
+            .and_then(|device| device.as_ref())
+            .ok_or(OgnError::UnknownDevice(device))
+    }
+
+    /// Returns the flight at `index` as a flight of the flightlog, numbered
+    /// `index + 1`, its launch method being detected with `launch` and its
+    /// times being in the time zone of the airport.
+    pub fn flight(
+        &self,
+        index: usize,
+        launch: &LaunchSettings,
+        zone: &AirportZone,
+    ) -> Result<Option<Flight>, OgnError> {
+        let logbook_flight = match self.flights.get(index) {
+            Some(Some(flight)) => flight,
+            _ => return Ok(None),
+        };
+        let immatriculation = self
+            .device(logbook_flight)?
+            .registration
+            .clone()
+            .unwrap_or_default();
         // Takeoff
-        let mut start_json = vol_json["start"].clone();
-        let start_str = start_json
-            .take_string()
-            .unwrap_or_else(|| "00h00".to_string())
-            .clone();
-        let takeoff = NaiveTime::parse_from_str(&start_str, "%Hh%M").unwrap();
+        let takeoff = flight_time(
+            logbook_flight.start.as_deref(),
+            logbook_flight.start_tsp,
+            zone,
+        )?;
         // Landing
-        let stop_json = vol_json["stop"].clone();
-        let stop_str = match stop_json {
-            json::JsonValue::Short(short) => short.as_str().to_string(),
-            _ => "00h00".to_string(),
-        };
-        let landing = NaiveTime::parse_from_str(stop_str.as_str(), "%Hh%M").unwrap();
+        let landing = flight_time(
+            logbook_flight.stop.as_deref(),
+            logbook_flight.stop_tsp,
+            zone,
+        )
+        .unwrap_or_else(|err| {
+            log::warn!("Unknown landing of the OGN flight {index} : {err}");
+            NaiveTime::default()
+        });
         // TakeoffCode
-        let mut takeoff_machine = "".to_string();
-        let takeoff_code = if vol_json["tow"] == JsonValue::Null {
-            "T"
-        } else {
-            let vol_remorqueur =
-                vols_json[vol_json["tow"].clone().as_u8().unwrap() as usize].clone();
-            let numero_immat_remorqueur = vol_remorqueur["device"].as_u8().unwrap() as usize;
-            takeoff_machine = appareils_ogn[numero_immat_remorqueur]
-                .immatriculation
-                .clone();
-            "R"
-        }
-        .to_string();
+        let launch = detect_launch(self, index, launch);
 
-        vols.push(Flight {
-            ogn_nb: index as i32,
-            takeoff_code,
-            takeoff_machine,
+        Ok(Some(Flight {
+            ogn_nb: index as i32 + 1,
+            takeoff_code: launch.code,
+            takeoff_machine: launch.machine,
             takeoff_machine_pilot: "".to_string(),
             glider: immatriculation,
             flight_code: "".to_string(),
@@ -136,19 +318,194 @@ pub async fn ogn_flights(
             pilot2: "".to_string(),
             takeoff,
             landing,
-        });
+        }))
+    }
+}
+
+/// Returns a time of a flight in the time zone of the airport. The timestamp
+/// is used if the zone is known, the flightbook giving the local times of the
+/// airfield otherwise.
+fn flight_time(
+    time: Option<&str>,
+    timestamp: Option<i64>,
+    zone: &AirportZone,
+) -> Result<NaiveTime, OgnError> {
+    match (zone.0, timestamp) {
+        (Some(_), Some(timestamp)) => zone
+            .time_of_timestamp(timestamp)
+            .ok_or(OgnError::InvalidTime(timestamp.to_string())),
+        _ => parse_time(time),
+    }
+}
+
+/// Parses an OGN time like `10h42`, `None` being an unknown time (00:00).
+fn parse_time(time: Option<&str>) -> Result<NaiveTime, OgnError> {
+    match time {
+        None => Ok(NaiveTime::default()),
+        Some(time) => NaiveTime::parse_from_str(time, "%Hh%M")
+            .map_err(|_| OgnError::InvalidTime(time.to_string())),
+    }
+}
+
+/// How the logbook of an airport is read.
+#[derive(Debug, Clone, Default)]
+pub struct LogbookSettings {
+    /// The aircraft logged at the airport.
+    pub selection: Selection,
+    /// What the launch methods are detected from.
+    pub launch: LaunchSettings,
+    /// The time zone of the airport.
+    pub zone: AirportZone,
+    /// The devices database resolving the registrations.
+    pub devices: Arc<DeviceDatabase>,
+    /// How the flights entered by hand are matched to the OGN flights.
+    pub matching: MatchPolicy,
+}
+
+impl LogbookSettings {
+    /// Returns the settings of an airport.
+    pub fn for_airport(context: &Context, oaci: &String) -> Self {
+        Self {
+            selection: Selection::for_airport(&context.configuration, oaci),
+            launch: LaunchSettings::for_airport(&context.configuration, oaci),
+            zone: context.configuration.time_zone(oaci),
+            devices: context.devices.clone(),
+            matching: MatchPolicy::from_minutes(context.configuration.match_tolerance_mins),
+        }
     }
-    Ok(vols)
 }
 
-/// Synchronizes the server requesting OGN latest data.
+/// Returns Flights that we requested to OGN and these are sorted, with the
+/// aircraft seen that day.
+pub async fn ogn_flights(
+    date: NaiveDate,
+    oaci: String,
+    source: &dyn OgnSource,
+    settings: &LogbookSettings,
+) -> Result<(Vec<Flight>, Vec<Aircraft>), OgnError> {
+    let bytes = source.logbook(&oaci, date).await?;
+    log::info!("Traitement de la requete.");
+    let mut logbook = Logbook::parse(&bytes)?;
+    logbook.resolve_devices(&settings.devices);
+    Ok((flights_from_logbook(&logbook, settings), logbook.aircraft()))
+}
+
+/// Returns the flights of the aircraft of the selection in a logbook. The
+/// flights that cannot be read are skipped.
+pub fn flights_from_logbook(logbook: &Logbook, settings: &LogbookSettings) -> Vec<Flight> {
+    let mut vols: Vec<Flight> = Vec::new();
+    for (index, logbook_flight) in logbook.flights.iter().enumerate() {
+        match logbook.flight(index, &settings.launch, &settings.zone) {
+            Ok(Some(flight)) => {
+                //Don't take immatriculation into account if not selected
+                let category = logbook_flight
+                    .as_ref()
+                    .and_then(|logbook_flight| logbook.device(logbook_flight).ok())
+                    .and_then(|device| device.aircraft_type);
+                if settings.selection.selects(&flight.glider, category) {
+                    vols.push(flight);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("Skipping the OGN flight {index} : {err}"),
+        }
+    }
+    vols
+}
+
+/// Synchronizes the server requesting OGN latest data, and saves the
+/// flightlog of the day if it changed.
 pub async fn synchronisation_ogn(
     flightlog_arc: Arc<Mutex<FlightLog>>,
     oaci: &String,
     context: &Context,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut flightlog_lock = flightlog_arc.lock().unwrap();
-    let _ = flightlog_lock.update_ogn(&oaci, context);
-    drop(flightlog_lock);
-    return Ok(());
+    // OGN is requested without holding the lock, then the flights are merged.
+    let date = flightlog_arc.lock().unwrap().date;
+    // Only the configured airports are synchronised.
+    context.configuration.airport_configuration(oaci)?;
+    let settings = LogbookSettings::for_airport(context, oaci);
+    let (flights, aircraft) =
+        ogn_flights(date, oaci.clone(), context.ogn.as_ref(), &settings).await?;
+    // Merged and saved under the lock of the day, like the updates, so that
+    // an older version of the flightlog is never written over a newer one.
+    let day = context.days.lock((oaci.clone(), date)).await;
+    let merged = context
+        .provenance
+        .update(date, oaci, |provenance| {
+            let mut flightlog_lock = flightlog_arc.lock().unwrap();
+            if flightlog_lock.date != date {
+                return None;
+            }
+            let before = flightlog_lock.flights.clone();
+            let report = reconcile(
+                &mut flightlog_lock.flights,
+                flights,
+                provenance,
+                &settings.matching,
+            );
+            fill_tow_pilots(&mut flightlog_lock, &settings.launch);
+            if report.has_changes() {
+                log::debug!("Synchronisation of {oaci} with OGN : {report:?}");
+            }
+            (flightlog_lock.flights != before).then(|| flightlog_lock.clone())
+        })
+        .await;
+    let saved = match merged {
+        Some(flightlog) => flightlog.save(oaci, context).await,
+        None => Ok(()),
+    };
+    drop(day);
+    if let Err(err) = context.aircraft.record(date, oaci, aircraft).await {
+        log::error!("Could not save the aircraft of {oaci} on the {date} : {err}");
+    }
+    saved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flights_from_logbook, Logbook, LogbookSettings};
+    use crate::selection::Selection;
+    use chrono::NaiveTime;
+
+    #[test]
+    fn skips_malformed_flights() {
+        let mut devices: Vec<String> = (0..300)
+            .map(|index| format!(r#"{{"registration": "F-C{index:03}", "aircraft_type": 1}}"#))
+            .collect();
+        devices.push(r#"{"registration": 42}"#.to_string());
+        let logbook = format!(
+            r#"{{"devices": [{}], "flights": [
+                {{"device": 299, "start": "10h00", "stop": "11h00", "tow": null}},
+                {{"device": 1000, "start": "10h00"}},
+                {{"device": 300, "start": "10h00"}},
+                {{"device": 1, "start": "yesterday"}},
+                {{"device": "one"}},
+                {{"device": 2, "start": "12h00", "stop": "--h--", "tow": 42}},
+                {{"start": "12h00"}}
+            ]}}"#,
+            devices.join(",")
+        );
+        let logbook = Logbook::parse(logbook.as_bytes()).unwrap();
+        let immatriculations: Vec<String> =
+            (0..300).map(|index| format!("F-C{index:03}")).collect();
+        let settings = LogbookSettings {
+            selection: Selection::of_immatriculations(immatriculations),
+            ..Default::default()
+        };
+        let flights = flights_from_logbook(&logbook, &settings);
+
+        assert_eq!(flights.len(), 2);
+        assert_eq!(flights[0].ogn_nb, 1);
+        assert_eq!(flights[0].glider, "F-C299");
+        assert_eq!(flights[0].takeoff_code, "T");
+        // the unknown landing and tow plane do not prevent to log the flight
+        assert_eq!(flights[1].ogn_nb, 6);
+        assert_eq!(flights[1].landing, NaiveTime::default());
+        assert_eq!(flights[1].takeoff_code, "R");
+        assert_eq!(flights[1].takeoff_machine, "");
+
+        assert!(Logbook::parse(b"<html>Bad gateway</html>").is_err());
+        assert_eq!(Logbook::parse(b"{}").unwrap(), Logbook::default());
+    }
 }
@@ -0,0 +1,282 @@
+//! Where the fields of the flights come from, and their conflicts.
+//! A field of a flight is entered by hand or filled from OGN. When OGN sees a
+//! value that differs from the one entered by hand (the launch point typed
+//! 14:10, OGN says 14:25), the value entered by hand is kept and a conflict is
+//! recorded for the frontend, which resolves it by posting an update of the
+//! field, with the value it keeps. This is kept next to the flightlog of the
+//! day: `YYYY/MM/DD/OACI.provenance.json`.
+
+use crate::archive;
+use crate::single_flight::KeyedLocks;
+use crate::storage::{day_dir, write_atomic, StorageError};
+use chrono::{DateTime, Local, NaiveDate};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Where the value of a field comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// Entered by hand, with an update.
+    Manual,
+    /// Filled from OGN.
+    Ogn,
+}
+
+/// A field entered by hand whose value differs from the one seen by OGN.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Conflict {
+    /// The number of the flight.
+    pub ogn_nb: i32,
+    /// The field, like `takeoff`.
+    pub field: String,
+    /// The value entered by hand, which is kept.
+    pub manual: String,
+    /// The value seen by OGN.
+    pub ogn: String,
+    /// When the conflict was found.
+    pub detected_at: DateTime<Local>,
+    /// When it was resolved, by an update of the field or because the values
+    /// agree again.
+    pub resolved_at: Option<DateTime<Local>>,
+}
+
+/// The sources of the fields of the flights of a day, and their conflicts.
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub struct Provenance {
+    /// The source of each field known, by `ogn_nb`.
+    #[serde(default)]
+    pub sources: BTreeMap<i32, BTreeMap<String, Source>>,
+    /// The conflicts, resolved or not.
+    #[serde(default)]
+    pub conflicts: Vec<Conflict>,
+    /// The flights entered by hand that OGN numbered: the new `ogn_nb` of
+    /// each former one.
+    #[serde(default)]
+    pub renumbered: BTreeMap<i32, i32>,
+}
+
+impl Provenance {
+    /// Returns the source of a field, if known.
+    pub fn source(&self, ogn_nb: i32, field: &str) -> Option<Source> {
+        self.sources.get(&ogn_nb)?.get(field).copied()
+    }
+
+    /// Sets the source of a field.
+    pub fn set_source(&mut self, ogn_nb: i32, field: &str, source: Source) {
+        self.sources
+            .entry(ogn_nb)
+            .or_default()
+            .insert(field.to_string(), source);
+    }
+
+    /// Records that a field was entered by hand: its conflict is resolved.
+    pub fn record_manual(&mut self, ogn_nb: i32, field: &str) {
+        self.set_source(ogn_nb, field, Source::Manual);
+        self.resolve(ogn_nb, field);
+    }
+
+    /// Records that the value entered by hand differs from the one seen by
+    /// OGN. Returns true if it is a new conflict: a conflict resolved for the
+    /// same value of OGN is not raised again.
+    pub fn detect_conflict(&mut self, ogn_nb: i32, field: &str, manual: &str, ogn: &str) -> bool {
+        if let Some(conflict) = self.conflicts.iter_mut().find(|conflict| {
+            conflict.ogn_nb == ogn_nb && conflict.field == field && conflict.resolved_at.is_none()
+        }) {
+            let is_new = conflict.ogn != ogn;
+            conflict.manual = manual.to_string();
+            conflict.ogn = ogn.to_string();
+            return is_new;
+        }
+        if self.conflicts.iter().any(|conflict| {
+            conflict.ogn_nb == ogn_nb && conflict.field == field && conflict.ogn == ogn
+        }) {
+            return false;
+        }
+        self.conflicts.push(Conflict {
+            ogn_nb,
+            field: field.to_string(),
+            manual: manual.to_string(),
+            ogn: ogn.to_string(),
+            detected_at: Local::now(),
+            resolved_at: None,
+        });
+        true
+    }
+
+    /// Resolves the conflict of a field, if any.
+    pub fn resolve(&mut self, ogn_nb: i32, field: &str) {
+        for conflict in self.conflicts.iter_mut().filter(|conflict| {
+            conflict.ogn_nb == ogn_nb && conflict.field == field && conflict.resolved_at.is_none()
+        }) {
+            conflict.resolved_at = Some(Local::now());
+        }
+    }
+
+    /// Moves the fields of a flight entered by hand to the number OGN gave it,
+    /// and remembers its former number.
+    pub fn renumber(&mut self, old: i32, new: i32) {
+        if old == new {
+            return;
+        }
+        self.renumbered.insert(old, new);
+        if let Some(sources) = self.sources.remove(&old) {
+            self.sources.insert(new, sources);
+        }
+        for conflict in self
+            .conflicts
+            .iter_mut()
+            .filter(|conflict| conflict.ogn_nb == old)
+        {
+            conflict.ogn_nb = new;
+        }
+    }
+
+    /// Returns the numbers a flight had before it was renumbered, if any.
+    pub fn former_numbers(&self, ogn_nb: i32) -> Vec<i32> {
+        let mut numbers = vec![ogn_nb];
+        let mut index = 0;
+        while index < numbers.len() {
+            let number = numbers[index];
+            numbers.extend(
+                self.renumbered
+                    .iter()
+                    .filter(|(old, new)| **new == number && !numbers.contains(old))
+                    .map(|(old, _)| *old)
+                    .collect::<Vec<i32>>(),
+            );
+            index += 1;
+        }
+        numbers.remove(0);
+        numbers
+    }
+
+    /// Returns the conflicts that are not resolved.
+    pub fn open_conflicts(&self) -> Vec<Conflict> {
+        self.conflicts
+            .iter()
+            .filter(|conflict| conflict.resolved_at.is_none())
+            .cloned()
+            .collect()
+    }
+}
+
+/// The provenance files of all the days, stored under a root directory.
+pub struct ProvenanceStore {
+    root: PathBuf,
+    /// The changes of a day at an airport are made one at a time.
+    days: KeyedLocks<(String, NaiveDate)>,
+}
+
+impl ProvenanceStore {
+    /// Creates a store keeping its files under `root`.
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            days: KeyedLocks::new(),
+        }
+    }
+
+    /// Returns the path of the provenance file of a day at an airport.
+    pub fn path(&self, date: NaiveDate, oaci: &str) -> PathBuf {
+        day_dir(&self.root, date).join(format!("{}.provenance.json", oaci))
+    }
+
+    /// Returns the provenance of the flights of a day at an airport.
+    pub async fn day(&self, date: NaiveDate, oaci: &str) -> Result<Provenance, StorageError> {
+        let path = self.path(date, oaci);
+        let content = if path.exists() {
+            Some(fs::read(&path).await?)
+        } else {
+            let root = self.root.clone();
+            let name = format!("{}.provenance.json", oaci);
+            tokio::task::spawn_blocking(move || archive::read_archived(&root, date, &name))
+                .await??
+        };
+        match content {
+            Some(content) => Ok(serde_json::from_slice(&content)?),
+            None => Ok(Provenance::default()),
+        }
+    }
+
+    /// Changes the provenance of a day at an airport and saves it if it
+    /// changed. The provenance only helps the field crew, so it never stops
+    /// the flights from being updated: when the file cannot be read, the
+    /// change is made on an empty provenance but not saved, so that the file
+    /// is kept for `fsck` to quarantine, and the errors are logged.
+    pub async fn update<R>(
+        &self,
+        date: NaiveDate,
+        oaci: &str,
+        change: impl FnOnce(&mut Provenance) -> R,
+    ) -> R {
+        let _day = self.days.lock((oaci.to_string(), date)).await;
+        let (mut provenance, readable) = match self.day(date, oaci).await {
+            Ok(provenance) => (provenance, true),
+            Err(err) => {
+                log::error!(
+                    "Could not read the provenance of {oaci} on the {date}, it is not saved until it is repaired : {err}"
+                );
+                (Provenance::default(), false)
+            }
+        };
+        let before = provenance.clone();
+        let result = change(&mut provenance);
+        if readable && provenance != before {
+            if let Err(err) = self.save(date, oaci, &provenance).await {
+                log::error!("Could not save the provenance of {oaci} on the {date} : {err}");
+            }
+        }
+        result
+    }
+
+    async fn save(
+        &self,
+        date: NaiveDate,
+        oaci: &str,
+        provenance: &Provenance,
+    ) -> Result<(), StorageError> {
+        let path = self.path(date, oaci);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        write_atomic(&path, &serde_json::to_vec(provenance)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProvenanceStore, Source};
+    use chrono::NaiveDate;
+
+    #[tokio::test]
+    async fn an_unreadable_provenance_is_not_replaced() {
+        let root = std::env::temp_dir().join(format!("cepo-provenance-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let store = ProvenanceStore::new(root.clone());
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+
+        store
+            .update(date, "LFLE", |provenance| {
+                provenance.record_manual(1, "pilot1")
+            })
+            .await;
+        let provenance = store.day(date, "LFLE").await.unwrap();
+        assert_eq!(provenance.source(1, "pilot1"), Some(Source::Manual));
+
+        // a file that cannot be read is kept for fsck
+        let path = store.path(date, "LFLE");
+        std::fs::write(&path, "{\"sources\": tru").unwrap();
+        let changed = store
+            .update(date, "LFLE", |provenance| {
+                provenance.record_manual(2, "pilot1");
+                provenance.source(2, "pilot1")
+            })
+            .await;
+        assert_eq!(changed, Some(Source::Manual));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"sources\": tru");
+        assert!(store.day(date, "LFLE").await.is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,742 @@
+//! Reconciliation of the flights of a flightlog with newer ones, usually from
+//! OGN.
+//! A new flight is matched to a stored one in this order:
+//! 1. the stored flight with the same `ogn_nb`;
+//! 2. for a flight seen by OGN (positive `ogn_nb`), a flight entered by hand
+//!    (negative `ogn_nb`) for the same glider, and the same tug for an aerotow:
+//!    - the ones whose takeoff time was typed are matched, in the order they
+//!      took off, to the OGN flights in the order they took off, within the
+//!      tolerance of the [`MatchPolicy`]: as many flights as possible, and
+//!      then the closest times;
+//!    - the others are matched in the order they were entered (the one
+//!      closest to zero first) to the OGN flights in the order they took off;
+//! 3. otherwise the flight is added.
+//!
+//! The fields of a matched flight are then merged one by one, knowing where
+//! their value comes from (see [`crate::provenance`]): an empty field and a
+//! field filled from OGN take the value seen by OGN, and so do the times of a
+//! flight entered by hand before it took off. A value entered by hand is kept,
+//! and if OGN saw another one, a conflict is recorded.
+
+use crate::launch::AEROTOW;
+use crate::provenance::{Provenance, Source};
+use brick_ogn::flight::Flight;
+use chrono::{Duration, NaiveTime};
+use std::cmp::Reverse;
+
+/// The fields of a flight that OGN knows.
+pub const OGN_FIELDS: [&str; 4] = ["takeoff", "landing", "takeoff_code", "takeoff_machine"];
+
+/// How the flights entered by hand are matched to the flights seen by OGN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchPolicy {
+    /// The largest difference between a typed takeoff time and the one seen
+    /// by OGN.
+    pub tolerance: Duration,
+}
+
+impl MatchPolicy {
+    /// Creates a policy accepting `minutes` of difference between the takeoff
+    /// times.
+    pub fn from_minutes(minutes: u32) -> Self {
+        Self {
+            tolerance: Duration::minutes(minutes as i64),
+        }
+    }
+}
+
+impl Default for MatchPolicy {
+    fn default() -> Self {
+        Self::from_minutes(15)
+    }
+}
+
+/// A flight seen by OGN that could be matched to several flights entered by
+/// hand.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Ambiguity {
+    /// The OGN number of the flight.
+    pub ogn_nb: i32,
+    /// The glider of the flight.
+    pub glider: String,
+    /// The `ogn_nb` of the flights entered by hand that could match.
+    pub candidates: Vec<i32>,
+    /// The one it was matched to.
+    pub chosen: i32,
+}
+
+/// What a reconciliation did to the flights, by `ogn_nb` (the new one for the
+/// flights entered by hand that were matched).
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize)]
+pub struct ReconcileReport {
+    /// The new flights that were found in the flightlog.
+    pub matched: Vec<i32>,
+    /// The matched flights that changed.
+    pub updated: Vec<i32>,
+    /// The new flights that were added.
+    pub created: Vec<i32>,
+    /// The matches that had several candidates.
+    pub ambiguous: Vec<Ambiguity>,
+    /// The new conflicts between a value entered by hand and OGN, by `ogn_nb`
+    /// and field.
+    pub conflicts: Vec<(i32, String)>,
+}
+
+impl ReconcileReport {
+    /// Returns true if a flight was added or changed.
+    pub fn has_changes(&self) -> bool {
+        !self.updated.is_empty() || !self.created.is_empty()
+    }
+}
+
+/// Reconciles the `stored` flights with the `incoming` ones, following and
+/// updating the `provenance` of their fields.
+pub fn reconcile(
+    stored: &mut Vec<Flight>,
+    incoming: Vec<Flight>,
+    provenance: &mut Provenance,
+    policy: &MatchPolicy,
+) -> ReconcileReport {
+    let mut report = ReconcileReport::default();
+    let mut unknown = Vec::new();
+    for new_flight in incoming {
+        match stored
+            .iter()
+            .position(|flight| flight.ogn_nb == new_flight.ogn_nb)
+        {
+            Some(index) => {
+                report.matched.push(new_flight.ogn_nb);
+                let flight = &mut stored[index];
+                if merge_fields(flight, &new_flight, false, provenance, &mut report) {
+                    report.updated.push(new_flight.ogn_nb);
+                }
+            }
+            None => unknown.push(new_flight),
+        }
+    }
+    let matches = match_entered_flights(stored, &unknown, policy, &mut report);
+    for (new_flight, index) in unknown.into_iter().zip(matches) {
+        match index {
+            Some(index) => {
+                let flight = &mut stored[index];
+                provenance.renumber(flight.ogn_nb, new_flight.ogn_nb);
+                flight.ogn_nb = new_flight.ogn_nb;
+                merge_fields(flight, &new_flight, true, provenance, &mut report);
+                report.matched.push(new_flight.ogn_nb);
+                report.updated.push(new_flight.ogn_nb);
+            }
+            None => {
+                for field in OGN_FIELDS {
+                    if !field_value(&new_flight, field).is_empty() {
+                        provenance.set_source(new_flight.ogn_nb, field, Source::Ogn);
+                    }
+                }
+                report.created.push(new_flight.ogn_nb);
+                stored.push(new_flight);
+            }
+        }
+    }
+    report
+}
+
+/// Returns, for each `unknown` flight, the index of the stored flight entered
+/// by hand it is matched to, if any.
+fn match_entered_flights(
+    stored: &[Flight],
+    unknown: &[Flight],
+    policy: &MatchPolicy,
+    report: &mut ReconcileReport,
+) -> Vec<Option<usize>> {
+    let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    let can_match = |new_flight: &Flight, flight: &Flight| {
+        new_flight.ogn_nb > 0
+            && flight.ogn_nb < 0
+            && same_aircraft(&flight.glider, &new_flight.glider)
+            && same_tug(flight, new_flight)
+            && (flight.takeoff == midnight
+                || (flight.takeoff - new_flight.takeoff).abs() <= policy.tolerance)
+    };
+    let mut matches = vec![None; unknown.len()];
+    let mut taken = vec![false; stored.len()];
+    let mut choose = |new_index: usize, index: usize, taken: &mut Vec<bool>| {
+        let new_flight = &unknown[new_index];
+        let mut candidates: Vec<i32> = stored
+            .iter()
+            .enumerate()
+            .filter(|(i, flight)| !taken[*i] && can_match(new_flight, flight))
+            .map(|(_, flight)| flight.ogn_nb)
+            .collect();
+        if candidates.len() > 1 {
+            log::warn!(
+                "OGN flight {} of {} could be {} flights entered by hand",
+                new_flight.ogn_nb,
+                new_flight.glider,
+                candidates.len()
+            );
+            candidates.sort_by_key(|ogn_nb| Reverse(*ogn_nb));
+            report.ambiguous.push(Ambiguity {
+                ogn_nb: new_flight.ogn_nb,
+                glider: new_flight.glider.clone(),
+                candidates,
+                chosen: stored[index].ogn_nb,
+            });
+        }
+        taken[index] = true;
+    };
+
+    // The flights whose takeoff time was typed, glider by glider, in the order
+    // they took off.
+    let mut gliders: Vec<String> = unknown
+        .iter()
+        .map(|new_flight| immatriculation(&new_flight.glider))
+        .collect();
+    gliders.sort();
+    gliders.dedup();
+    for glider in gliders {
+        let mut new_indexes: Vec<usize> = (0..unknown.len())
+            .filter(|new_index| immatriculation(&unknown[*new_index].glider) == glider)
+            .collect();
+        new_indexes.sort_by_key(|new_index| unknown[*new_index].takeoff);
+        let mut indexes: Vec<usize> = (0..stored.len())
+            .filter(|index| {
+                stored[*index].takeoff != midnight
+                    && immatriculation(&stored[*index].glider) == glider
+            })
+            .collect();
+        indexes.sort_by_key(|index| stored[*index].takeoff);
+        let pairs = match_in_order(&new_indexes, &indexes, |new_index, index| {
+            let (new_flight, flight) = (&unknown[new_index], &stored[index]);
+            can_match(new_flight, flight).then(|| (flight.takeoff - new_flight.takeoff).abs())
+        });
+        for (new_index, index) in pairs {
+            choose(new_index, index, &mut taken);
+            matches[new_index] = Some(index);
+        }
+    }
+
+    // The others, in the order they were entered, to the flights in the order
+    // they took off.
+    let mut order: Vec<usize> = (0..unknown.len()).collect();
+    order.sort_by_key(|new_index| unknown[*new_index].takeoff);
+    for new_index in order {
+        if matches[new_index].is_some() {
+            continue;
+        }
+        let index = stored
+            .iter()
+            .enumerate()
+            .filter(|(index, flight)| {
+                !taken[*index]
+                    && flight.takeoff == midnight
+                    && can_match(&unknown[new_index], flight)
+            })
+            .max_by_key(|(_, flight)| flight.ogn_nb)
+            .map(|(index, _)| index);
+        if let Some(index) = index {
+            choose(new_index, index, &mut taken);
+            matches[new_index] = Some(index);
+        }
+    }
+    matches
+}
+
+/// Matches `new_indexes` to `indexes`, both in the order the flights took
+/// off, without crossing: a later flight is never matched to an earlier one.
+/// Returns the pairs of the matching with the most pairs and, among them, the
+/// smallest total gap. `gap` is `None` for two flights that cannot match.
+fn match_in_order(
+    new_indexes: &[usize],
+    indexes: &[usize],
+    gap: impl Fn(usize, usize) -> Option<Duration>,
+) -> Vec<(usize, usize)> {
+    // best[i][j] is the best matching of the first i new flights with the
+    // first j stored ones, as its number of pairs and its total gap.
+    let columns = indexes.len() + 1;
+    let mut best = vec![(0, Reverse(Duration::zero())); (new_indexes.len() + 1) * columns];
+    for i in 1..=new_indexes.len() {
+        for j in 1..=indexes.len() {
+            let mut score = best[(i - 1) * columns + j].max(best[i * columns + j - 1]);
+            if let Some(gap) = gap(new_indexes[i - 1], indexes[j - 1]) {
+                let (pairs, Reverse(total)) = best[(i - 1) * columns + j - 1];
+                score = score.max((pairs + 1, Reverse(total + gap)));
+            }
+            best[i * columns + j] = score;
+        }
+    }
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (new_indexes.len(), indexes.len());
+    while i > 0 && j > 0 {
+        if best[i * columns + j] == best[(i - 1) * columns + j] {
+            i -= 1;
+        } else if best[i * columns + j] == best[i * columns + j - 1] {
+            j -= 1;
+        } else {
+            pairs.push((new_indexes[i - 1], indexes[j - 1]));
+            i -= 1;
+            j -= 1;
+        }
+    }
+    pairs.reverse();
+    pairs
+}
+
+/// Returns an immatriculation without its case, dashes and spaces.
+fn immatriculation(immatriculation: &str) -> String {
+    immatriculation
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+/// Returns true if two immatriculations are the same, whatever their case,
+/// dashes and spaces.
+fn same_aircraft(first: &str, second: &str) -> bool {
+    immatriculation(first) == immatriculation(second)
+}
+
+/// Returns false if both flights are aerotows behind different tugs.
+fn same_tug(flight: &Flight, new_flight: &Flight) -> bool {
+    flight.takeoff_code != AEROTOW
+        || new_flight.takeoff_code != AEROTOW
+        || flight.takeoff_machine.is_empty()
+        || new_flight.takeoff_machine.is_empty()
+        || same_aircraft(&flight.takeoff_machine, &new_flight.takeoff_machine)
+}
+
+/// Merges the fields seen by OGN in a matched flight. The times of a flight
+/// entered by hand that was just `attached` to OGN are replaced, unless they
+/// were typed. Returns true if the flight changed.
+fn merge_fields(
+    flight: &mut Flight,
+    new_flight: &Flight,
+    attached: bool,
+    provenance: &mut Provenance,
+    report: &mut ReconcileReport,
+) -> bool {
+    let mut changed = false;
+    for field in OGN_FIELDS {
+        let current = field_value(flight, field);
+        let seen = field_value(new_flight, field);
+        if seen.is_empty() {
+            continue;
+        }
+        if current == seen {
+            provenance.resolve(flight.ogn_nb, field);
+            continue;
+        }
+        let source = provenance.source(flight.ogn_nb, field);
+        let is_time = field == "takeoff" || field == "landing";
+        if current.is_empty()
+            || source == Some(Source::Ogn)
+            || (attached && is_time && source.is_none())
+        {
+            copy_field(flight, new_flight, field);
+            provenance.set_source(flight.ogn_nb, field, Source::Ogn);
+            changed = true;
+        } else if provenance.detect_conflict(flight.ogn_nb, field, &current, &seen) {
+            report.conflicts.push((flight.ogn_nb, field.to_string()));
+        }
+    }
+    changed
+}
+
+/// Returns the value of a field of a flight as shown to the field crew, empty
+/// when not known (a time of 00:00).
+pub fn field_value(flight: &Flight, field: &str) -> String {
+    let time = |time: NaiveTime| {
+        if time == NaiveTime::from_hms_opt(0, 0, 0).unwrap() {
+            String::new()
+        } else {
+            time.format("%H:%M").to_string()
+        }
+    };
+    match field {
+        "takeoff" => time(flight.takeoff),
+        "landing" => time(flight.landing),
+        "takeoff_code" => flight.takeoff_code.clone(),
+        "takeoff_machine" => flight.takeoff_machine.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Copies a field of `new_flight` to `flight`.
+fn copy_field(flight: &mut Flight, new_flight: &Flight, field: &str) {
+    match field {
+        "takeoff" => flight.takeoff = new_flight.takeoff,
+        "landing" => flight.landing = new_flight.landing,
+        "takeoff_code" => flight.takeoff_code = new_flight.takeoff_code.clone(),
+        "takeoff_machine" => flight.takeoff_machine = new_flight.takeoff_machine.clone(),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_in_order, reconcile, Ambiguity, MatchPolicy, ReconcileReport};
+    use crate::provenance::{Provenance, Source};
+    use brick_ogn::flight::Flight;
+    use chrono::{Duration, NaiveTime};
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn flight(ogn_nb: i32, glider: &str, takeoff: &str, landing: &str, code: &str) -> Flight {
+        Flight {
+            ogn_nb,
+            glider: glider.to_string(),
+            takeoff: time(takeoff),
+            landing: time(landing),
+            takeoff_code: code.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn tow(flight: Flight, tug: &str) -> Flight {
+        Flight {
+            takeoff_machine: tug.to_string(),
+            ..flight
+        }
+    }
+
+    #[test]
+    fn reconcile_flights() {
+        struct Case {
+            name: &'static str,
+            sources: Vec<(i32, &'static str, Source)>,
+            stored: Vec<Flight>,
+            incoming: Vec<Flight>,
+            expected: Vec<Flight>,
+            report: ReconcileReport,
+        }
+        let cases = [
+            Case {
+                name: "new flights are added",
+                sources: vec![],
+                stored: vec![],
+                incoming: vec![flight(1, "F-CEJU", "10:02", "11:30", "R")],
+                expected: vec![flight(1, "F-CEJU", "10:02", "11:30", "R")],
+                report: ReconcileReport {
+                    created: vec![1],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "known flights only get their missing fields",
+                sources: vec![],
+                stored: vec![
+                    flight(1, "F-CEJU", "10:01", "00:00", "T"),
+                    flight(2, "F-CECY", "10:40", "11:00", ""),
+                ],
+                incoming: vec![
+                    flight(1, "F-CEJU", "10:02", "11:30", "R"),
+                    flight(2, "F-CECY", "10:40", "11:00", ""),
+                ],
+                expected: vec![
+                    flight(1, "F-CEJU", "10:01", "11:30", "T"),
+                    flight(2, "F-CECY", "10:40", "11:00", ""),
+                ],
+                report: ReconcileReport {
+                    matched: vec![1, 2],
+                    updated: vec![1],
+                    conflicts: vec![(1, "takeoff".into()), (1, "takeoff_code".into())],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "flights entered by hand get the OGN number and times",
+                sources: vec![],
+                stored: vec![flight(-1, "F-CEJU", "10:00", "00:00", "T")],
+                incoming: vec![flight(4, "F-CEJU", "10:02", "11:30", "R")],
+                expected: vec![flight(4, "F-CEJU", "10:02", "11:30", "T")],
+                report: ReconcileReport {
+                    matched: vec![4],
+                    updated: vec![4],
+                    conflicts: vec![(4, "takeoff_code".into())],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "typed takeoff times are matched to the closest OGN flight",
+                sources: vec![],
+                stored: vec![
+                    flight(-2, "F-CEJU", "14:00", "00:00", ""),
+                    flight(-1, "F-CEJU", "10:00", "00:00", ""),
+                    flight(-3, "F-CECY", "10:00", "00:00", ""),
+                ],
+                incoming: vec![
+                    flight(1, "F-CEJU", "10:02", "11:30", "R"),
+                    flight(2, "F-CEJU", "14:05", "15:00", "R"),
+                ],
+                expected: vec![
+                    flight(2, "F-CEJU", "14:05", "15:00", "R"),
+                    flight(1, "F-CEJU", "10:02", "11:30", "R"),
+                    flight(-3, "F-CECY", "10:00", "00:00", ""),
+                ],
+                report: ReconcileReport {
+                    matched: vec![1, 2],
+                    updated: vec![1, 2],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "typed takeoff times are matched in the order of the flights",
+                sources: vec![],
+                stored: vec![
+                    flight(-1, "F-CEJU", "10:00", "00:00", ""),
+                    flight(-2, "F-CEJU", "10:10", "00:00", ""),
+                ],
+                incoming: vec![
+                    flight(1, "F-CEJU", "10:08", "11:00", "R"),
+                    flight(2, "F-CEJU", "10:12", "11:30", "R"),
+                ],
+                expected: vec![
+                    flight(1, "F-CEJU", "10:08", "11:00", "R"),
+                    flight(2, "F-CEJU", "10:12", "11:30", "R"),
+                ],
+                report: ReconcileReport {
+                    matched: vec![1, 2],
+                    updated: vec![1, 2],
+                    ambiguous: vec![Ambiguity {
+                        ogn_nb: 1,
+                        glider: String::from("F-CEJU"),
+                        candidates: vec![-1, -2],
+                        chosen: -1,
+                    }],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "a typed takeoff time too far from OGN is another flight",
+                sources: vec![],
+                stored: vec![flight(-1, "F-CEJU", "10:00", "00:00", "")],
+                incoming: vec![flight(1, "F-CEJU", "11:00", "12:00", "R")],
+                expected: vec![
+                    flight(-1, "F-CEJU", "10:00", "00:00", ""),
+                    flight(1, "F-CEJU", "11:00", "12:00", "R"),
+                ],
+                report: ReconcileReport {
+                    created: vec![1],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "flights without time are matched in the order they were entered",
+                sources: vec![],
+                stored: vec![
+                    flight(-2, "F-CEJU", "00:00", "00:00", ""),
+                    flight(-1, "f-ceju", "00:00", "00:00", ""),
+                ],
+                incoming: vec![
+                    flight(2, "F-CEJU", "14:05", "15:00", "R"),
+                    flight(1, "F-CEJU", "10:02", "11:30", "R"),
+                ],
+                expected: vec![
+                    flight(2, "F-CEJU", "14:05", "15:00", "R"),
+                    flight(1, "f-ceju", "10:02", "11:30", "R"),
+                ],
+                report: ReconcileReport {
+                    matched: vec![2, 1],
+                    updated: vec![2, 1],
+                    ambiguous: vec![Ambiguity {
+                        ogn_nb: 1,
+                        glider: String::from("F-CEJU"),
+                        candidates: vec![-1, -2],
+                        chosen: -1,
+                    }],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "an aerotow is matched to the flight behind the same tug",
+                sources: vec![],
+                stored: vec![
+                    tow(flight(-1, "F-CEJU", "10:03", "00:00", "R"), "F-GDRT"),
+                    tow(flight(-2, "F-CEJU", "10:06", "00:00", "R"), "F-JXYZ"),
+                ],
+                incoming: vec![tow(flight(1, "F-CEJU", "10:04", "11:30", "R"), "F-JXYZ")],
+                expected: vec![
+                    tow(flight(-1, "F-CEJU", "10:03", "00:00", "R"), "F-GDRT"),
+                    tow(flight(1, "F-CEJU", "10:04", "11:30", "R"), "F-JXYZ"),
+                ],
+                report: ReconcileReport {
+                    matched: vec![1],
+                    updated: vec![1],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "flights entered by hand are only matched to OGN flights",
+                sources: vec![],
+                stored: vec![flight(-1, "F-CEJU", "10:00", "00:00", "T")],
+                incoming: vec![flight(-2, "F-CEJU", "11:00", "00:00", "T")],
+                expected: vec![
+                    flight(-1, "F-CEJU", "10:00", "00:00", "T"),
+                    flight(-2, "F-CEJU", "11:00", "00:00", "T"),
+                ],
+                report: ReconcileReport {
+                    created: vec![-2],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "values entered by hand are kept and raise a conflict",
+                sources: vec![(1, "takeoff", Source::Manual)],
+                stored: vec![flight(1, "F-CEJU", "14:10", "00:00", "")],
+                incoming: vec![flight(1, "F-CEJU", "14:25", "15:00", "R")],
+                expected: vec![flight(1, "F-CEJU", "14:10", "15:00", "R")],
+                report: ReconcileReport {
+                    matched: vec![1],
+                    updated: vec![1],
+                    conflicts: vec![(1, "takeoff".into())],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "values filled from OGN follow OGN",
+                sources: vec![(1, "landing", Source::Ogn)],
+                stored: vec![flight(1, "F-CEJU", "10:02", "11:30", "R")],
+                incoming: vec![flight(1, "F-CEJU", "10:02", "11:45", "R")],
+                expected: vec![flight(1, "F-CEJU", "10:02", "11:45", "R")],
+                report: ReconcileReport {
+                    matched: vec![1],
+                    updated: vec![1],
+                    ..Default::default()
+                },
+            },
+            Case {
+                name: "typed times of flights entered by hand are kept",
+                sources: vec![(-1, "takeoff", Source::Manual)],
+                stored: vec![flight(-1, "F-CEJU", "14:10", "00:00", "")],
+                incoming: vec![flight(3, "F-CEJU", "14:25", "15:00", "R")],
+                expected: vec![flight(3, "F-CEJU", "14:10", "15:00", "R")],
+                report: ReconcileReport {
+                    matched: vec![3],
+                    updated: vec![3],
+                    conflicts: vec![(3, "takeoff".into())],
+                    ..Default::default()
+                },
+            },
+        ];
+        for case in cases {
+            let mut stored = case.stored;
+            let mut provenance = Provenance::default();
+            for (ogn_nb, field, source) in case.sources {
+                provenance.set_source(ogn_nb, field, source);
+            }
+            let report = reconcile(
+                &mut stored,
+                case.incoming,
+                &mut provenance,
+                &MatchPolicy::default(),
+            );
+            assert_eq!(stored, case.expected, "{}", case.name);
+            assert_eq!(report, case.report, "{}", case.name);
+        }
+    }
+
+    /// Returns the `ogn_nb` the stored flights have once reconciled with the
+    /// incoming ones under a tolerance of `minutes`.
+    fn matched(stored: Vec<Flight>, incoming: Vec<Flight>, minutes: u32) -> Vec<i32> {
+        let mut stored = stored;
+        reconcile(
+            &mut stored,
+            incoming,
+            &mut Provenance::default(),
+            &MatchPolicy::from_minutes(minutes),
+        );
+        stored.iter().map(|flight| flight.ogn_nb).collect()
+    }
+
+    #[test]
+    fn typed_times_are_matched_within_the_configured_tolerance() {
+        let typed = || vec![flight(-1, "F-CEJU", "10:00", "00:00", "")];
+        // just inside the tolerance, both ways
+        assert_eq!(
+            matched(
+                typed(),
+                vec![flight(1, "F-CEJU", "10:10", "11:00", "R")],
+                10
+            ),
+            vec![1]
+        );
+        assert_eq!(
+            matched(
+                typed(),
+                vec![flight(1, "F-CEJU", "09:50", "11:00", "R")],
+                10
+            ),
+            vec![1]
+        );
+        // just outside, another flight
+        assert_eq!(
+            matched(
+                typed(),
+                vec![flight(1, "F-CEJU", "10:11", "11:00", "R")],
+                10
+            ),
+            vec![-1, 1]
+        );
+        // the same gap with a larger tolerance
+        assert_eq!(
+            matched(
+                typed(),
+                vec![flight(1, "F-CEJU", "10:11", "11:00", "R")],
+                15
+            ),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn aerotows_behind_another_tug_are_not_matched() {
+        let typed = vec![tow(flight(-1, "F-CEJU", "10:03", "00:00", "R"), "F-GDRT")];
+        let seen = vec![tow(flight(1, "F-CEJU", "10:03", "11:00", "R"), "F-JXYZ")];
+        assert_eq!(matched(typed, seen, 15), vec![-1, 1]);
+        // the same tug, whatever its case and dashes
+        let typed = vec![tow(flight(-1, "F-CEJU", "10:03", "00:00", "R"), "F-GDRT")];
+        let seen = vec![tow(flight(1, "F-CEJU", "10:03", "11:00", "R"), "fgdrt")];
+        assert_eq!(matched(typed, seen, 15), vec![1]);
+    }
+
+    #[test]
+    fn matches_in_order_never_cross() {
+        // Taking the closest pair first would match the first OGN flight
+        // (10:08) to the second typed one (10:10) and the second OGN flight
+        // (10:12) to the first typed one (10:00): the flights would cross.
+        let seen = [time("10:08"), time("10:12")];
+        let typed = [time("10:00"), time("10:10")];
+        let gap = |new_index: usize, index: usize| {
+            let gap = (seen[new_index] - typed[index]).abs();
+            (gap <= Duration::minutes(15)).then_some(gap)
+        };
+        assert_eq!(match_in_order(&[0, 1], &[0, 1], gap), vec![(0, 0), (1, 1)]);
+        assert_eq!(
+            matched(
+                vec![
+                    flight(-1, "F-CEJU", "10:00", "00:00", ""),
+                    flight(-2, "F-CEJU", "10:10", "00:00", ""),
+                ],
+                vec![
+                    flight(1, "F-CEJU", "10:08", "10:11", "R"),
+                    flight(2, "F-CEJU", "10:12", "11:00", "R"),
+                ],
+                15,
+            ),
+            vec![1, 2]
+        );
+
+        // More pairs win over a smaller gap: the second OGN flight (10:30)
+        // is only close enough to the second typed one (10:20).
+        let seen = [time("10:15"), time("10:30")];
+        let typed = [time("10:00"), time("10:20")];
+        let gap = |new_index: usize, index: usize| {
+            let gap = (seen[new_index] - typed[index]).abs();
+            (gap <= Duration::minutes(15)).then_some(gap)
+        };
+        assert_eq!(match_in_order(&[0, 1], &[0, 1], gap), vec![(0, 0), (1, 1)]);
+        // a pair that cannot match is never made
+        assert!(match_in_order(&[0], &[0], |_, _| None).is_empty());
+    }
+}
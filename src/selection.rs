@@ -0,0 +1,203 @@
+//! Which aircraft are logged at an airport.
+//! Besides the listed immatriculations, an airport can log all the aircraft of
+//! some OGN categories, the immatriculations matching patterns like `F-C*` or
+//! `D-??34`, or everything seen on the field, except the excluded ones.
+
+use crate::configuration::Configuration;
+
+/// The rules selecting the aircraft logged at an airport, on top of its
+/// immatriculations.
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct SelectionRules {
+    /// Logs every aircraft seen at the airport.
+    pub log_all: bool,
+    /// The OGN categories of the aircraft to log (1 for the gliders).
+    pub categories: Vec<u8>,
+    /// The patterns of the immatriculations to log, where `*` stands for any
+    /// characters and `?` for one, like `F-C*`.
+    pub patterns: Vec<String>,
+    /// The immatriculations or patterns never logged.
+    pub exclusions: Vec<String>,
+}
+
+/// The aircraft logged at an airport.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Selection {
+    /// The immatriculations always logged.
+    pub immatriculations: Vec<String>,
+    /// The other rules.
+    pub rules: SelectionRules,
+}
+
+impl Selection {
+    /// Returns the selection of an airport, with the permanent immatriculations
+    /// of the configuration.
+    pub fn for_airport(configuration: &Configuration, oaci: &String) -> Self {
+        let mut immatriculations = configuration.permanent_immatriculations();
+        let mut rules = SelectionRules::default();
+        if let Ok(airport) = configuration.airport_configuration(oaci) {
+            immatriculations.append(&mut airport.immatriculations());
+            rules = airport.selection();
+        }
+        Self {
+            immatriculations,
+            rules,
+        }
+    }
+
+    /// Returns a selection of only some immatriculations.
+    pub fn of_immatriculations(immatriculations: Vec<String>) -> Self {
+        Self {
+            immatriculations,
+            rules: SelectionRules::default(),
+        }
+    }
+
+    /// Returns true if the aircraft is logged. The category is unknown for the
+    /// aircraft that did not send it.
+    pub fn selects(&self, immatriculation: &str, category: Option<u8>) -> bool {
+        if immatriculation.is_empty()
+            || self
+                .rules
+                .exclusions
+                .iter()
+                .any(|exclusion| matches_pattern(exclusion, immatriculation))
+        {
+            return false;
+        }
+        self.rules.log_all
+            || self
+                .immatriculations
+                .iter()
+                .any(|listed| listed == immatriculation)
+            || category.is_some_and(|category| self.rules.categories.contains(&category))
+            || self
+                .rules
+                .patterns
+                .iter()
+                .any(|pattern| matches_pattern(pattern, immatriculation))
+    }
+
+    /// Returns the immatriculation of an aircraft using it as callsign, with
+    /// or without its dashes, if the selection may log it: the listed
+    /// immatriculation, or the callsign itself when it matches a pattern.
+    pub fn immatriculation_of_callsign(&self, callsign: &str) -> Option<String> {
+        let listed = self
+            .immatriculations
+            .iter()
+            .find(|listed| bare(listed) == bare(callsign));
+        if let Some(listed) = listed {
+            return Some(listed.clone());
+        }
+        self.rules
+            .patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, callsign))
+            .then(|| callsign.to_string())
+    }
+}
+
+/// Returns an immatriculation in upper case and without its dashes.
+fn bare(immatriculation: &str) -> String {
+    immatriculation.replace('-', "").to_uppercase()
+}
+
+/// Returns true if the immatriculation matches the pattern, ignoring the case
+/// and the dashes.
+pub fn matches_pattern(pattern: &str, immatriculation: &str) -> bool {
+    let pattern: Vec<char> = bare(pattern).chars().collect();
+    let immatriculation: Vec<char> = bare(immatriculation).chars().collect();
+    // matched[j]: the pattern read so far matches the first j characters.
+    let mut matched = vec![false; immatriculation.len() + 1];
+    matched[0] = true;
+    for wildcard in pattern {
+        let mut next = vec![false; immatriculation.len() + 1];
+        for j in 0..=immatriculation.len() {
+            next[j] = match wildcard {
+                '*' => matched[j] || (j > 0 && next[j - 1]),
+                '?' => j > 0 && matched[j - 1],
+                c => j > 0 && matched[j - 1] && immatriculation[j - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[immatriculation.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches_pattern, Selection, SelectionRules};
+
+    #[test]
+    fn select_by_list_category_and_pattern() {
+        assert!(matches_pattern("F-C*", "f-ceju"));
+        assert!(matches_pattern("D-??34", "D-1234"));
+        assert!(matches_pattern("*", ""));
+        assert!(!matches_pattern("F-C*", "F-GDRT"));
+        assert!(!matches_pattern("F-CEJ", "F-CEJU"));
+        assert!(matches_pattern("F-C*", "FCEJU"));
+
+        let selection = Selection {
+            immatriculations: vec![String::from("F-GDRT")],
+            rules: SelectionRules {
+                categories: vec![1],
+                patterns: vec![String::from("F-C*")],
+                exclusions: vec![String::from("F-CECY"), String::from("D-1*")],
+                ..Default::default()
+            },
+        };
+        let cases = [
+            ("F-GDRT", Some(2), true),
+            ("D-4321", Some(1), true),
+            ("F-CEJU", None, true),
+            ("F-CECY", Some(1), false),
+            ("D-1234", Some(1), false),
+            ("D-4321", None, false),
+            ("HB-1234", Some(8), false),
+            ("", Some(1), false),
+        ];
+        for (immatriculation, category, expected) in cases {
+            assert_eq!(
+                selection.selects(immatriculation, category),
+                expected,
+                "{immatriculation}"
+            );
+        }
+
+        let all = Selection {
+            rules: SelectionRules {
+                log_all: true,
+                exclusions: vec![String::from("F-CECY")],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(all.selects("HB-1234", None));
+        assert!(!all.selects("F-CECY", Some(1)));
+    }
+
+    #[test]
+    fn immatriculations_used_as_callsigns() {
+        let selection = Selection {
+            immatriculations: vec![String::from("F-GDRT")],
+            rules: SelectionRules {
+                patterns: vec![String::from("F-C*")],
+                categories: vec![1],
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            selection.immatriculation_of_callsign("FGDRT").as_deref(),
+            Some("F-GDRT")
+        );
+        assert_eq!(
+            selection.immatriculation_of_callsign("FCEJU").as_deref(),
+            Some("FCEJU")
+        );
+        assert!(selection.selects("FCEJU", None));
+        // a device address is not an immatriculation
+        assert_eq!(selection.immatriculation_of_callsign("FLRDD8E3A"), None);
+        assert_eq!(selection.immatriculation_of_callsign("HB1234"), None);
+    }
+}
@@ -0,0 +1,233 @@
+//! Coalescing and serialisation of concurrent work on the same key.
+//! When several tablets open the same past day at once, only the first one
+//! requests OGN, merges and saves the flightlog; the others wait for it and
+//! get the same result. If the first one is cancelled (its client went away),
+//! one of the waiting callers takes over. The work that cannot be shared, like
+//! two updates of the same day, is done one at a time with [`KeyedLocks`].
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, OwnedMutexGuard};
+
+/// The work in flight, by key. Each caller of a key waits for the result of
+/// the first one instead of doing the same work again.
+pub struct SingleFlight<K, V> {
+    in_flight: Mutex<HashMap<K, watch::Receiver<Option<V>>>>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Removes the key of the work in flight when it is done or cancelled.
+struct Leader<'a, K: Eq + Hash, V> {
+    key: Option<K>,
+    in_flight: &'a Mutex<HashMap<K, watch::Receiver<Option<V>>>>,
+}
+
+impl<K: Eq + Hash, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    /// Creates an empty set of work in flight.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of keys being worked on.
+    pub fn len(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+
+    /// Returns true if nothing is being worked on.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `work` for `key`, unless it is already running for this key: then
+    /// waits for it and returns its result.
+    pub async fn run<F, Fut>(&self, key: K, work: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        loop {
+            let (sender, mut receiver) = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(receiver) => (None, receiver.clone()),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        in_flight.insert(key.clone(), receiver.clone());
+                        (Some(sender), receiver)
+                    }
+                }
+            };
+            if let Some(sender) = sender {
+                let _leader = Leader {
+                    key: Some(key),
+                    in_flight: &self.in_flight,
+                };
+                let value = work().await;
+                sender.send_replace(Some(value.clone()));
+                return value;
+            }
+            loop {
+                if let Some(value) = receiver.borrow().clone() {
+                    return value;
+                }
+                if receiver.changed().await.is_err() {
+                    // The first caller was cancelled before it finished.
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// A lock per key: the work on a key is done one at a time, and the work on
+/// different keys at the same time.
+pub struct KeyedLocks<K> {
+    locks: Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl<K> Default for KeyedLocks<K> {
+    fn default() -> Self {
+        Self {
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Holds the lock of a key, and forgets it when nobody else waits for it.
+pub struct KeyGuard<'a, K: Eq + Hash> {
+    key: Option<K>,
+    guard: Option<OwnedMutexGuard<()>>,
+    locks: &'a Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl<K: Eq + Hash> Drop for KeyGuard<'_, K> {
+    fn drop(&mut self) {
+        let mut locks = self.locks.lock().unwrap();
+        self.guard.take();
+        if let Some(key) = self.key.take() {
+            if locks
+                .get(&key)
+                .is_some_and(|lock| Arc::strong_count(lock) == 1)
+            {
+                locks.remove(&key);
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedLocks<K> {
+    /// Creates a set of locks where no key is locked.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of keys locked or waited for.
+    pub fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+
+    /// Returns true if no key is locked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until nobody holds the lock of `key`, and takes it.
+    pub async fn lock(&self, key: K) -> KeyGuard<'_, K> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let mut key_guard = KeyGuard {
+            key: Some(key),
+            guard: None,
+            locks: &self.locks,
+        };
+        key_guard.guard = Some(lock.lock_owned().await);
+        key_guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyedLocks, SingleFlight};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_the_work() {
+        let flights = Arc::new(SingleFlight::<&str, usize>::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let call = |key| {
+            let flights = flights.clone();
+            let runs = runs.clone();
+            tokio::spawn(async move {
+                flights
+                    .run(key, || async {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        runs.fetch_add(1, Ordering::SeqCst) + 1
+                    })
+                    .await
+            })
+        };
+
+        // the first caller is cancelled, the next one takes over
+        let cancelled = call("LFLE");
+        tokio::task::yield_now().await;
+        let waiting = [call("LFLE"), call("LFLE")];
+        let other = call("LFLB");
+        tokio::task::yield_now().await;
+        cancelled.abort();
+        let [first, second] = waiting;
+        assert_eq!(first.await.unwrap(), second.await.unwrap());
+        other.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(flights.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn work_on_a_key_is_done_one_at_a_time() {
+        let locks = Arc::new(KeyedLocks::<&str>::new());
+        let running = Arc::new(AtomicUsize::new(0));
+        let work = |key| {
+            let locks = locks.clone();
+            let running = running.clone();
+            tokio::spawn(async move {
+                let _guard = locks.lock(key).await;
+                let others = running.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                others
+            })
+        };
+        let same = [work("LFLE"), work("LFLE"), work("LFLE")];
+        let other = work("LFLB");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(running.load(Ordering::SeqCst), 2);
+        for work in same {
+            assert!(work.await.unwrap() <= 1);
+        }
+        assert_eq!(other.await.unwrap(), 1);
+        assert!(locks.is_empty());
+    }
+}
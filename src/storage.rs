@@ -0,0 +1,738 @@
+//! Storage backends: where and how the flightlogs are kept on the computer.
+//! The [`Backend`] held by the [`crate::Context`] is chosen in the
+//! [`crate::configuration::Configuration`]: either one JSON file per airport
+//! per day ([`JsonFiles`]) or an embedded SQLite database ([`Sqlite`]) that
+//! can be queried by pilot, glider or date range.
+
+use crate::archive;
+use crate::configuration::StorageConfiguration;
+use crate::nb_2digits_string;
+use async_trait::async_trait;
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::FlightLog;
+use chrono::{Datelike, NaiveDate};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Errors returned by the storage backends.
+pub type StorageError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The error returned when no flightlog is stored for a day, to tell it apart
+/// from a flightlog that exists but cannot be read.
+#[derive(Debug)]
+pub struct NotFound;
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No FlightLog found for the date.")
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// The writes in progress, by path, so that two writers of the same file
+/// never interleave.
+static WRITES: OnceLock<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+
+/// The number of the next temporary file of this process.
+static NEXT_TEMPORARY: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the lock of the files of the data dir: every write takes it
+/// shared, and the archiving takes it exclusively while it replaces the files
+/// it packed by their archive (see [`crate::archive::archive_period`]).
+pub fn data_dir_lock() -> &'static tokio::sync::RwLock<()> {
+    static LOCK: OnceLock<tokio::sync::RwLock<()>> = OnceLock::new();
+    LOCK.get_or_init(Default::default)
+}
+
+/// The file holding the process id of the server running on a data dir.
+pub const SERVER_PID_FILE: &str = "server.pid";
+
+/// Marks a data dir as used by the running server, until it is dropped.
+pub struct ServerMark {
+    path: PathBuf,
+}
+
+impl ServerMark {
+    /// Writes the process id of the server in the data dir.
+    pub fn new(data_dir: &Path) -> Result<Self, StorageError> {
+        let path = data_dir.join(SERVER_PID_FILE);
+        std::fs::write(&path, std::process::id().to_string())?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ServerMark {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the process id of the server running on the data dir, if any. The
+/// file left by a server that was killed names a process that is gone.
+pub fn running_server(data_dir: &Path) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(data_dir.join(SERVER_PID_FILE))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let proc = Path::new("/proc");
+    let alive = !proc.exists() || proc.join(pid.to_string()).exists();
+    (alive && pid != std::process::id()).then_some(pid)
+}
+
+/// Releases the lock of a path, and forgets it when nobody else waits for it.
+struct PathLock<'a> {
+    path: &'a Path,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for PathLock<'_> {
+    fn drop(&mut self) {
+        let mut writes = WRITES.get_or_init(Default::default).lock().unwrap();
+        // the map and this lock
+        if Arc::strong_count(&self.lock) == 2 {
+            writes.remove(self.path);
+        }
+    }
+}
+
+/// Writes `content` to `path` without ever leaving a truncated file: the
+/// content is written and synced to a temporary file next to `path`, which is
+/// then renamed over it, and the directory is synced. The temporary file has a
+/// name of its own (see [`temporary_file_target`]) and the writes to the same
+/// path are done one after the other.
+pub async fn write_atomic(path: &Path, content: &[u8]) -> Result<(), StorageError> {
+    let path_lock = PathLock {
+        path,
+        lock: WRITES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone(),
+    };
+    let _guard = path_lock.lock.lock().await;
+    let _writing = data_dir_lock().read().await;
+
+    let mut temporary_name = path.file_name().unwrap_or_default().to_os_string();
+    temporary_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        NEXT_TEMPORARY.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temporary_path = path.with_file_name(temporary_name);
+    let result = write_and_rename(&temporary_path, path, content).await;
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path).await;
+    }
+    result
+}
+
+async fn write_and_rename(
+    temporary_path: &Path,
+    path: &Path,
+    content: &[u8],
+) -> Result<(), StorageError> {
+    let mut file = fs::File::create(temporary_path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(temporary_path, path).await?;
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the file that the temporary file `name` left by
+/// [`write_atomic`] was written for (`LFLE.json` for `LFLE.json.412-7.tmp` or
+/// `LFLE.json.tmp`), or `None` if it is not a temporary file.
+pub fn temporary_file_target(name: &str) -> Option<&str> {
+    let name = name.strip_suffix(".tmp")?;
+    match name.rsplit_once('.') {
+        Some((target, suffix))
+            if suffix.contains('-') && suffix.chars().all(|c| c.is_ascii_digit() || c == '-') =>
+        {
+            Some(target)
+        }
+        _ => Some(name),
+    }
+}
+
+/// Appends a line to the file at `path` (created if needed) and syncs it. If
+/// the last line of the file was torn by a power loss, it is terminated first
+/// so that the new line stays readable.
+pub async fn append_line(path: &Path, line: &str) -> Result<(), StorageError> {
+    let _writing = data_dir_lock().read().await;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut content = String::new();
+    let length = file.metadata().await?.len();
+    if length > 0 {
+        let mut last_byte = [0u8];
+        file.seek(std::io::SeekFrom::Start(length - 1)).await?;
+        file.read_exact(&mut last_byte).await?;
+        if last_byte[0] != b'\n' {
+            content.push('\n');
+        }
+    }
+    content.push_str(line);
+    content.push('\n');
+    file.write_all(content.as_bytes()).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+/// Returns the directory of a day under `root`: `root/YYYY/MM/DD`.
+pub fn day_dir(root: &Path, date: NaiveDate) -> PathBuf {
+    let mut path = root.to_path_buf();
+    path.push(date.year().to_string());
+    path.push(nb_2digits_string(date.month() as i32));
+    path.push(nb_2digits_string(date.day() as i32));
+    path
+}
+
+/// A query on the stored flights. Every `None` field matches everything.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FlightQuery {
+    /// The OACI code of the airport.
+    pub oaci: String,
+    /// The first day of the range (included).
+    pub from: NaiveDate,
+    /// The last day of the range (included).
+    pub to: NaiveDate,
+    /// The immatriculation of the glider.
+    pub glider: Option<String>,
+    /// The name of a pilot, either as first or second pilot.
+    pub pilot: Option<String>,
+}
+
+impl FlightQuery {
+    /// Returns true if the flight matches the glider and pilot of the query.
+    pub fn matches(&self, flight: &Flight) -> bool {
+        let glider_ok = match &self.glider {
+            Some(glider) => flight.glider == *glider,
+            None => true,
+        };
+        let pilot_ok = match &self.pilot {
+            Some(pilot) => flight.pilot1 == *pilot || flight.pilot2 == *pilot,
+            None => true,
+        };
+        glider_ok && pilot_ok
+    }
+}
+
+/// A flight as returned by a [`FlightQuery`], with the day and airport it
+/// belongs to.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct StoredFlight {
+    /// The day of the flight.
+    pub date: NaiveDate,
+    /// The OACI code of the airport.
+    pub oaci: String,
+    /// The flight itself.
+    pub flight: Flight,
+}
+
+/// A place where flightlogs are loaded from and saved to.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Returns the flightlog of the day at the airport `oaci`.
+    async fn load(&self, date: NaiveDate, oaci: &str) -> Result<FlightLog, StorageError>;
+    /// Saves the flightlog of the airport `oaci`, replacing the stored one.
+    async fn save(&self, flightlog: &FlightLog, oaci: &str) -> Result<(), StorageError>;
+    /// Returns the days between `from` and `to` (included) for which a
+    /// flightlog is stored at the airport `oaci`, sorted.
+    async fn dates(
+        &self,
+        oaci: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<NaiveDate>, StorageError>;
+
+    /// Returns the stored flightlogs between `from` and `to` (included).
+    async fn load_range(
+        &self,
+        oaci: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<FlightLog>, StorageError> {
+        let mut flightlogs = Vec::new();
+        for date in self.dates(oaci, from, to).await? {
+            flightlogs.push(self.load(date, oaci).await?);
+        }
+        Ok(flightlogs)
+    }
+
+    /// Returns the stored flights matching the query.
+    async fn flights(&self, query: &FlightQuery) -> Result<Vec<StoredFlight>, StorageError> {
+        let mut flights = Vec::new();
+        for flightlog in self.load_range(&query.oaci, query.from, query.to).await? {
+            for flight in flightlog.flights {
+                if query.matches(&flight) {
+                    flights.push(StoredFlight {
+                        date: flightlog.date,
+                        oaci: query.oaci.clone(),
+                        flight,
+                    });
+                }
+            }
+        }
+        Ok(flights)
+    }
+}
+
+/// Opens the backend described in the configuration, storing its files
+/// under `root`.
+pub fn open_backend(
+    configuration: &StorageConfiguration,
+    root: &Path,
+) -> Result<Arc<dyn Backend>, StorageError> {
+    match configuration {
+        StorageConfiguration::Files => Ok(Arc::new(JsonFiles::new(root.to_path_buf()))),
+        StorageConfiguration::Sqlite => Ok(Arc::new(Sqlite::open(&root.join(SQLITE_FILE))?)),
+    }
+}
+
+/// One JSON file per airport and per day, at `root/YYYY/MM/DD/OACI.json`.
+/// The days of the finished seasons are read from their archives (see
+/// [`crate::archive`]) when they are not in the live tree.
+pub struct JsonFiles {
+    root: PathBuf,
+}
+
+impl JsonFiles {
+    /// Creates a backend storing its files under `root`.
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Returns the directory of a day: `root/YYYY/MM/DD`.
+    pub fn day_dir(&self, date: NaiveDate) -> PathBuf {
+        day_dir(&self.root, date)
+    }
+
+    /// Returns the path of the flightlog of a day at an airport.
+    pub fn flightlog_path(&self, date: NaiveDate, oaci: &str) -> PathBuf {
+        self.day_dir(date).join(format!("{}.json", oaci))
+    }
+}
+
+#[async_trait]
+impl Backend for JsonFiles {
+    async fn load(&self, date: NaiveDate, oaci: &str) -> Result<FlightLog, StorageError> {
+        log::info!("Loading FlightLog at {} from the disk {}", oaci, date);
+        let path = self.flightlog_path(date, oaci);
+        if path.exists() {
+            let flightlog_str = fs::read_to_string(path).await?;
+            let flightlog = serde_json::from_str(&flightlog_str)?;
+            Ok(flightlog)
+        } else {
+            let root = self.root.clone();
+            let name = format!("{}.json", oaci);
+            let archived =
+                tokio::task::spawn_blocking(move || archive::read_archived(&root, date, &name))
+                    .await??;
+            match archived {
+                Some(content) => Ok(serde_json::from_slice(&content)?),
+                None => Err(NotFound.into()),
+            }
+        }
+    }
+
+    async fn save(&self, flightlog: &FlightLog, oaci: &str) -> Result<(), StorageError> {
+        let dir = self.day_dir(flightlog.date);
+        if !dir.exists() {
+            fs::create_dir_all(&dir).await?;
+            log::info!("Creating path {:?}", &dir);
+        }
+        write_atomic(
+            &self.flightlog_path(flightlog.date, oaci),
+            serde_json::to_string(flightlog)?.as_bytes(),
+        )
+        .await?;
+        log::info!("Saved FlightLog of the {}.", flightlog.date);
+        Ok(())
+    }
+
+    async fn dates(
+        &self,
+        oaci: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<NaiveDate>, StorageError> {
+        let root = self.root.clone();
+        let name = format!("{}.json", oaci);
+        let archived =
+            tokio::task::spawn_blocking(move || archived_days(&root, &name, from, to)).await??;
+        let mut dates = Vec::new();
+        let mut date = from;
+        while date <= to {
+            if self.flightlog_path(date, oaci).exists() || archived.contains(&date) {
+                dates.push(date);
+            }
+            date = match date.succ_opt() {
+                Some(next) => next,
+                None => break,
+            };
+        }
+        Ok(dates)
+    }
+}
+
+/// Returns the days between `from` and `to` having the file `name` in the
+/// archives of the data dir.
+fn archived_days(
+    root: &Path,
+    name: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<HashSet<NaiveDate>, StorageError> {
+    let mut days = HashSet::new();
+    let archives = root.join(archive::ARCHIVES_DIR);
+    if !archives.exists() {
+        return Ok(days);
+    }
+    for entry in std::fs::read_dir(&archives)? {
+        let path = entry?.path();
+        if !path.to_string_lossy().ends_with(".tar.gz") {
+            continue;
+        }
+        for relative in archive::archived_paths(&path)? {
+            let parts: Vec<&str> = relative.split('/').collect();
+            if let [year, month, day, file] = parts[..] {
+                let date = NaiveDate::from_ymd_opt(
+                    year.parse().unwrap_or(0),
+                    month.parse().unwrap_or(0),
+                    day.parse().unwrap_or(0),
+                );
+                if let Some(date) = date.filter(|date| file == name && from <= *date && *date <= to)
+                {
+                    days.insert(date);
+                }
+            }
+        }
+    }
+    Ok(days)
+}
+
+/// The name of the SQLite database in the data dir.
+pub const SQLITE_FILE: &str = "cepo.sqlite";
+
+/// An embedded SQLite database. Each flightlog is stored as JSON and its
+/// flights are also written in a `flights` table to allow real queries. The
+/// database is used on a blocking thread, so that its disk accesses do not
+/// stop the other requests.
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    /// Opens (and creates if needed) the database at `path`.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(path)?;
+        Self::from_connection(connection)
+    }
+
+    /// Writes a consistent copy of the database at `database` to
+    /// `destination`, even while the server is using it.
+    pub fn snapshot(database: &Path, destination: &Path) -> Result<(), StorageError> {
+        let connection = Connection::open(database)?;
+        connection.execute(
+            "VACUUM INTO ?1",
+            params![destination.to_string_lossy().to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Opens an in-memory database, mainly for tests.
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> Result<Self, StorageError> {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS flightlogs (
+                oaci TEXT NOT NULL,
+                date TEXT NOT NULL,
+                content TEXT NOT NULL,
+                PRIMARY KEY (oaci, date)
+            );
+            CREATE TABLE IF NOT EXISTS flights (
+                oaci TEXT NOT NULL,
+                date TEXT NOT NULL,
+                ogn_nb INTEGER NOT NULL,
+                glider TEXT NOT NULL,
+                pilot1 TEXT NOT NULL,
+                pilot2 TEXT NOT NULL,
+                content TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS flights_day ON flights (oaci, date);
+            CREATE INDEX IF NOT EXISTS flights_glider ON flights (glider);
+            CREATE INDEX IF NOT EXISTS flights_pilot1 ON flights (pilot1);
+            CREATE INDEX IF NOT EXISTS flights_pilot2 ON flights (pilot2);",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Returns every stored flightlog as it is in the database: its airport,
+    /// its day and its content, even if they cannot be parsed (for `fsck`).
+    pub async fn contents(&self) -> Result<Vec<(String, String, String)>, StorageError> {
+        self.with_connection(|connection| {
+            let mut statement = connection
+                .prepare("SELECT oaci, date, content FROM flightlogs ORDER BY date, oaci")?;
+            let rows =
+                statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            let mut contents = Vec::new();
+            for row in rows {
+                contents.push(row?);
+            }
+            Ok(contents)
+        })
+        .await
+    }
+
+    /// Removes the flightlog stored at `oaci` for the day written `date`, and
+    /// its flights.
+    pub async fn remove(&self, oaci: &str, date: &str) -> Result<(), StorageError> {
+        let (oaci, date) = (oaci.to_string(), date.to_string());
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM flightlogs WHERE oaci = ?1 AND date = ?2",
+                params![oaci, date],
+            )?;
+            transaction.execute(
+                "DELETE FROM flights WHERE oaci = ?1 AND date = ?2",
+                params![oaci, date],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Runs `work` with the connection on a blocking thread.
+    async fn with_connection<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut Connection) -> Result<T, StorageError> + Send + 'static,
+    ) -> Result<T, StorageError> {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || work(&mut connection.lock().unwrap())).await?
+    }
+}
+
+#[async_trait]
+impl Backend for Sqlite {
+    async fn load(&self, date: NaiveDate, oaci: &str) -> Result<FlightLog, StorageError> {
+        log::info!("Loading FlightLog at {} from the database {}", oaci, date);
+        let oaci = oaci.to_string();
+        let content: Option<String> = self
+            .with_connection(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT content FROM flightlogs WHERE oaci = ?1 AND date = ?2",
+                        params![oaci, date.to_string()],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        match content {
+            Some(content) => Ok(serde_json::from_str(&content)?),
+            None => Err(NotFound.into()),
+        }
+    }
+
+    async fn save(&self, flightlog: &FlightLog, oaci: &str) -> Result<(), StorageError> {
+        let date = flightlog.date.to_string();
+        let content = serde_json::to_string(flightlog)?;
+        let flights = flightlog
+            .flights
+            .iter()
+            .map(|flight| {
+                Ok((
+                    flight.ogn_nb,
+                    flight.glider.clone(),
+                    flight.pilot1.clone(),
+                    flight.pilot2.clone(),
+                    serde_json::to_string(flight)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        let oaci = oaci.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO flightlogs (oaci, date, content) VALUES (?1, ?2, ?3)",
+                params![oaci, date, content],
+            )?;
+            transaction.execute(
+                "DELETE FROM flights WHERE oaci = ?1 AND date = ?2",
+                params![oaci, date],
+            )?;
+            for (ogn_nb, glider, pilot1, pilot2, content) in flights {
+                transaction.execute(
+                    "INSERT INTO flights (oaci, date, ogn_nb, glider, pilot1, pilot2, content)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![oaci, date, ogn_nb, glider, pilot1, pilot2, content],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        log::info!("Saved FlightLog of the {}.", flightlog.date);
+        Ok(())
+    }
+
+    async fn dates(
+        &self,
+        oaci: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<NaiveDate>, StorageError> {
+        let oaci = oaci.to_string();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT date FROM flightlogs WHERE oaci = ?1 AND date >= ?2 AND date <= ?3
+                ORDER BY date",
+            )?;
+            let rows = statement
+                .query_map(params![oaci, from.to_string(), to.to_string()], |row| {
+                    row.get::<_, String>(0)
+                })?;
+            let mut dates = Vec::new();
+            for row in rows {
+                dates.push(row?.parse::<NaiveDate>()?);
+            }
+            Ok(dates)
+        })
+        .await
+    }
+
+    async fn flights(&self, query: &FlightQuery) -> Result<Vec<StoredFlight>, StorageError> {
+        let query = query.clone();
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT date, content FROM flights
+                WHERE oaci = ?1 AND date >= ?2 AND date <= ?3
+                AND (?4 IS NULL OR glider = ?4)
+                AND (?5 IS NULL OR pilot1 = ?5 OR pilot2 = ?5)
+                ORDER BY date, ogn_nb",
+            )?;
+            let rows = statement.query_map(
+                params![
+                    query.oaci,
+                    query.from.to_string(),
+                    query.to.to_string(),
+                    query.glider,
+                    query.pilot
+                ],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )?;
+            let mut flights = Vec::new();
+            for row in rows {
+                let (date, content) = row?;
+                flights.push(StoredFlight {
+                    date: date.parse()?,
+                    oaci: query.oaci.clone(),
+                    flight: serde_json::from_str(&content)?,
+                });
+            }
+            Ok(flights)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{temporary_file_target, write_atomic, Backend, FlightQuery, Sqlite};
+    use brick_ogn::flight::Flight;
+    use brick_ogn::flightlog::FlightLog;
+    use chrono::NaiveDate;
+
+    #[tokio::test]
+    async fn sqlite_save_load_and_query() {
+        let backend = Sqlite::open_in_memory().unwrap();
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let mut flightlog = FlightLog::new();
+        flightlog.date = date;
+        flightlog.flights.push(Flight {
+            ogn_nb: 1,
+            glider: String::from("F-CEAF"),
+            pilot1: String::from("Walt Disney"),
+            ..Default::default()
+        });
+        flightlog.flights.push(Flight {
+            ogn_nb: 2,
+            glider: String::from("F-CECY"),
+            pilot1: String::from("Roy Disney"),
+            ..Default::default()
+        });
+        backend.save(&flightlog, "LFLE").await.unwrap();
+        // saving twice must not duplicate flights
+        backend.save(&flightlog, "LFLE").await.unwrap();
+
+        let loaded = backend.load(date, "LFLE").await.unwrap();
+        assert_eq!(loaded.flights, flightlog.flights);
+        assert!(backend.load(date, "LFLB").await.is_err());
+
+        let query = FlightQuery {
+            oaci: String::from("LFLE"),
+            from: date,
+            to: date,
+            glider: None,
+            pilot: Some(String::from("Walt Disney")),
+        };
+        let flights = backend.flights(&query).await.unwrap();
+        assert_eq!(flights.len(), 1);
+        assert_eq!(flights[0].flight.glider, "F-CEAF");
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_of_the_same_file() {
+        let root = std::env::temp_dir().join(format!("cepo-write-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("LFLE.json");
+        let writes = (0..20).map(|i| {
+            let path = path.clone();
+            tokio::spawn(
+                async move { write_atomic(&path, i.to_string().repeat(1000).as_bytes()).await },
+            )
+        });
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.len() % 1000, 0);
+        assert_eq!(content, content[..content.len() / 1000].repeat(1000));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            temporary_file_target("LFLE.json.412-7.tmp"),
+            Some("LFLE.json")
+        );
+        assert_eq!(temporary_file_target("LFLE.json.tmp"), Some("LFLE.json"));
+        assert_eq!(temporary_file_target("LFLE.json"), None);
+    }
+}
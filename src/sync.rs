@@ -0,0 +1,417 @@
+//! Resilience of the synchronisation with OGN.
+//! Every request to OGN goes through a [`ResilientOgn`]: it is given up after
+//! a timeout, retried with an exponential backoff, and OGN is not requested
+//! for an airport for a while once it failed too many times in a row for that
+//! airport (circuit breaker). The outcome of the last request of each airport
+//! is kept in a [`SyncStatusBoard`], served on `/status`. Its [`RateLimiter`] spaces out
+//! all the requests that leave the server; the wait for a free slot does not
+//! count in the timeout of a request.
+
+use crate::configuration::OgnSyncConfiguration;
+use crate::ogn::{OgnError, OgnSource};
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDate};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The state of the synchronisation of an airport with OGN.
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+pub struct SyncStatus {
+    /// When OGN was last requested.
+    pub last_attempt: Option<DateTime<Local>>,
+    /// When OGN last answered.
+    pub last_success: Option<DateTime<Local>>,
+    /// The error of the last request, if it failed.
+    pub last_error: Option<String>,
+    /// Since when the requests fail.
+    pub failing_since: Option<DateTime<Local>>,
+    /// The number of failed requests in a row.
+    pub consecutive_failures: u32,
+    /// A short sentence for the field crew, like `OGN unreachable since 14:05`.
+    pub message: String,
+}
+
+impl SyncStatus {
+    fn record_success(&mut self, now: DateTime<Local>) {
+        self.last_attempt = Some(now);
+        self.last_success = Some(now);
+        self.last_error = None;
+        self.failing_since = None;
+        self.consecutive_failures = 0;
+        self.message = format!("Synchronised with OGN at {}", now.format("%H:%M"));
+    }
+
+    fn record_failure(&mut self, now: DateTime<Local>, err: &OgnError) {
+        self.last_attempt = Some(now);
+        self.last_error = Some(err.to_string());
+        let since = *self.failing_since.get_or_insert(now);
+        self.consecutive_failures += 1;
+        self.message = format!("OGN unreachable since {}", since.format("%H:%M"));
+    }
+}
+
+/// The synchronisation status of every airport.
+#[derive(Default)]
+pub struct SyncStatusBoard {
+    statuses: Mutex<HashMap<String, SyncStatus>>,
+}
+
+impl SyncStatusBoard {
+    /// Creates an empty board.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the status of an airport.
+    pub fn get(&self, oaci: &str) -> SyncStatus {
+        self.statuses
+            .lock()
+            .unwrap()
+            .get(oaci)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn update(&self, oaci: &str, update: impl FnOnce(&mut SyncStatus)) {
+        let mut statuses = self.statuses.lock().unwrap();
+        update(statuses.entry(oaci.to_string()).or_default());
+    }
+}
+
+/// Returns a random number in `[0, 1)`, without needing a random crate: the
+/// keys of a new `RandomState` are random.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    hasher.write_u32(now.subsec_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Returns the delay before the next attempt after `failures` failures in a
+/// row: `base` doubled for each failure, at most `max`, with a random jitter
+/// taking it between its half and its whole so that the airports do not all
+/// request OGN at the same time.
+pub fn backoff_delay(failures: u32, base: Duration, max: Duration) -> Duration {
+    let exponential = base
+        .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .unwrap_or(max)
+        .min(max);
+    exponential.mul_f64(0.5 + random_fraction() / 2.0)
+}
+
+/// The circuit breaker of the requests to OGN of an airport.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<(Instant, DateTime<Local>)>,
+}
+
+/// An [`OgnSource`] adding timeouts, retries and a circuit breaker to another
+/// one, and recording the outcome of the requests in a [`SyncStatusBoard`].
+pub struct ResilientOgn {
+    inner: Arc<dyn OgnSource>,
+    policy: OgnSyncConfiguration,
+    breakers: Mutex<HashMap<String, Breaker>>,
+    board: Arc<SyncStatusBoard>,
+    limiter: RateLimiter,
+}
+
+impl ResilientOgn {
+    /// Wraps `inner` with the `policy`, recording in `board`.
+    pub fn new(
+        inner: Arc<dyn OgnSource>,
+        policy: OgnSyncConfiguration,
+        board: Arc<SyncStatusBoard>,
+    ) -> Self {
+        let limiter = RateLimiter::new(Duration::from_millis(policy.min_request_interval_ms));
+        Self {
+            inner,
+            policy,
+            breakers: Mutex::new(HashMap::new()),
+            board,
+            limiter,
+        }
+    }
+
+    /// Returns an error if the breaker of the airport is open. Once the
+    /// cooldown is over, one request is let through to see if OGN is back.
+    fn check_breaker(&self, oaci: &str) -> Result<(), OgnError> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(oaci.to_string()).or_default();
+        match breaker.open_until {
+            Some((until, until_local)) if Instant::now() < until => {
+                Err(OgnError::CircuitOpen(until_local))
+            }
+            Some(_) => {
+                // Half open: the next failure opens it again.
+                breaker.open_until = None;
+                breaker.failures = self.policy.breaker_threshold.saturating_sub(1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record(&self, oaci: &str, result: &Result<Vec<u8>, OgnError>) {
+        let now = Local::now();
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(oaci.to_string()).or_default();
+        match result {
+            Ok(_) => {
+                breaker.failures = 0;
+                self.board.update(oaci, |status| status.record_success(now));
+            }
+            // An error answer of OGN shows that it is up.
+            Err(err) if !is_transient(err) => {
+                breaker.failures = 0;
+                self.board
+                    .update(oaci, |status| status.record_failure(now, err));
+            }
+            Err(err) => {
+                breaker.failures += 1;
+                if breaker.failures >= self.policy.breaker_threshold.max(1) {
+                    let cooldown = Duration::from_secs(self.policy.breaker_cooldown_secs);
+                    let until_local = now
+                        + chrono::Duration::from_std(cooldown).unwrap_or(chrono::Duration::zero());
+                    if breaker.open_until.is_none() {
+                        log::warn!(
+                            "OGN failed {} times in a row for {oaci}, not requesting it until {}",
+                            breaker.failures,
+                            until_local.format("%H:%M")
+                        );
+                    }
+                    breaker.open_until = Some((Instant::now() + cooldown, until_local));
+                }
+                self.board
+                    .update(oaci, |status| status.record_failure(now, err));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl OgnSource for ResilientOgn {
+    async fn logbook(&self, oaci: &str, date: NaiveDate) -> Result<Vec<u8>, OgnError> {
+        let timeout = Duration::from_secs(self.policy.timeout_secs);
+        let mut attempt = 0;
+        loop {
+            if let Err(err) = self.check_breaker(oaci) {
+                self.board.update(oaci, |status| {
+                    status.last_error = Some(err.to_string());
+                });
+                return Err(err);
+            }
+            self.limiter.wait().await;
+            let result = match tokio::time::timeout(timeout, self.inner.logbook(oaci, date)).await {
+                Ok(result) => result,
+                Err(_) => Err(OgnError::Timeout(timeout)),
+            };
+            self.record(oaci, &result);
+            match result {
+                Err(err) if attempt < self.policy.retries && is_transient(&err) => {
+                    attempt += 1;
+                    let delay = backoff_delay(
+                        attempt,
+                        Duration::from_secs(self.policy.retry_base_secs),
+                        Duration::from_secs(self.policy.max_backoff_secs),
+                    );
+                    log::warn!("Request to OGN failed ({err}), retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Leaves at least `interval` between two requests to OGN, whatever the
+/// airport and the day, so that the server stays a polite client of OGN.
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Option<tokio::time::Instant>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter leaving `interval` between two requests.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Books the next free slot and returns when it starts.
+    fn book_slot(&self) -> tokio::time::Instant {
+        let now = tokio::time::Instant::now();
+        let mut next_slot = self.next_slot.lock().unwrap();
+        let slot = next_slot.map_or(now, |next_slot| next_slot.max(now));
+        *next_slot = Some(slot + self.interval);
+        slot
+    }
+
+    /// Waits for the next free slot.
+    pub async fn wait(&self) {
+        tokio::time::sleep_until(self.book_slot()).await;
+    }
+}
+
+/// Returns true for the errors that may not happen again on a new attempt.
+fn is_transient(err: &OgnError) -> bool {
+    match err {
+        OgnError::Http(_) | OgnError::Timeout(_) => true,
+        OgnError::Status(status) => status.is_server_error(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff_delay, ResilientOgn, SyncStatusBoard};
+    use crate::configuration::OgnSyncConfiguration;
+    use crate::ogn::{OgnError, OgnSource};
+    use async_trait::async_trait;
+    use chrono::NaiveDate;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A source that never answers.
+    struct Silent {
+        requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl OgnSource for Silent {
+        async fn logbook(&self, _oaci: &str, _date: NaiveDate) -> Result<Vec<u8>, OgnError> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_and_limit() {
+        let base = Duration::from_secs(10);
+        let max = Duration::from_secs(100);
+        for (failures, expected) in [(1, 10), (2, 20), (3, 40), (4, 80), (5, 100), (60, 100)] {
+            let delay = backoff_delay(failures, base, max);
+            let expected = Duration::from_secs(expected);
+            assert!(
+                expected / 2 <= delay && delay <= expected,
+                "{failures}: {delay:?}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_open_the_breaker() {
+        let silent = Arc::new(Silent {
+            requests: AtomicUsize::new(0),
+        });
+        let board = Arc::new(SyncStatusBoard::new());
+        let policy = OgnSyncConfiguration {
+            timeout_secs: 5,
+            retries: 1,
+            breaker_threshold: 3,
+            ..Default::default()
+        };
+        let ogn = ResilientOgn::new(silent.clone(), policy, board.clone());
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+
+        // a timeout, retried once
+        assert!(matches!(
+            ogn.logbook("LFLE", date).await,
+            Err(OgnError::Timeout(_))
+        ));
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 2);
+        // the third failure opens the breaker, then OGN is left alone
+        assert!(ogn.logbook("LFLE", date).await.is_err());
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 3);
+        assert!(matches!(
+            ogn.logbook("LFLE", date).await,
+            Err(OgnError::CircuitOpen(_))
+        ));
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 3);
+
+        let status = board.get("LFLE");
+        assert_eq!(status.consecutive_failures, 3);
+        assert!(status.message.starts_with("OGN unreachable since "));
+
+        // the other airports still request OGN, and their status tells it
+        assert!(matches!(
+            ogn.logbook("LFLB", date).await,
+            Err(OgnError::Timeout(_))
+        ));
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 5);
+        assert_eq!(board.get("LFLB").consecutive_failures, 2);
+        assert_eq!(board.get("LFLE").consecutive_failures, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_are_spaced_out() {
+        let silent = Arc::new(Silent {
+            requests: AtomicUsize::new(0),
+        });
+        let policy = OgnSyncConfiguration {
+            min_request_interval_ms: 2000,
+            ..Default::default()
+        };
+        let ogn = Arc::new(ResilientOgn::new(
+            silent.clone(),
+            policy,
+            Arc::new(SyncStatusBoard::new()),
+        ));
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        for oaci in ["LFLE", "LFLB", "LFLE"] {
+            let ogn = ogn.clone();
+            tokio::spawn(async move { ogn.logbook(oaci, date).await });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 1);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(silent.requests.load(Ordering::SeqCst), 3);
+    }
+
+    /// A source that answers after a second.
+    struct Slow;
+
+    #[async_trait]
+    impl OgnSource for Slow {
+        async fn logbook(&self, _oaci: &str, _date: NaiveDate) -> Result<Vec<u8>, OgnError> {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_for_a_slot_is_not_a_timeout() {
+        let policy = OgnSyncConfiguration {
+            timeout_secs: 5,
+            retries: 0,
+            breaker_threshold: 1,
+            min_request_interval_ms: 2000,
+            ..Default::default()
+        };
+        let board = Arc::new(SyncStatusBoard::new());
+        let ogn = Arc::new(ResilientOgn::new(Arc::new(Slow), policy, board.clone()));
+        let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        // the last one waits 18 s for its slot
+        let requests: Vec<_> = (0..10)
+            .map(|_| {
+                let ogn = ogn.clone();
+                tokio::spawn(async move { ogn.logbook("LFLE", date).await })
+            })
+            .collect();
+        for request in requests {
+            assert!(request.await.unwrap().is_ok());
+        }
+        assert_eq!(board.get("LFLE").consecutive_failures, 0);
+    }
+}
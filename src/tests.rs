@@ -1,23 +1,63 @@
-#[cfg(test)]
-#[test]
-fn test_flight_from_json() {
-    use brick_ogn::flight::Flight;
-    use chrono::NaiveTime;
-
-    let flight = Flight {
-        ogn_nb: 1,
-        takeoff_code: String::from(""),
-        takeoff_machine: String::from(""),
-        takeoff_machine_pilot: String::from(""),
-        glider: String::from("F-CEAF"),
-        flight_code: String::from(""),
-        pilot1: String::from(""),
-        pilot2: String::from(""),
-        takeoff: NaiveTime::from_hms_opt(14, 14, 0).unwrap(),
-        landing: NaiveTime::from_hms_opt(14, 19, 0).unwrap(),
-    };
-
-    let flight_json = serde_json::to_string(&flight).unwrap_or_default();
-    let flight_test = serde_json::from_str(&flight_json).unwrap_or_default();
-    assert_eq!(flight, flight_test)
-}
+#[cfg(test)]
+#[test]
+fn test_flight_from_json() {
+    use brick_ogn::flight::Flight;
+    use chrono::NaiveTime;
+
+    let flight = Flight {
+        ogn_nb: 1,
+        takeoff_code: String::from(""),
+        takeoff_machine: String::from(""),
+        takeoff_machine_pilot: String::from(""),
+        glider: String::from("F-CEAF"),
+        flight_code: String::from(""),
+        pilot1: String::from(""),
+        pilot2: String::from(""),
+        takeoff: NaiveTime::from_hms_opt(14, 14, 0).unwrap(),
+        landing: NaiveTime::from_hms_opt(14, 19, 0).unwrap(),
+    };
+
+    let flight_json = serde_json::to_string(&flight).unwrap_or_default();
+    let flight_test = serde_json::from_str(&flight_json).unwrap_or_default();
+    assert_eq!(flight, flight_test)
+}
+
+#[cfg(test)]
+#[test]
+fn test_data_dir_priority() {
+    use crate::{override_data_dir, Cli};
+    use clap::Parser;
+    use serveur::configuration::Configuration;
+    use std::path::PathBuf;
+
+    // The command line, then CEPO_DATA_DIR, then the configuration, then the
+    // default.
+    let data_dir = |args: &[&str], configured: Option<&str>| {
+        let cli = Cli::try_parse_from(args).unwrap();
+        let mut configuration = Configuration {
+            data_dir: configured.map(PathBuf::from),
+            ..Configuration::example()
+        };
+        override_data_dir(&mut configuration, cli.data_dir);
+        configuration.data_dir()
+    };
+    std::env::remove_var("CEPO_DATA_DIR");
+    assert_eq!(data_dir(&["serveur"], None), serveur::data_dir());
+    assert_eq!(
+        data_dir(&["serveur"], Some("/configured")),
+        PathBuf::from("/configured")
+    );
+    std::env::set_var("CEPO_DATA_DIR", "/environment");
+    assert_eq!(
+        data_dir(&["serveur", "fsck"], Some("/configured")),
+        PathBuf::from("/environment")
+    );
+    assert_eq!(
+        data_dir(
+            &["serveur", "--data-dir", "/command-line", "fsck"],
+            Some("/configured")
+        ),
+        PathBuf::from("/command-line")
+    );
+    std::env::remove_var("CEPO_DATA_DIR");
+}
@@ -0,0 +1,77 @@
+//! Time zones of the airports.
+//! The times of a flightlog are the local times of its airport, and its day
+//! starts at the local midnight of the airport. An airport without a
+//! `time_zone` uses the one of the server, which is wrong as soon as the
+//! server runs in UTC (like in a container).
+
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+/// The time zone of an airport: a named zone, or the zone of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AirportZone(pub Option<Tz>);
+
+impl AirportZone {
+    /// Returns the local date and time at the airport.
+    pub fn now(&self) -> NaiveDateTime {
+        self.from_utc(Utc::now().naive_utc())
+    }
+
+    /// Returns the current day at the airport.
+    pub fn today(&self) -> NaiveDate {
+        self.now().date()
+    }
+
+    /// Converts a UTC date and time to the local one of the airport.
+    pub fn from_utc(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        let utc = Utc.from_utc_datetime(&utc);
+        match self.0 {
+            Some(zone) => utc.with_timezone(&zone).naive_local(),
+            None => utc.with_timezone(&Local).naive_local(),
+        }
+    }
+
+    /// Returns the local time of the airport, to the minute like the
+    /// flightbook, of a UNIX timestamp.
+    pub fn time_of_timestamp(&self, timestamp: i64) -> Option<NaiveTime> {
+        let utc = Utc.timestamp_opt(timestamp, 0).single()?.naive_utc();
+        Some(to_the_minute(self.from_utc(utc).time()))
+    }
+
+    /// Returns the local time of the airport, to the minute, of a UTC time of
+    /// the day `date`.
+    pub fn time_of_utc(&self, date: NaiveDate, utc_time: NaiveTime) -> NaiveTime {
+        to_the_minute(self.from_utc(date.and_time(utc_time)).time())
+    }
+}
+
+/// Drops the seconds of a time.
+fn to_the_minute(time: NaiveTime) -> NaiveTime {
+    time.with_second(0)
+        .and_then(|time| time.with_nanosecond(0))
+        .unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AirportZone;
+    use chrono::{NaiveDate, NaiveTime};
+
+    #[test]
+    fn convert_utc_to_airport_times() {
+        let paris = AirportZone(Some(chrono_tz::Europe::Paris));
+        let time = |hour, minute, second| NaiveTime::from_hms_opt(hour, minute, second).unwrap();
+        let summer = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let winter = NaiveDate::from_ymd_opt(2024, 1, 10).unwrap();
+
+        assert_eq!(paris.time_of_utc(summer, time(8, 2, 15)), time(10, 2, 0));
+        assert_eq!(paris.time_of_utc(winter, time(8, 2, 15)), time(9, 2, 0));
+        // 2024-06-10 08:02:00 UTC
+        assert_eq!(paris.time_of_timestamp(1718006520), Some(time(10, 2, 0)));
+        // the day at the airport starts at its own midnight
+        let late = summer.and_time(time(22, 30, 0));
+        assert_eq!(paris.from_utc(late).date(), summer.succ_opt().unwrap());
+        let utc = AirportZone(Some(chrono_tz::UTC));
+        assert_eq!(utc.from_utc(late).date(), summer);
+    }
+}
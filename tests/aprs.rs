@@ -0,0 +1,50 @@
+//! Live APRS ingestion against recorded beacons.
+
+mod common;
+
+use chrono::{NaiveDate, NaiveTime};
+use common::{replay_beacons, test_configuration};
+use serveur::aprs::{followed_airports, session, FlightDetector};
+use serveur::configuration::AprsConfiguration;
+use serveur::flightlog::Storage;
+use serveur::Context;
+use std::collections::HashMap;
+
+#[tokio::test]
+async fn replayed_beacons_update_the_flightlog() {
+    let (server, login) = replay_beacons("LFLE-2024-06-10.txt").await;
+    let mut configuration = test_configuration("aprs-replay", "http://127.0.0.1:9");
+    configuration.aprs = Some(AprsConfiguration {
+        server,
+        devices: HashMap::from([
+            (String::from("dd8e3a"), String::from("F-CEJU")),
+            (String::from("DDA1B4"), String::from("F-CECY")),
+        ]),
+        ..Default::default()
+    });
+    let context = Context::new(configuration).await;
+    let oaci = String::from("LFLE");
+    let date = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+    let flightlog_arc = context.flightlogs[&oaci].clone();
+    flightlog_arc.lock().unwrap().date = date;
+
+    let mut detector = FlightDetector::new(followed_airports(&context), 10.0);
+    let events = session(&context, &mut detector).await.unwrap();
+    assert_eq!(events, 2);
+    let login = login.await.unwrap();
+    assert!(login.starts_with("user CEPO pass -1 "));
+    assert!(login.ends_with("filter r/45.5611/5.9756/10 r/45.6381/5.8803/10\r\n"));
+
+    let flightlog = flightlog_arc.lock().unwrap().clone();
+    assert_eq!(flightlog.flights.len(), 1);
+    let flight = &flightlog.flights[0];
+    assert_eq!(flight.glider, "F-CEJU");
+    // the beacons are in UTC, the flightlog in the time zone of the airport
+    assert_eq!(flight.takeoff, NaiveTime::from_hms_opt(12, 2, 0).unwrap());
+    assert_eq!(flight.landing, NaiveTime::from_hms_opt(13, 30, 0).unwrap());
+    let stored = brick_ogn::flightlog::FlightLog::load(date, &oaci, &context)
+        .await
+        .unwrap();
+    assert_eq!(stored.flights, flightlog.flights);
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
@@ -0,0 +1,107 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server, StatusCode};
+use serveur::configuration::Configuration;
+use serveur::Context;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Returns the directory of the recorded data used by the tests.
+pub fn fixtures_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+/// A local stand-in for the OGN flightbook API, serving the recorded logbooks
+/// of `tests/fixtures/logbook/OACI/YYYY-MM-DD.json`.
+pub struct FixtureServer {
+    /// The base URL to give to `HttpOgn`.
+    pub url: String,
+    /// The number of requests received.
+    pub requests: Arc<AtomicUsize>,
+}
+
+impl FixtureServer {
+    /// Starts the server on a free local port.
+    pub fn start() -> Self {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let service = make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let relative = req.uri().path().trim_start_matches('/');
+                        let path = fixtures_dir().join(format!("{}.json", relative));
+                        let response = match std::fs::read(path) {
+                            Ok(content) if !relative.contains("..") => {
+                                Response::new(Body::from(content))
+                            }
+                            _ => Response::builder()
+                                .status(StatusCode::NOT_FOUND)
+                                .body(Body::empty())
+                                .unwrap(),
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        Self { url, requests }
+    }
+
+    /// Returns the number of requests received so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the example configuration, storing in a new temporary directory
+/// named after `name` and requesting OGN at `ogn_url` without retrying nor
+/// spacing out the requests.
+pub fn test_configuration(name: &str, ogn_url: &str) -> Configuration {
+    let data_dir = std::env::temp_dir().join(format!("cepo-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let mut configuration = Configuration::example();
+    configuration.data_dir = Some(data_dir);
+    configuration.ogn_url = ogn_url.to_string();
+    configuration.ogn_sync.retries = 0;
+    configuration.ogn_sync.min_request_interval_ms = 0;
+    configuration
+}
+
+/// Returns a context on the [`test_configuration`].
+pub async fn test_context(name: &str, ogn_url: &str) -> Context {
+    Context::new(test_configuration(name, ogn_url)).await
+}
+
+/// A local stand-in for an APRS-IS server: it accepts one client, reads its
+/// login line, sends the recorded beacons of `tests/fixtures/aprs/<file>` and
+/// closes the connection. Returns its address and the login line received.
+pub async fn replay_beacons(file: &str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let beacons = std::fs::read(fixtures_dir().join("aprs").join(file)).unwrap();
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut login = String::new();
+        BufReader::new(reader).read_line(&mut login).await.unwrap();
+        writer.write_all(&beacons).await.unwrap();
+        writer.shutdown().await.unwrap();
+        login
+    });
+    (address, handle)
+}
@@ -0,0 +1,36 @@
+//! Day boundary of the airports.
+
+mod common;
+
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::FlightLog;
+use common::test_context;
+use serveur::flightlog::{roll_over, Storage};
+
+#[tokio::test]
+async fn roll_over_saves_the_day_and_starts_the_next() {
+    let context = test_context("day-roll-over", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    let today = context.configuration.today(&oaci);
+    let yesterday = today.pred_opt().unwrap();
+    let flightlog_arc = context.flightlogs[&oaci].clone();
+    assert_eq!(flightlog_arc.lock().unwrap().date, today);
+    assert!(!roll_over(&flightlog_arc, &oaci, &context).await.unwrap());
+
+    {
+        let mut flightlog = flightlog_arc.lock().unwrap();
+        flightlog.date = yesterday;
+        flightlog.flights = vec![Flight {
+            ogn_nb: 1,
+            glider: String::from("F-CEJU"),
+            ..Default::default()
+        }];
+    }
+    assert!(roll_over(&flightlog_arc, &oaci, &context).await.unwrap());
+    let flightlog = flightlog_arc.lock().unwrap().clone();
+    assert_eq!(flightlog.date, today);
+    assert!(flightlog.flights.is_empty());
+    let stored = FlightLog::load(yesterday, &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights.len(), 1);
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
@@ -0,0 +1,160 @@
+//! Checks of the stored flightlogs.
+
+mod common;
+
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::FlightLog;
+use chrono::NaiveDate;
+use common::{test_configuration, test_context};
+use serveur::archive::{archive_finished, archived_paths, ArchivePeriod};
+use serveur::configuration::StorageConfiguration;
+use serveur::flightlog::Storage;
+use serveur::fsck::{fsck, FsckOptions, ProblemKind};
+use serveur::storage::SQLITE_FILE;
+use serveur::Context;
+
+#[tokio::test]
+async fn corrupted_files_are_quarantined_and_the_check_goes_on() {
+    let context = test_context("fsck-corrupted", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    let corrupted = NaiveDate::from_ymd_opt(2024, 6, 9).unwrap();
+    let sound = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+    let mut flightlog = FlightLog::new();
+    flightlog.date = sound;
+    flightlog.flights = vec![Flight {
+        ogn_nb: 1,
+        glider: String::from("F-CEJU"),
+        ..Default::default()
+    }];
+    flightlog.save(&oaci, &context).await.unwrap();
+    let path = context.data_dir.join("2024/06/09/LFLE.json");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, b"{\"date\":\"2024-06-09\",\xff\xfe").unwrap();
+
+    let options = FsckOptions {
+        repair: false,
+        quarantine: true,
+    };
+    let report = fsck(&context, options).await.unwrap();
+    assert_eq!(report.checked, 2);
+    assert_eq!(report.problems.len(), 1);
+    let problem = &report.problems[0];
+    assert_eq!(problem.date, corrupted);
+    assert!(matches!(problem.kind, ProblemKind::Unparsable(_)));
+    assert!(problem.fixed);
+    assert!(!path.exists());
+    assert!(context
+        .data_dir
+        .join("quarantine/2024/06/09/LFLE.json")
+        .exists());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn archived_flightlogs_and_archives_are_checked() {
+    let context = test_context("fsck-archives", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    let sound = NaiveDate::from_ymd_opt(2022, 6, 10).unwrap();
+    let corrupted = NaiveDate::from_ymd_opt(2022, 6, 11).unwrap();
+    let mut flightlog = FlightLog::new();
+    flightlog.date = sound;
+    flightlog.save(&oaci, &context).await.unwrap();
+    let path = context.data_dir.join("2022/06/11/LFLE.json");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "{\"date\":").unwrap();
+    let root = context.data_dir.clone();
+    tokio::task::spawn_blocking(move || {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        archive_finished(&root, ArchivePeriod::Year, today, 2)
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(!path.exists());
+    let unreadable = context.data_dir.join("archives/2021.tar.gz");
+    std::fs::write(&unreadable, "not an archive").unwrap();
+
+    let options = FsckOptions {
+        repair: false,
+        quarantine: true,
+    };
+    let report = fsck(&context, options).await.unwrap();
+    assert_eq!(report.checked, 2);
+    assert_eq!(report.problems.len(), 2);
+    assert!(matches!(
+        &report.problems[0].kind,
+        ProblemKind::UnreadableArchive(name, _) if name == "2021.tar.gz"
+    ));
+    assert_eq!(report.problems[1].date, corrupted);
+    assert!(matches!(
+        report.problems[1].kind,
+        ProblemKind::Unparsable(_)
+    ));
+    assert!(!report.has_remaining_problems());
+    assert!(context
+        .data_dir
+        .join("quarantine/archives/2021.tar.gz")
+        .exists());
+    assert_eq!(
+        std::fs::read_to_string(context.data_dir.join("quarantine/2022/06/11/LFLE.json")).unwrap(),
+        "{\"date\":"
+    );
+    // the archive keeps its sound flightlogs only
+    assert_eq!(
+        archived_paths(&context.data_dir.join("archives/2022.tar.gz")).unwrap(),
+        ["2022/06/10/LFLE.json"]
+    );
+    assert!(fsck(&context, options).await.unwrap().problems.is_empty());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn unreadable_days_of_the_database_are_quarantined() {
+    let mut configuration = test_configuration("fsck-sqlite", "http://127.0.0.1:9");
+    configuration.storage = StorageConfiguration::Sqlite;
+    let context = Context::new(configuration).await;
+    let sound = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+    let mut flightlog = FlightLog::new();
+    flightlog.date = sound;
+    context.storage.save(&flightlog, "LFLE").await.unwrap();
+    let database = rusqlite::Connection::open(context.data_dir.join(SQLITE_FILE)).unwrap();
+    for (date, content) in [("2024-06-11", "{\"date\":"), ("2024-13-45", "{}")] {
+        database
+            .execute(
+                "INSERT INTO flightlogs (oaci, date, content) VALUES ('LFLE', ?1, ?2)",
+                [date, content],
+            )
+            .unwrap();
+    }
+
+    let options = FsckOptions {
+        repair: false,
+        quarantine: true,
+    };
+    let report = fsck(&context, options).await.unwrap();
+    assert_eq!(report.checked, 3);
+    let kinds: Vec<&ProblemKind> = report
+        .problems
+        .iter()
+        .map(|problem| &problem.kind)
+        .collect();
+    assert!(
+        matches!(kinds[..], [ProblemKind::Unparsable(_), ProblemKind::UnreadableDay(day, _)] if day == "2024-13-45")
+    );
+    assert!(!report.has_remaining_problems());
+    assert!(context
+        .data_dir
+        .join("quarantine/sqlite/2024-06-11/LFLE.json")
+        .exists());
+    assert!(context
+        .data_dir
+        .join("quarantine/sqlite/2024-13-45/LFLE.json")
+        .exists());
+    let first = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    let last = NaiveDate::from_ymd_opt(2024, 12, 31).unwrap();
+    assert_eq!(
+        context.storage.dates("LFLE", first, last).await.unwrap(),
+        [sound]
+    );
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
@@ -0,0 +1,162 @@
+//! Properties of the reconciliation of stored flights with OGN, on random
+//! flightlogs and OGN flights.
+
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::FlightLog;
+use chrono::{Days, NaiveDate, NaiveTime};
+use proptest::prelude::*;
+use proptest::sample::select;
+use serveur::provenance::{Provenance, Source};
+use serveur::reconcile::{field_value, reconcile, MatchPolicy, ReconcileReport, OGN_FIELDS};
+use std::collections::HashSet;
+
+/// A time of the day, or 00:00 for an unknown one.
+fn time() -> impl Strategy<Value = NaiveTime> {
+    prop_oneof![
+        1 => Just(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+        4 => (8u32..20, 0u32..60).prop_map(|(hour, minute)| {
+            NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+        }),
+    ]
+}
+
+/// A flight without its number.
+fn flight() -> impl Strategy<Value = Flight> {
+    (
+        select(vec!["F-CEJU", "f-ceju", "F-CECY", "D-1234"]),
+        select(vec!["", "T", "R", "A"]),
+        select(vec!["", "F-GDRT", "F-JXYZ", "yellow"]),
+        select(vec!["", "Pierre", "Marie"]),
+        time(),
+        time(),
+    )
+        .prop_map(|(glider, code, machine, pilot, takeoff, landing)| Flight {
+            glider: glider.to_string(),
+            takeoff_code: code.to_string(),
+            takeoff_machine: machine.to_string(),
+            pilot1: pilot.to_string(),
+            takeoff,
+            landing,
+            ..Default::default()
+        })
+}
+
+/// A stored day and where the fields of its flights come from.
+#[derive(Debug, Clone)]
+struct Day {
+    date: NaiveDate,
+    flights: Vec<Flight>,
+    provenance: Provenance,
+}
+
+impl Day {
+    fn flightlog(&self) -> FlightLog {
+        let mut flightlog = FlightLog::new();
+        flightlog.date = self.date;
+        flightlog.flights = self.flights.clone();
+        flightlog
+    }
+}
+
+/// A stored day: flights entered by hand (negative numbers) and flights that
+/// came from OGN (odd numbers, some of them seen again by OGN), with fields
+/// entered by hand.
+fn day() -> impl Strategy<Value = Day> {
+    (
+        0u64..366,
+        prop::collection::vec((flight(), any::<bool>(), any::<[bool; 4]>()), 0..8),
+    )
+        .prop_map(|(day, flights)| {
+            let mut stored = Day {
+                date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap() + Days::new(day),
+                flights: Vec::new(),
+                provenance: Provenance::default(),
+            };
+            for (index, (mut flight, entered, manual)) in flights.into_iter().enumerate() {
+                let index = index as i32;
+                flight.ogn_nb = if entered { -(index + 1) } else { 2 * index + 1 };
+                for (field, manual) in OGN_FIELDS.iter().zip(manual) {
+                    if manual {
+                        stored
+                            .provenance
+                            .set_source(flight.ogn_nb, field, Source::Manual);
+                    }
+                }
+                stored.flights.push(flight);
+            }
+            stored
+        })
+}
+
+/// The flights of an OGN logbook, numbered from 1.
+fn ogn_flights() -> impl Strategy<Value = Vec<Flight>> {
+    prop::collection::vec(flight(), 0..8).prop_map(|flights| {
+        flights
+            .into_iter()
+            .enumerate()
+            .map(|(index, flight)| Flight {
+                ogn_nb: index as i32 + 1,
+                pilot1: String::new(),
+                ..flight
+            })
+            .collect()
+    })
+}
+
+/// Merges the OGN flights in the flightlog of the day.
+fn merge(day: &mut Day, ogn: Vec<Flight>) -> ReconcileReport {
+    let mut flightlog = day.flightlog();
+    let report = reconcile(
+        &mut flightlog.flights,
+        ogn,
+        &mut day.provenance,
+        &MatchPolicy::default(),
+    );
+    day.flights = flightlog.flights;
+    report
+}
+
+proptest! {
+    #[test]
+    fn merging_twice_changes_nothing(mut day in day(), ogn in ogn_flights()) {
+        merge(&mut day, ogn.clone());
+        let merged = day.clone();
+        let report = merge(&mut day, ogn);
+        prop_assert!(!report.has_changes(), "{:?}", report);
+        prop_assert!(report.conflicts.is_empty());
+        prop_assert_eq!(day.flights, merged.flights);
+        prop_assert_eq!(day.provenance, merged.provenance);
+    }
+
+    #[test]
+    fn fields_entered_by_hand_are_kept(mut day in day(), ogn in ogn_flights()) {
+        let before = day.clone();
+        merge(&mut day, ogn);
+        for (old, new) in before.flights.iter().zip(&day.flights) {
+            prop_assert_eq!(&old.glider, &new.glider);
+            prop_assert_eq!(&old.pilot1, &new.pilot1);
+            prop_assert_eq!(&old.pilot2, &new.pilot2);
+            prop_assert_eq!(&old.flight_code, &new.flight_code);
+            for field in OGN_FIELDS {
+                let value = field_value(old, field);
+                let manual = before.provenance.source(old.ogn_nb, field) == Some(Source::Manual);
+                if manual && !value.is_empty() {
+                    prop_assert_eq!(value, field_value(new, field), "{}", field);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_flight_is_there_once(mut day in day(), ogn in ogn_flights()) {
+        let stored = day.flights.len();
+        let report = merge(&mut day, ogn.clone());
+        let numbers: HashSet<i32> = day.flights.iter().map(|flight| flight.ogn_nb).collect();
+        prop_assert_eq!(numbers.len(), day.flights.len());
+        for flight in &ogn {
+            prop_assert!(numbers.contains(&flight.ogn_nb));
+        }
+        prop_assert_eq!(day.flights.len(), stored + report.created.len());
+        prop_assert_eq!(report.matched.len() + report.created.len(), ogn.len());
+    }
+}
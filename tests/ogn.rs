@@ -0,0 +1,366 @@
+//! Synchronisation with OGN against recorded logbooks.
+
+mod common;
+
+use brick_ogn::flight::Flight;
+use brick_ogn::flightlog::update::Update;
+use brick_ogn::flightlog::FlightLog;
+use chrono::{NaiveDate, NaiveTime};
+use common::{fixtures_dir, test_context, FixtureServer};
+use serveur::apply_update;
+use serveur::backfill::{backfill, start_backfill, AlreadyRunning, DayOutcome};
+use serveur::cache::past_flightlog;
+use serveur::flightlog::Storage;
+use serveur::ogn::{synchronisation_ogn, HttpOgn, OgnSource};
+use serveur::provenance::Provenance;
+use std::time::Duration;
+
+fn date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2024, 6, 10).unwrap()
+}
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+/// The flights of the LFLE gliders in the recorded logbook.
+fn expected_flights() -> Vec<Flight> {
+    vec![
+        Flight {
+            ogn_nb: 2,
+            takeoff_code: String::from("R"),
+            takeoff_machine: String::from("F-GDRT"),
+            glider: String::from("F-CEJU"),
+            takeoff: time(10, 2),
+            landing: time(11, 30),
+            ..Default::default()
+        },
+        Flight {
+            ogn_nb: 3,
+            takeoff_code: String::from("T"),
+            glider: String::from("F-CECY"),
+            takeoff: time(10, 40),
+            landing: time(0, 0),
+            ..Default::default()
+        },
+    ]
+}
+
+#[tokio::test]
+async fn http_source_returns_recorded_logbook() {
+    let server = FixtureServer::start();
+    let source = HttpOgn::new(format!("{}/", server.url));
+    let logbook = source.logbook("LFLE", date()).await.unwrap();
+    let recorded = std::fs::read(fixtures_dir().join("logbook/LFLE/2024-06-10.json")).unwrap();
+    assert_eq!(logbook, recorded);
+    assert!(source
+        .logbook("LFLE", NaiveDate::from_ymd_opt(2024, 6, 11).unwrap())
+        .await
+        .is_err());
+    assert_eq!(server.requests(), 2);
+}
+
+#[tokio::test]
+async fn from_day_fills_and_saves_the_flightlog() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-from-day", &server.url).await;
+    let oaci = String::from("LFLE");
+
+    let flightlog = FlightLog::from_day(date(), &oaci, &context).await.unwrap();
+    assert_eq!(flightlog.date, date());
+    assert_eq!(flightlog.flights, expected_flights());
+    assert_eq!(context.sync_status.get(&oaci).consecutive_failures, 0);
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, expected_flights());
+    // the aircraft seen that day are kept with their model and category
+    let aircraft = context.aircraft.day(date(), &oaci).await.unwrap();
+    let models: Vec<(&str, &str, u8)> = aircraft
+        .iter()
+        .map(|aircraft| {
+            (
+                aircraft.immatriculation.as_str(),
+                aircraft.modele.as_str(),
+                aircraft.category,
+            )
+        })
+        .collect();
+    assert_eq!(
+        models,
+        [
+            ("D-1234", "LS-4", 1),
+            ("F-CECY", "ASK-21", 1),
+            ("F-CEJU", "Pegase", 1),
+            ("F-GDRT", "DR-400", 2)
+        ]
+    );
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn synchronisation_keeps_manual_changes() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-synchronisation", &server.url).await;
+    let oaci = String::from("LFLE");
+    let flightlog_arc = context.flightlogs[&oaci].clone();
+    {
+        let mut flightlog = flightlog_arc.lock().unwrap();
+        flightlog.date = date();
+        flightlog.flights = vec![Flight {
+            ogn_nb: 2,
+            takeoff_code: String::from("T"),
+            takeoff_machine: String::from("yellow"),
+            glider: String::from("F-CEJU"),
+            takeoff: time(10, 1),
+            ..Default::default()
+        }];
+    }
+
+    synchronisation_ogn(flightlog_arc.clone(), &oaci, &context)
+        .await
+        .unwrap();
+    synchronisation_ogn(flightlog_arc.clone(), &oaci, &context)
+        .await
+        .unwrap();
+    let flightlog = flightlog_arc.lock().unwrap().clone();
+    assert_eq!(flightlog.flights.len(), 2);
+    // the takeoff time that was entered is kept, the landing comes from OGN
+    assert_eq!(flightlog.flights[0].takeoff, time(10, 1));
+    assert_eq!(flightlog.flights[0].landing, time(11, 30));
+    // so is the launch method, while OGN saw an aerotow
+    assert_eq!(flightlog.flights[0].takeoff_code, "T");
+    assert_eq!(flightlog.flights[0].takeoff_machine, "yellow");
+    assert_eq!(flightlog.flights[1], expected_flights()[1]);
+    // the merged flightlog is saved
+    let saved = context.storage.load(date(), &oaci).await.unwrap();
+    assert_eq!(saved.flights, flightlog.flights);
+    // and the differences with OGN are conflicts for the field crew
+    let conflicts = |provenance: Provenance| -> Vec<(String, String, String)> {
+        provenance
+            .open_conflicts()
+            .into_iter()
+            .map(|conflict| (conflict.field, conflict.manual, conflict.ogn))
+            .collect()
+    };
+    let provenance = context.provenance.day(date(), &oaci).await.unwrap();
+    assert_eq!(
+        conflicts(provenance),
+        [
+            ("takeoff".into(), "10:01".into(), "10:02".into()),
+            ("takeoff_code".into(), "T".into(), "R".into()),
+            ("takeoff_machine".into(), "yellow".into(), "F-GDRT".into()),
+        ]
+    );
+    // an update of the field resolves its conflict for good
+    let update = Update {
+        ogn_nb: 2,
+        field: String::from("takeoff"),
+        date: date(),
+        time: date().and_time(time(12, 0)),
+    };
+    apply_update(update, &oaci, date(), &context).await.unwrap();
+    synchronisation_ogn(flightlog_arc.clone(), &oaci, &context)
+        .await
+        .unwrap();
+    let provenance = context.provenance.day(date(), &oaci).await.unwrap();
+    assert_eq!(conflicts(provenance).len(), 2);
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_updates_of_today_are_all_saved() {
+    let context = test_context("ogn-today-updates", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    {
+        let mut flightlog = context.flightlogs[&oaci].lock().unwrap();
+        flightlog.date = date();
+        flightlog.flights = expected_flights();
+    }
+    let update = |ogn_nb: i32, field: String| Update {
+        ogn_nb,
+        field,
+        date: date(),
+        time: date().and_time(time(12, 0)),
+    };
+
+    let updates = (0..20).map(|index| {
+        let context = context.clone();
+        let oaci = oaci.clone();
+        let update = update(2 + index % 2, format!("pilot{index}"));
+        tokio::spawn(async move { apply_update(update, &oaci, date(), &context).await })
+    });
+    for update in updates.collect::<Vec<_>>() {
+        update.await.unwrap().unwrap();
+    }
+    let in_memory = context.flightlogs[&oaci].lock().unwrap().clone();
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, in_memory.flights);
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn an_update_that_is_not_saved_keeps_the_conflicts() {
+    let context = test_context("ogn-unsaved-update", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    let today = date() + chrono::Days::new(60);
+    let path = context.data_dir.join("2024/06/10/LFLE.json");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, "{\"date\":").unwrap();
+
+    let update = Update {
+        ogn_nb: 2,
+        field: String::from("takeoff"),
+        date: date(),
+        time: date().and_time(time(12, 0)),
+    };
+    assert!(apply_update(update, &oaci, today, &context).await.is_err());
+    let provenance = context.provenance.day(date(), &oaci).await.unwrap();
+    assert_eq!(provenance.source(2, "takeoff"), None);
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_openings_of_a_day_share_one_request() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-coalesce", &server.url).await;
+    let oaci = String::from("LFLE");
+    let today = date().succ_opt().unwrap();
+
+    let (first, second, third) = tokio::join!(
+        past_flightlog(date(), &oaci, today, &context),
+        past_flightlog(date(), &oaci, today, &context),
+        past_flightlog(date(), &oaci, today, &context),
+    );
+    for flightlog in [first, second, third] {
+        assert_eq!(flightlog.unwrap().flights, expected_flights());
+    }
+    assert_eq!(server.requests(), 1);
+    assert!(context.fetches.is_empty());
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, expected_flights());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_ogn_is_an_error() {
+    let context = test_context("ogn-unreachable", "http://127.0.0.1:9").await;
+    let oaci = String::from("LFLE");
+    let flightlog_arc = context.flightlogs[&oaci].clone();
+    assert!(synchronisation_ogn(flightlog_arc, &oaci, &context)
+        .await
+        .is_err());
+    let status = context.sync_status.get(&oaci);
+    assert_eq!(status.consecutive_failures, 1);
+    assert!(status.last_error.is_some());
+    assert!(status.message.starts_with("OGN unreachable since "));
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn backfill_reports_each_day() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-backfill", &server.url).await;
+    let oaci = String::from("LFLE");
+    let from = date().pred_opt().unwrap();
+    let to = date().succ_opt().unwrap();
+
+    let report = backfill(&context, &oaci, from, to, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(report.days.len(), 3);
+    assert!(matches!(report.days[0].outcome, DayOutcome::Failed { .. }));
+    assert_eq!(
+        report.days[1].outcome,
+        DayOutcome::Updated {
+            flights: 2,
+            new_flights: 2
+        }
+    );
+    assert_eq!(report.failures(), 2);
+    assert_eq!(server.requests(), 3);
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, expected_flights());
+
+    // a second backfill merges in the stored flightlog
+    let report = backfill(&context, &oaci, date(), date(), Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(
+        report.days[0].outcome,
+        DayOutcome::Updated {
+            flights: 2,
+            new_flights: 0
+        }
+    );
+    assert!(backfill(&context, &oaci, to, from, Duration::ZERO)
+        .await
+        .is_err());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn backfill_in_the_background_reports_its_progress() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-backfill-job", &server.url).await;
+    let oaci = String::from("LFLE");
+    let to = date().succ_opt().unwrap();
+
+    let job = start_backfill(&context, &oaci, date(), to, Duration::ZERO).unwrap();
+    assert!(job.running);
+    assert!(job.report.days.is_empty());
+    let err = start_backfill(&context, &oaci, date(), to, Duration::ZERO).unwrap_err();
+    assert!(err.is::<AlreadyRunning>());
+
+    let job = loop {
+        let job = context.backfills.get(&oaci).unwrap();
+        if !job.running {
+            break job;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(job.report.days.len(), 2);
+    assert_eq!(
+        job.report.days[0].outcome,
+        DayOutcome::Updated {
+            flights: 2,
+            new_flights: 2
+        }
+    );
+    assert!(context.fetches.is_empty());
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, expected_flights());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn concurrent_updates_of_a_past_day_are_all_kept() {
+    let server = FixtureServer::start();
+    let context = test_context("ogn-past-updates", &server.url).await;
+    let oaci = String::from("LFLE");
+    let today = date().succ_opt().unwrap();
+    let update = |ogn_nb: i32, field: &str| Update {
+        ogn_nb,
+        field: field.to_string(),
+        date: date(),
+        time: date().and_time(time(12, 0)),
+    };
+
+    let (first, second) = tokio::join!(
+        apply_update(update(2, "pilot1"), &oaci, today, &context),
+        apply_update(update(3, "pilot1"), &oaci, today, &context),
+    );
+    first.unwrap();
+    second.unwrap();
+    let mut expected = FlightLog::new();
+    expected.date = date();
+    expected.flights = expected_flights();
+    expected.update(update(2, "pilot1"));
+    expected.update(update(3, "pilot1"));
+    let stored = FlightLog::load(date(), &oaci, &context).await.unwrap();
+    assert_eq!(stored.flights, expected.flights);
+    let served = past_flightlog(date(), &oaci, today, &context)
+        .await
+        .unwrap();
+    assert_eq!(served.flights, expected.flights);
+    assert!(context.days.is_empty());
+    std::fs::remove_dir_all(&context.data_dir).unwrap();
+}